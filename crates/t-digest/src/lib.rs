@@ -96,6 +96,37 @@ impl Default for Centroid {
     }
 }
 
+// Neumaier's variant of Kahan summation: the rounding error of every addition
+// is accumulated separately and added back at the end, so that e.g. many small
+// values added to a huge one aren't lost.
+#[derive(Clone, Copy, Default)]
+struct CompensatedSum {
+    sum: f64,
+    compensation: f64,
+}
+
+impl CompensatedSum {
+    fn add(&mut self, value: f64) {
+        let sum = self.sum + value;
+        if self.sum.abs() >= value.abs() {
+            self.compensation += (self.sum - sum) + value;
+        } else {
+            self.compensation += (value - sum) + self.sum;
+        }
+        self.sum = sum;
+    }
+
+    fn total(&self) -> f64 {
+        // once the sum overflows or a value is infinite the error terms are
+        // meaningless, most likely NaN
+        if self.sum.is_finite() {
+            self.sum + self.compensation
+        } else {
+            self.sum
+        }
+    }
+}
+
 /// T-Digest to be operated on.
 #[derive(Debug, PartialEq, Eq, Clone)]
 #[cfg_attr(feature = "use_serde", derive(Serialize, Deserialize))]
@@ -133,7 +164,7 @@ impl TDigest {
         } else {
             let sz = centroids.len();
             let digests: Vec<TDigest> = vec![
-                TDigest::new_with_size(100),
+                TDigest::new_with_size(max_size),
                 TDigest::new(centroids, sum, count, max, min, sz),
             ];
 
@@ -223,23 +254,58 @@ impl TDigest {
     }
 
     pub fn merge_unsorted(&self, unsorted_values: Vec<f64>) -> TDigest {
-        let mut sorted_values: Vec<OrderedFloat<f64>> = unsorted_values.into_iter().map(OrderedFloat::from).collect();
-        sorted_values.sort();
-        let sorted_values = sorted_values.into_iter().map(|f| f.into_inner()).collect();
+        let mut unsorted_values = unsorted_values;
+        self.merge_unsorted_in_place(&mut unsorted_values)
+    }
 
-        self.merge_sorted(sorted_values)
+    /// Like `merge_unsorted`, but sorts the values in place instead of taking
+    /// ownership of them, so the caller can reuse the buffer afterwards.
+    pub fn merge_unsorted_in_place(&self, unsorted_values: &mut [f64]) -> TDigest {
+        unsorted_values.sort_by(|a, b| OrderedFloat::from(*a).cmp(&OrderedFloat::from(*b)));
+        self.merge_sorted_slice(unsorted_values)
     }
 
     pub fn merge_sorted(&self, sorted_values: Vec<f64>) -> TDigest {
-        if sorted_values.is_empty() {
-            return self.clone();
+        self.merge_sorted_slice(&sorted_values)
+    }
+
+    pub fn merge_sorted_slice(&self, sorted_values: &[f64]) -> TDigest {
+        match (sorted_values.first(), sorted_values.last()) {
+            (Some(&first), Some(&last)) => {
+                self.merge_sorted_iter(sorted_values.iter().copied(), sorted_values.len() as f64, first, last)
+            }
+            _ => self.clone(),
+        }
+    }
+
+    /// Like `merge_unsorted_in_place`, but with the values given as runs of
+    /// `(value, repetitions)`, each repeated at least once. The result is
+    /// exactly the same as for the values written out one by one.
+    pub fn merge_unsorted_runs_in_place(&self, unsorted_runs: &mut [(f64, u64)]) -> TDigest {
+        unsorted_runs.sort_by(|a, b| OrderedFloat::from(a.0).cmp(&OrderedFloat::from(b.0)));
+        self.merge_sorted_runs(unsorted_runs)
+    }
+
+    pub fn merge_sorted_runs(&self, sorted_runs: &[(f64, u64)]) -> TDigest {
+        debug_assert!(sorted_runs.iter().all(|&(_, repetitions)| repetitions > 0));
+        let count: u64 = sorted_runs.iter().map(|&(_, repetitions)| repetitions).sum();
+        match (sorted_runs.first(), sorted_runs.last()) {
+            (Some(&(first, _)), Some(&(last, _))) => {
+                let values = sorted_runs.iter()
+                    .flat_map(|&(value, repetitions)| std::iter::repeat_n(value, repetitions as usize));
+                self.merge_sorted_iter(values, count as f64, first, last)
+            }
+            _ => self.clone(),
         }
+    }
 
+    // Merge `count` sorted values, from `first` to `last`, into the digest.
+    fn merge_sorted_iter(&self, sorted_values: impl Iterator<Item = f64>, count: f64, first: f64, last: f64) -> TDigest {
         let mut result = TDigest::new_with_size(self.max_size());
-        result.count = OrderedFloat::from(self.count() + (sorted_values.len() as f64));
+        result.count = OrderedFloat::from(self.count() + count);
 
-        let maybe_min = OrderedFloat::from(*sorted_values.first().unwrap());
-        let maybe_max = OrderedFloat::from(*sorted_values.last().unwrap());
+        let maybe_min = OrderedFloat::from(first);
+        let maybe_max = OrderedFloat::from(last);
 
         if self.count() > 0.0 {
             result.min = std::cmp::min(self.min, maybe_min);
@@ -255,18 +321,22 @@ impl TDigest {
         let mut q_limit_times_count: f64 = Self::k_to_q(k_limit, self.max_size as f64) * result.count.into_inner();
         k_limit += 1.0;
 
+        // the sum is kept from the values themselves rather than recomputed
+        // from the centroids, whose means are rounded
+        let mut sum = CompensatedSum::default();
+        sum.add(self.sum());
         let mut iter_centroids = self.centroids.iter().peekable();
-        let mut iter_sorted_values = sorted_values.iter().peekable();
+        let mut iter_sorted_values = sorted_values.inspect(|&value| sum.add(value)).peekable();
 
         let mut curr: Centroid = if let Some(c) = iter_centroids.peek() {
-            let curr = **iter_sorted_values.peek().unwrap();
+            let curr = *iter_sorted_values.peek().unwrap();
             if c.mean() < curr {
                 iter_centroids.next().unwrap().clone()
             } else {
-                Centroid::new(*iter_sorted_values.next().unwrap(), 1.0)
+                Centroid::new(iter_sorted_values.next().unwrap(), 1.0)
             }
         } else {
-            Centroid::new(*iter_sorted_values.next().unwrap(), 1.0)
+            Centroid::new(iter_sorted_values.next().unwrap(), 1.0)
         };
 
         let mut weight_so_far: f64 = curr.weight();
@@ -276,13 +346,13 @@ impl TDigest {
 
         while iter_centroids.peek().is_some() || iter_sorted_values.peek().is_some() {
             let next: Centroid = if let Some(c) = iter_centroids.peek() {
-                if iter_sorted_values.peek().is_none() || c.mean() < **iter_sorted_values.peek().unwrap() {
+                if iter_sorted_values.peek().is_none() || c.mean() < *iter_sorted_values.peek().unwrap() {
                     iter_centroids.next().unwrap().clone()
                 } else {
-                    Centroid::new(*iter_sorted_values.next().unwrap(), 1.0)
+                    Centroid::new(iter_sorted_values.next().unwrap(), 1.0)
                 }
             } else {
-                Centroid::new(*iter_sorted_values.next().unwrap(), 1.0)
+                Centroid::new(iter_sorted_values.next().unwrap(), 1.0)
             };
 
             let next_sum: f64 = next.mean() * next.weight();
@@ -292,7 +362,7 @@ impl TDigest {
                 sums_to_merge += next_sum;
                 weights_to_merge += next.weight();
             } else {
-                result.sum = OrderedFloat::from(result.sum.into_inner() + curr.add(sums_to_merge, weights_to_merge));
+                curr.add(sums_to_merge, weights_to_merge);
                 sums_to_merge = 0.0;
                 weights_to_merge = 0.0;
 
@@ -303,7 +373,8 @@ impl TDigest {
             }
         }
 
-        result.sum = OrderedFloat::from(result.sum.into_inner() + curr.add(sums_to_merge, weights_to_merge));
+        curr.add(sums_to_merge, weights_to_merge);
+        result.sum = OrderedFloat::from(sum.total());
         compressed.push(curr);
         compressed.shrink_to_fit();
         compressed.sort();
@@ -364,8 +435,9 @@ impl TDigest {
         let mut starts: Vec<usize> = Vec::with_capacity(digests.len());
 
         let mut count: f64 = 0.0;
-        let mut min = OrderedFloat::from(std::f64::INFINITY);
-        let mut max = OrderedFloat::from(std::f64::NEG_INFINITY);
+        let mut sum = CompensatedSum::default();
+        let mut min = OrderedFloat::from(f64::INFINITY);
+        let mut max = OrderedFloat::from(f64::NEG_INFINITY);
 
         let mut start: usize = 0;
         for digest in digests.into_iter() {
@@ -376,6 +448,7 @@ impl TDigest {
                 min = std::cmp::min(min, digest.min);
                 max = std::cmp::max(max, digest.max);
                 count += curr_count;
+                sum.add(digest.sum());
                 for centroid in digest.centroids {
                     centroids.push(centroid);
                     start += 1;
@@ -404,44 +477,198 @@ impl TDigest {
         }
 
         let mut result = TDigest::new_with_size(max_size);
-        let mut compressed: Vec<Centroid> = Vec::with_capacity(max_size);
+        Self::compress_sorted_in_place(&mut centroids, count, max_size);
+        centroids.shrink_to_fit();
+
+        result.sum = OrderedFloat::from(sum.total());
+        result.count = OrderedFloat::from(count);
+        result.min = min;
+        result.max = max;
+        result.centroids = centroids;
+        result
+    }
+
+    /// Merge multiple T-Digests at the largest of their sizes, sorting all of
+    /// their centroids together and compressing them once. Unlike
+    /// `merge_digests`, whose ties and size depend on the order of `digests`,
+    /// and unlike merging them one at a time, which compresses after every
+    /// step, the result is the same however the digests are ordered.
+    pub fn merge_digests_unordered(digests: Vec<TDigest>) -> TDigest {
+        let max_size = digests.iter().map(|d| d.max_size).max().unwrap_or(0);
+        let mut centroids: Vec<Centroid> = Vec::new();
+        let mut sums: Vec<OrderedFloat<f64>> = Vec::with_capacity(digests.len());
+
+        let mut count: f64 = 0.0;
+        let mut min = OrderedFloat::from(f64::INFINITY);
+        let mut max = OrderedFloat::from(f64::NEG_INFINITY);
+
+        for digest in digests.into_iter() {
+            if digest.count() > 0.0 {
+                min = std::cmp::min(min, digest.min);
+                max = std::cmp::max(max, digest.max);
+                count += digest.count();
+                sums.push(digest.sum);
+                centroids.extend(digest.centroids);
+            }
+        }
+        if centroids.is_empty() {
+            return TDigest::new_with_size(max_size);
+        }
+
+        // centroids with equal means are ordered by weight, and the sums are
+        // added in order, so that neither depends on the input order
+        centroids.sort_by_key(|cent| (cent.mean, cent.weight));
+        sums.sort();
+        let mut sum = CompensatedSum::default();
+        for digest_sum in sums {
+            sum.add(digest_sum.into_inner());
+        }
+
+        Self::compress_sorted_in_place(&mut centroids, count, max_size);
+        centroids.shrink_to_fit();
+
+        let mut result = TDigest::new_with_size(max_size);
+        result.sum = OrderedFloat::from(sum.total());
+        result.count = OrderedFloat::from(count);
+        result.min = min;
+        result.max = max;
+        result.centroids = centroids;
+        result
+    }
+
+    /// Merge `other` into this digest, at this digest's size. The result is the
+    /// same as `merge_digests(vec![self, other])`, but it is built in this
+    /// digest's centroid storage, so folding many digests into one only
+    /// allocates while that storage is still growing. The exception is a pair
+    /// of digests whose ranges don't overlap, e.g. consecutive time buckets of
+    /// a value that keeps growing: their centroids are already in order end to
+    /// end, so if there's room for all of them they are just concatenated,
+    /// which is cheaper and loses nothing to compression. Two empty digests
+    /// leave an empty one at the larger of their sizes, so that merging in
+    /// more later doesn't fall back to the default size.
+    pub fn merge_digest_in_place(&mut self, other: &TDigest) {
+        if self.centroids.is_empty() && other.centroids.is_empty() {
+            *self = TDigest::new_with_size(std::cmp::max(self.max_size, other.max_size));
+            return;
+        }
 
+        if self.count() > 0.0
+            && other.count() > 0.0
+            && (self.max <= other.min || other.max <= self.min)
+            && self.centroids.len() + other.centroids.len() <= self.max_size
+        {
+            if self.max <= other.min {
+                self.centroids.extend_from_slice(&other.centroids);
+            } else {
+                self.centroids.splice(0..0, other.centroids.iter().cloned());
+            }
+            let mut sum = CompensatedSum::default();
+            sum.add(self.sum());
+            sum.add(other.sum());
+            self.sum = OrderedFloat::from(sum.total());
+            self.count = OrderedFloat::from(self.count() + other.count());
+            self.min = std::cmp::min(self.min, other.min);
+            self.max = std::cmp::max(self.max, other.max);
+            return;
+        }
+
+        let mut count: f64 = 0.0;
+        let mut sum = CompensatedSum::default();
+        let mut min = OrderedFloat::from(f64::INFINITY);
+        let mut max = OrderedFloat::from(f64::NEG_INFINITY);
+
+        if self.count() > 0.0 {
+            min = std::cmp::min(min, self.min);
+            max = std::cmp::max(max, self.max);
+            count += self.count();
+            sum.add(self.sum());
+        } else {
+            self.centroids.clear();
+        }
+
+        if other.count() > 0.0 {
+            min = std::cmp::min(min, other.min);
+            max = std::cmp::max(max, other.max);
+            count += other.count();
+            sum.add(other.sum());
+
+            // merge from the back, so that no centroid of ours is overwritten
+            // before it is moved; on ties ours come first, as in external_merge
+            let mut i = self.centroids.len();
+            let mut j = other.centroids.len();
+            self.centroids.resize(i + j, Centroid::default());
+            while j > 0 {
+                let k = i + j - 1;
+                if i > 0 && self.centroids[i - 1] > other.centroids[j - 1] {
+                    self.centroids[k] = self.centroids[i - 1].clone();
+                    i -= 1;
+                } else {
+                    self.centroids[k] = other.centroids[j - 1].clone();
+                    j -= 1;
+                }
+            }
+        }
+
+        if self.centroids.is_empty() {
+            return;
+        }
+
+        Self::compress_sorted_in_place(&mut self.centroids, count, self.max_size);
+        self.sum = OrderedFloat::from(sum.total());
+        self.count = OrderedFloat::from(count);
+        self.min = min;
+        self.max = max;
+    }
+
+    // Compress sorted, non-empty centroids down to at most max_size, writing
+    // the compressed centroids over the front of the vector, which never gets
+    // ahead of the ones still being read.
+    fn compress_sorted_in_place(centroids: &mut Vec<Centroid>, count: f64, max_size: usize) {
         let mut k_limit: f64 = 1.0;
         let mut q_limit_times_count: f64 = Self::k_to_q(k_limit, max_size as f64) * (count as f64);
+        k_limit += 1.0;
 
-        let mut iter_centroids = centroids.iter_mut();
-        let mut curr = iter_centroids.next().unwrap();
-        let mut weight_so_far: f64 = curr.weight();
+        let mut compressed: usize = 0;
+        let mut curr: usize = 0;
+        let mut weight_so_far: f64 = centroids[curr].weight();
         let mut sums_to_merge: f64 = 0.0;
         let mut weights_to_merge: f64 = 0.0;
 
-        for centroid in iter_centroids {
+        for i in 1..centroids.len() {
+            let centroid = &centroids[i];
             weight_so_far += centroid.weight();
 
             if weight_so_far <= q_limit_times_count {
                 sums_to_merge += centroid.mean() * centroid.weight();
                 weights_to_merge += centroid.weight();
             } else {
-                result.sum = OrderedFloat::from(result.sum.into_inner() + curr.add(sums_to_merge, weights_to_merge));
+                centroids[curr].add(sums_to_merge, weights_to_merge);
                 sums_to_merge = 0.0;
                 weights_to_merge = 0.0;
-                compressed.push(curr.clone());
+                centroids[compressed] = centroids[curr].clone();
+                compressed += 1;
                 q_limit_times_count = Self::k_to_q(k_limit, max_size as f64) * (count as f64);
                 k_limit += 1.0;
-                curr = centroid;
+                curr = i;
             }
         }
 
-        result.sum = OrderedFloat::from(result.sum.into_inner() + curr.add(sums_to_merge, weights_to_merge));
-        compressed.push(curr.clone());
-        compressed.shrink_to_fit();
-        compressed.sort();
+        centroids[curr].add(sums_to_merge, weights_to_merge);
+        centroids[compressed] = centroids[curr].clone();
+        centroids.truncate(compressed + 1);
+        centroids.sort();
+    }
 
-        result.count = OrderedFloat::from(count as f64);
-        result.min = min;
-        result.max = max;
-        result.centroids = compressed;
-        result
+    /// Re-compress the digest into at most `max_size` centroids, trading away
+    /// the accuracy the larger size would have given. The result also merges
+    /// at the reduced size from then on.
+    pub fn compressed_to(&self, max_size: usize) -> TDigest {
+        let mut digest = self.clone();
+        digest.max_size = std::cmp::min(self.max_size, max_size);
+        if digest.centroids.len() <= digest.max_size {
+            return digest;
+        }
+        TDigest::merge_digests(vec![digest])
     }
 
     /// Given a value estimate the corresponding quantile in a digest
@@ -485,70 +712,126 @@ impl TDigest {
 
     /// To estimate the value located at `q` quantile
     pub fn estimate_quantile(&self, q: f64) -> f64 {
-        if self.centroids.is_empty() {
-            return 0.0;
-        }
+        let centroids = &self.centroids;
+        estimate_quantile(
+            centroids.len(),
+            |i| centroids[i].mean(),
+            |i| centroids[i].weight(),
+            self.count(),
+            self.min(),
+            self.max(),
+            q,
+        )
+    }
+}
 
-        let count_: f64 = self.count.into_inner();
-        let rank: f64 = q * count_;
+/// A read-only view of centroids stored as separate arrays of means and
+/// weights, e.g. straight out of a serialized digest. Estimates from it are
+/// identical to those of the equivalent `TDigest`, without having to copy the
+/// centroids into one first.
+#[derive(Debug, Clone, Copy)]
+pub struct CentroidSlices<'a, W> {
+    means: &'a [f64],
+    weights: &'a [W],
+    count: f64,
+    min: f64,
+    max: f64,
+}
 
-        let mut pos: usize;
-        let mut t: f64;
-        if q > 0.5 {
-            if q >= 1.0 {
-                return self.max();
-            }
+impl<'a, W: Copy + Into<f64>> CentroidSlices<'a, W> {
+    pub fn new(means: &'a [f64], weights: &'a [W], count: f64, min: f64, max: f64) -> Self {
+        assert_eq!(means.len(), weights.len());
+        CentroidSlices { means, weights, count, min, max }
+    }
 
-            pos = 0;
-            t = count_;
+    pub fn estimate_quantile(&self, q: f64) -> f64 {
+        estimate_quantile(
+            self.means.len(),
+            |i| self.means[i],
+            |i| self.weights[i].into(),
+            self.count,
+            self.min,
+            self.max,
+            q,
+        )
+    }
+}
 
-            for (k, centroid) in self.centroids.iter().enumerate().rev() {
-                t -= centroid.weight();
+// Shared by TDigest and CentroidSlices, so that both produce exactly the same
+// estimates regardless of how the centroids are stored.
+fn estimate_quantile(
+    len: usize,
+    mean: impl Fn(usize) -> f64,
+    weight: impl Fn(usize) -> f64,
+    count: f64,
+    digest_min: f64,
+    digest_max: f64,
+    q: f64,
+) -> f64 {
+    if len == 0 {
+        return 0.0;
+    }
 
-                if rank >= t {
-                    pos = k;
-                    break;
-                }
-            }
-        } else {
-            if q <= 0.0 {
-                return self.min();
-            }
+    let rank: f64 = q * count;
 
-            pos = self.centroids.len() - 1;
-            t = 0.0;
+    let mut pos: usize;
+    let mut t: f64;
+    if q > 0.5 {
+        if q >= 1.0 {
+            return digest_max;
+        }
 
-            for (k, centroid) in self.centroids.iter().enumerate() {
-                if rank < t + centroid.weight() {
-                    pos = k;
-                    break;
-                }
+        pos = 0;
+        t = count;
+
+        for k in (0..len).rev() {
+            t -= weight(k);
 
-                t += centroid.weight();
+            if rank >= t {
+                pos = k;
+                break;
             }
         }
+    } else {
+        if q <= 0.0 {
+            return digest_min;
+        }
 
-        let mut delta = 0.0;
-        let mut min: f64 = self.min.into_inner();
-        let mut max: f64 = self.max.into_inner();
+        pos = len - 1;
+        t = 0.0;
 
-        if self.centroids.len() > 1 {
-            if pos == 0 {
-                delta = self.centroids[pos + 1].mean() - self.centroids[pos].mean();
-                max = self.centroids[pos + 1].mean();
-            } else if pos == (self.centroids.len() - 1) {
-                delta = self.centroids[pos].mean() - self.centroids[pos - 1].mean();
-                min = self.centroids[pos - 1].mean();
-            } else {
-                delta = (self.centroids[pos + 1].mean() - self.centroids[pos - 1].mean()) / 2.0;
-                min = self.centroids[pos - 1].mean();
-                max = self.centroids[pos + 1].mean();
+        for k in 0..len {
+            if rank < t + weight(k) {
+                pos = k;
+                break;
             }
+
+            t += weight(k);
         }
+    }
 
-        let value = self.centroids[pos].mean() + ((rank - t) / self.centroids[pos].weight() - 0.5) * delta;
-        Self::clamp(value, min, max)
+    let mut delta = 0.0;
+    let mut min: f64 = digest_min;
+    let mut max: f64 = digest_max;
+
+    if len > 1 {
+        if pos == 0 {
+            delta = mean(pos + 1) - mean(pos);
+            max = mean(pos + 1);
+        } else if pos == (len - 1) {
+            delta = mean(pos) - mean(pos - 1);
+            min = mean(pos - 1);
+        } else {
+            delta = (mean(pos + 1) - mean(pos - 1)) / 2.0;
+            min = mean(pos - 1);
+            max = mean(pos + 1);
+        }
     }
+
+    let value = mean(pos) + ((rank - t) / weight(pos) - 0.5) * delta;
+    // rounding while merging centroids can leave a mean just past the
+    // true extremes, estimates should never go beyond an observed value
+    TDigest::clamp(TDigest::clamp(value, min, max), digest_min, digest_max)
 }
 
 #[cfg(test)]
@@ -753,6 +1036,399 @@ mod tests {
         assert!(percentage < 0.01);
     }
 
+    #[test]
+    fn test_merge_digests_unordered() {
+        let digests: Vec<TDigest> = (0..10)
+            .map(|i| {
+                let values: Vec<f64> = (0..1_000).map(|j| ((j * 7919 + i * 131) % 1_000 + i * 10) as f64 / 7.0).collect();
+                TDigest::new_with_size(50 + i as usize * 10).merge_unsorted(values)
+            })
+            .collect();
+
+        let forward = TDigest::merge_digests_unordered(digests.clone());
+        let mut reversed = digests.clone();
+        reversed.reverse();
+        assert_eq!(TDigest::merge_digests_unordered(reversed), forward);
+        let mut rotated = digests.clone();
+        rotated.rotate_left(3);
+        assert_eq!(TDigest::merge_digests_unordered(rotated), forward);
+
+        assert_eq!(forward.max_size(), 140);
+        assert_eq!(forward.count(), 10_000.0);
+        assert!(forward.raw_centroids().len() <= 140);
+
+        let fast = TDigest::merge_digests(digests);
+        for i in 1..10 {
+            let q = i as f64 / 10.0;
+            let (a, b) = (forward.estimate_quantile(q), fast.estimate_quantile(q));
+            assert!((a - b).abs() / b < 0.01, "{} vs {} at {}", a, b, q);
+        }
+
+        assert!(TDigest::merge_digests_unordered(vec![]).is_empty());
+    }
+
+    #[test]
+    fn test_merge_digest_in_place_matches_merge_digests() {
+        let build = |size: usize, values: &[f64]| TDigest::new_with_size(size).merge_unsorted(values.to_vec());
+        let uniform: Vec<f64> = (1..=1_000).map(f64::from).collect();
+        let skewed: Vec<f64> = (1..=1_000).map(|i| f64::from(i).powi(3)).collect();
+        let repeated: Vec<f64> = (1..=1_000).map(|i| f64::from(i % 10)).collect();
+        let digests = vec![
+            build(100, &uniform),
+            build(100, &skewed),
+            build(100, &repeated),
+            build(20, &uniform),
+            build(100, &[]),
+        ];
+
+        for a in &digests {
+            for b in &digests {
+                let expected = TDigest::merge_digests(vec![a.clone(), b.clone()]);
+                let mut actual = a.clone();
+                actual.merge_digest_in_place(b);
+                assert_eq!(actual, expected);
+            }
+        }
+
+        // a deep fold reuses the same storage throughout
+        let mut expected = TDigest::new_with_size(100);
+        let mut actual = TDigest::new_with_size(100);
+        for i in 0..100 {
+            let digest = &digests[i % 3];
+            expected = TDigest::merge_digests(vec![expected, digest.clone()]);
+            actual.merge_digest_in_place(digest);
+            assert_eq!(actual, expected);
+        }
+    }
+
+    #[test]
+    fn test_merge_digest_in_place_concatenates_disjoint() {
+        let build = |values: std::ops::RangeInclusive<i32>| {
+            TDigest::new_with_size(100).merge_unsorted(values.map(f64::from).collect())
+        };
+        let (low, high) = (build(1..=50), build(101..=150));
+        assert!(low.centroids.len() + high.centroids.len() <= 100);
+        let concatenated: Vec<Centroid> = low.centroids.iter().chain(&high.centroids).cloned().collect();
+        let merged = TDigest::merge_digests(vec![low.clone(), high.clone()]);
+        // a full merge would compress them
+        assert_ne!(merged.centroids, concatenated);
+
+        // in either order, every centroid is kept as it was
+        for (a, b) in &[(&low, &high), (&high, &low)] {
+            let mut actual = (*a).clone();
+            actual.merge_digest_in_place(b);
+            assert_eq!(actual.centroids, concatenated);
+            assert_eq!((actual.count(), actual.sum(), actual.min(), actual.max()),
+                (100.0, merged.sum(), 1.0, 150.0));
+            for &q in &[0.01, 0.25, 0.5, 0.75, 0.99] {
+                let (actual, merged) = (actual.estimate_quantile(q), merged.estimate_quantile(q));
+                assert!((actual - merged).abs() <= 0.01 * merged, "{} {} {}", q, actual, merged);
+            }
+        }
+
+        // overlapping digests, or ones that don't fit, are merged as usual
+        let overlapping = build(25..=75);
+        let mut actual = low.clone();
+        actual.merge_digest_in_place(&overlapping);
+        assert_eq!(actual, TDigest::merge_digests(vec![low.clone(), overlapping]));
+        let small = TDigest::new_with_size(10).merge_unsorted((1..=1000).map(f64::from).collect());
+        let mut actual = small.clone();
+        actual.merge_digest_in_place(&high);
+        assert_eq!(actual, TDigest::merge_digests(vec![small, high]));
+    }
+
+    #[test]
+    fn test_merge_digest_in_place_keeps_size_of_empty() {
+        let (small, large) = (TDigest::new_with_size(50), TDigest::new_with_size(200));
+        for (a, b) in &[(&small, &large), (&large, &small)] {
+            let mut actual = (*a).clone();
+            actual.merge_digest_in_place(b);
+            assert_eq!(actual, large);
+        }
+    }
+
+    // Cost of folding many digests into one, as a deep rollup does, run with
+    // `cargo test --release -- --ignored --nocapture bench_`
+    #[test]
+    #[ignore]
+    fn bench_merge_digest_in_place() {
+        use std::time::Instant;
+
+        let digests: Vec<TDigest> = (0..10_000)
+            .map(|i| {
+                let values = (0..1_000).map(|j| ((i * 1_000 + j) * 7919 % 1_000_000) as f64).collect();
+                TDigest::new_with_size(100).merge_unsorted(values)
+            })
+            .collect();
+
+        let start = Instant::now();
+        let mut expected = TDigest::new_with_size(100);
+        for digest in &digests {
+            expected = TDigest::merge_digests(vec![expected, digest.clone()]);
+        }
+        let reference_time = start.elapsed();
+
+        let start = Instant::now();
+        let mut actual = TDigest::new_with_size(100);
+        for digest in &digests {
+            actual.merge_digest_in_place(digest);
+        }
+        let in_place_time = start.elapsed();
+
+        assert_eq!(actual, expected);
+        println!("rollup of {} digests: merge_digests {:?}, in place {:?} ({:.1}x)", digests.len(),
+            reference_time, in_place_time, reference_time.as_secs_f64() / in_place_time.as_secs_f64());
+    }
+
+    // Cost of the ways a rollup could fold in 10,000 small digests: a new
+    // merge_digests at every step, copying the running result at every step,
+    // folding in place, or merging batches of BATCH digests at once.
+    #[test]
+    #[ignore]
+    fn bench_rollup_small_digests() {
+        use std::time::Instant;
+        const BATCH: usize = 32;
+
+        let digests: Vec<TDigest> = (0..10_000i64)
+            .map(|i| {
+                let values = (0..100).map(|j| ((i * 100 + j) * 7919 % 1_000_000) as f64).collect();
+                TDigest::new_with_size(100).merge_unsorted(values)
+            })
+            .collect();
+
+        let start = Instant::now();
+        let mut reference = TDigest::new_with_size(100);
+        for digest in digests.iter().cloned() {
+            reference = TDigest::merge_digests(vec![reference, digest]);
+        }
+        let reference_time = start.elapsed();
+
+        let start = Instant::now();
+        let mut copied = TDigest::new_with_size(100);
+        for digest in &digests {
+            let mut next = copied.clone();
+            next.merge_digest_in_place(digest);
+            copied = next;
+        }
+        let copied_time = start.elapsed();
+
+        let start = Instant::now();
+        let mut in_place = TDigest::new_with_size(100);
+        for digest in &digests {
+            in_place.merge_digest_in_place(digest);
+        }
+        let in_place_time = start.elapsed();
+
+        let owned = digests.clone();
+        let start = Instant::now();
+        let mut batched = TDigest::new_with_size(100);
+        let mut batch = Vec::with_capacity(BATCH + 1);
+        for digest in owned {
+            batch.push(digest);
+            if batch.len() == BATCH {
+                batch.insert(0, std::mem::take(&mut batched));
+                batched = TDigest::merge_digests(std::mem::take(&mut batch));
+            }
+        }
+        batch.insert(0, batched);
+        let batched = TDigest::merge_digests(batch);
+        let batched_time = start.elapsed();
+
+        assert_eq!(copied, in_place);
+        for digest in &[&reference, &in_place, &batched] {
+            assert_eq!(digest.count(), 1_000_000.0);
+            for &q in &[0.1, 0.5, 0.9, 0.99] {
+                let expected = q * 1_000_000.0;
+                assert!((digest.estimate_quantile(q) - expected).abs() < 0.01 * expected);
+            }
+        }
+        println!("rollup of {} small digests: merge_digests {:?}, copying {:?}, in place {:?}, batches of {} {:?}",
+            digests.len(), reference_time, copied_time, in_place_time, BATCH, batched_time);
+    }
+
+    #[test]
+    fn test_sum_of_different_magnitudes() {
+        // sorted, these are -1e16, 10_000 ones and 1e16+2, and each 1 on its
+        // own is lost when added to 1e16, so a naive sum gives 2 (or 0)
+        let mut values = vec![1e16 + 2.0, -1e16];
+        values.extend(std::iter::repeat_n(1.0, 10_000));
+        let t = TDigest::new_with_size(100).merge_unsorted(values.clone());
+        assert_eq!(t.sum(), 10_002.0);
+
+        // the same holds when the values arrive in separate digests, or a
+        // few at a time
+        let parts: Vec<TDigest> = values.chunks(1000)
+            .map(|chunk| TDigest::new_with_size(100).merge_unsorted(chunk.to_vec()))
+            .collect();
+        assert_eq!(TDigest::merge_digests(parts).sum(), 10_002.0);
+
+        let mut t = TDigest::new_with_size(100);
+        for chunk in values.chunks(1000) {
+            t = t.merge_unsorted(chunk.to_vec());
+        }
+        assert_eq!(t.sum(), 10_002.0);
+
+        // compared against a sum of the values in 128-bit fixed point
+        let values: Vec<f64> = (1..=1_000_000).map(|i| (i as f64).powi(2) / 1024.0 + 1e-3).collect();
+        let exact: i128 = values.iter().map(|&v| (v * (1u64 << 40) as f64) as i128).sum();
+        let exact = exact as f64 / (1u64 << 40) as f64;
+        let t = TDigest::new_with_size(100).merge_sorted(values);
+        assert!((t.sum() - exact).abs() <= exact * 1e-15, "sum {} expected {}", t.sum(), exact);
+    }
+
+    fn centroid_slices(t: &TDigest) -> (Vec<f64>, Vec<u32>) {
+        let means = t.raw_centroids().iter().map(|c| c.mean()).collect();
+        let weights = t.raw_centroids().iter().map(|c| c.weight() as u32).collect();
+        (means, weights)
+    }
+
+    #[test]
+    fn test_centroid_slices_match_digest() {
+        let values: Vec<f64> = (1..=100_000).map(|i| ((i * 7919) % 100_000) as f64).collect();
+        let t = TDigest::new_with_size(500).merge_unsorted(values);
+        let (means, weights) = centroid_slices(&t);
+        let slices = CentroidSlices::new(&means, &weights, t.count(), t.min(), t.max());
+
+        for i in 0..=1000 {
+            let q = i as f64 / 1000.0;
+            assert_eq!(slices.estimate_quantile(q).to_bits(), t.estimate_quantile(q).to_bits());
+        }
+
+        let empty: CentroidSlices<u32> = CentroidSlices::new(&[], &[], 0.0, std::f64::NAN, std::f64::NAN);
+        assert_eq!(empty.estimate_quantile(0.5), TDigest::default().estimate_quantile(0.5));
+    }
+
+    // Throughput of the quantile read path, run with
+    // `cargo test --release -- --ignored --nocapture bench_`
+    #[test]
+    #[ignore]
+    fn bench_estimate_quantile() {
+        use std::time::Instant;
+
+        let values: Vec<f64> = (1..=1_000_000).map(|i| ((i * 7919) % 1_000_000) as f64).collect();
+        let t = TDigest::new_with_size(500).merge_unsorted(values);
+        let (means, weights) = centroid_slices(&t);
+        let iterations = 100_000;
+
+        // the reference path rebuilds a TDigest from the stored arrays first,
+        // the way reading a serialized digest used to
+        let reference = |quantiles: &[f64]| -> f64 {
+            let cents = means.iter().zip(&weights).map(|(&m, &w)| Centroid::new(m, w as f64)).collect();
+            let digest = TDigest::new(cents, t.sum(), t.count(), t.max(), t.min(), t.max_size());
+            quantiles.iter().map(|&q| digest.estimate_quantile(q)).sum()
+        };
+        let sliced = |quantiles: &[f64]| -> f64 {
+            let slices = CentroidSlices::new(&means, &weights, t.count(), t.min(), t.max());
+            quantiles.iter().map(|&q| slices.estimate_quantile(q)).sum()
+        };
+
+        let single = [0.99];
+        let batch: Vec<f64> = (0..=100).map(|i| i as f64 / 100.0).collect();
+        for (name, quantiles) in &[("single", &single[..]), ("batch", &batch[..])] {
+            let start = Instant::now();
+            let mut expected = 0.0;
+            for _ in 0..iterations {
+                expected += reference(quantiles);
+            }
+            let reference_time = start.elapsed();
+
+            let start = Instant::now();
+            let mut actual = 0.0;
+            for _ in 0..iterations {
+                actual += sliced(quantiles);
+            }
+            let sliced_time = start.elapsed();
+
+            assert_eq!(actual.to_bits(), expected.to_bits());
+            println!("{} quantile: reference {:?}, slices {:?} ({:.1}x)", name, reference_time, sliced_time,
+                reference_time.as_secs_f64() / sliced_time.as_secs_f64());
+        }
+    }
+
+    #[test]
+    fn test_merge_runs_matches_values() {
+        let mut runs = vec![];
+        let mut values = vec![];
+        for i in 0..1000u64 {
+            let value = ((i * 7919) % 100) as f64;
+            let repetitions = 1 + i % 13;
+            runs.push((value, repetitions));
+            values.extend(std::iter::repeat_n(value, repetitions as usize));
+        }
+
+        let t = TDigest::new_with_size(100).merge_unsorted(vec![0.5, 99.5, 50.5]);
+        assert_eq!(t.merge_unsorted_runs_in_place(&mut runs), t.merge_unsorted(values));
+        assert_eq!(t.merge_sorted_runs(&[]), t);
+    }
+
+    #[test]
+    fn test_quantile_within_min_max() {
+        let centroids = vec![
+            Centroid::new(0.9999999, 10.0),
+            Centroid::new(5.0, 10.0),
+            Centroid::new(10.0000001, 10.0),
+        ];
+        let t = TDigest::new(centroids, 160.0, 30.0, 10.0, 1.0, 100);
+
+        assert_eq!(t.estimate_quantile(0.0), 1.0);
+        assert_eq!(t.estimate_quantile(1.0), 10.0);
+        for &q in &[1e-12, 0.001, 0.01, 0.99, 0.999, 1.0 - 1e-12] {
+            let value = t.estimate_quantile(q);
+            assert!((1.0..=10.0).contains(&value), "quantile {} estimated as {}", q, value);
+        }
+    }
+
+    #[test]
+    fn test_compressed_to() {
+        let values: Vec<f64> = (1..=100_000).map(f64::from).collect();
+        let t = TDigest::new_with_size(1000).merge_sorted(values);
+        assert!(t.raw_centroids().len() > 100);
+
+        let compressed = t.compressed_to(100);
+        assert_eq!(compressed.max_size(), 100);
+        assert!(compressed.raw_centroids().len() <= 100);
+        assert_eq!(compressed.count(), 100_000.0);
+        assert_eq!(compressed.min(), 1.0);
+        assert_eq!(compressed.max(), 100_000.0);
+
+        let ans = compressed.estimate_quantile(0.5);
+        let expected: f64 = 50_000.0;
+        let percentage: f64 = (expected - ans).abs() / expected;
+        assert!(percentage < 0.01);
+
+        // already small enough, nothing to do
+        assert_eq!(compressed.compressed_to(200), compressed);
+    }
+
+    #[test]
+    fn test_new_with_too_many_centroids() {
+        let centroids: Vec<Centroid> = (1..=1000).map(|v| Centroid::new(f64::from(v), 1.0)).collect();
+        let t = TDigest::new(centroids, 500_500.0, 1000.0, 1000.0, 1.0, 50);
+
+        assert_eq!(t.max_size(), 50);
+        assert!(t.raw_centroids().len() <= 50);
+        assert_eq!(t.count(), 1000.0);
+    }
+
+    #[test]
+    fn test_quantile_with_large_weights() {
+        // both the individual weights and the total count are well past what fits in a u32
+        let weight = f64::from(u32::MAX) * 2.0;
+        let centroids: Vec<Centroid> = (1..=100).map(|v| Centroid::new(f64::from(v), weight)).collect();
+        let t = TDigest::new(centroids, weight * 5050.0, weight * 100.0, 100.0, 1.0, 100);
+        assert!(t.count() > f64::from(u32::MAX));
+
+        let ans = t.estimate_quantile(0.5);
+        let expected: f64 = 50.5;
+        let percentage: f64 = (expected - ans).abs() / expected;
+        assert!(percentage < 0.01, "expected {}, got {}", expected, ans);
+
+        let ans = t.estimate_quantile_at_value(50.5);
+        let expected: f64 = 0.5;
+        let percentage: f64 = (expected - ans).abs() / expected;
+        assert!(percentage < 0.01, "expected {}, got {}", expected, ans);
+    }
+
     #[test]
     fn test_quantile_and_value_estimates() {
         let t = TDigest::new_with_size(100);
@@ -1,3 +1,5 @@
+-- integer and real input goes through the implicit casts to double precision,
+-- which postgres prefers over those to numeric
 CREATE AGGREGATE t_digest(size int, value DOUBLE PRECISION)
 (
     sfunc=tdigest_trans,
@@ -5,5 +7,202 @@ CREATE AGGREGATE t_digest(size int, value DOUBLE PRECISION)
     finalfunc=tdigest_final,
     combinefunc=tdigest_combine,
     serialfunc = tdigest_serialize,
-    deserialfunc = tdigest_deserialize
-)
+    deserialfunc = tdigest_deserialize,
+    parallel = safe
+);
+
+-- t_digest_ordered is meant to be called with ORDER BY, e.g.
+-- t_digest_ordered(100, value ORDER BY time), for exact first and last values.
+-- Without one it may run in parallel, and then leaves them unknown, see
+-- tdigest_ordered_combine
+CREATE AGGREGATE t_digest_ordered(size int, value DOUBLE PRECISION)
+(
+    sfunc=tdigest_trans,
+    stype=internal,
+    finalfunc=tdigest_final,
+    combinefunc=tdigest_ordered_combine,
+    serialfunc = tdigest_serialize,
+    deserialfunc = tdigest_deserialize,
+    parallel = safe
+);
+
+-- t_digest_sampled only digests a deterministic sample of the values, see
+-- tdigest_sampled_trans
+CREATE AGGREGATE t_digest_sampled(size int, sample_rate DOUBLE PRECISION, seed bigint, value DOUBLE PRECISION)
+(
+    sfunc=tdigest_sampled_trans,
+    stype=internal,
+    finalfunc=tdigest_final,
+    combinefunc=tdigest_combine,
+    serialfunc = tdigest_serialize,
+    deserialfunc = tdigest_deserialize,
+    parallel = safe
+);
+
+-- t_digest_log digests the logarithms of the values, see tdigest_log_trans
+CREATE AGGREGATE t_digest_log(size int, value DOUBLE PRECISION)
+(
+    sfunc=tdigest_log_trans,
+    stype=internal,
+    finalfunc=tdigest_final,
+    combinefunc=tdigest_combine,
+    serialfunc = tdigest_serialize,
+    deserialfunc = tdigest_deserialize,
+    parallel = safe
+);
+
+-- t_digest_continue adds values to a stored digest, the seed. It has no
+-- combine function, since every partial aggregate would add the seed once.
+CREATE AGGREGATE t_digest_continue(seed TimescaleTDigest, value DOUBLE PRECISION)
+(
+    sfunc=tdigest_continue_trans,
+    stype=internal,
+    finalfunc=tdigest_final
+);
+
+-- t_digest_resized builds the digest at size but stores it at target_size,
+-- trading accuracy in the result for space without losing any while building
+CREATE AGGREGATE t_digest_resized(size int, target_size int, value DOUBLE PRECISION)
+(
+    sfunc=tdigest_resized_trans,
+    stype=internal,
+    finalfunc=tdigest_resized_final,
+    combinefunc=tdigest_resized_combine,
+    serialfunc = tdigest_resized_serialize,
+    deserialfunc = tdigest_resized_deserialize,
+    parallel = safe
+);
+
+CREATE AGGREGATE quantile_agg(quantile DOUBLE PRECISION, size int, value DOUBLE PRECISION)
+(
+    sfunc=tdigest_quantile_trans,
+    stype=internal,
+    finalfunc=tdigest_quantile_final,
+    combinefunc=tdigest_quantile_combine,
+    serialfunc = tdigest_quantile_serialize,
+    deserialfunc = tdigest_quantile_deserialize,
+    parallel = safe
+);
+
+-- t_digest_distinct digests each distinct value once. It keeps up to 10000 of
+-- them, 8 bytes each plus overhead, so those are counted exactly, see
+-- TDigestDistinctTransState
+CREATE AGGREGATE t_digest_distinct(size int, value DOUBLE PRECISION)
+(
+    sfunc=tdigest_distinct_trans,
+    stype=internal,
+    finalfunc=tdigest_distinct_final,
+    combinefunc=tdigest_distinct_combine,
+    serialfunc = tdigest_distinct_serialize,
+    deserialfunc = tdigest_distinct_deserialize,
+    parallel = safe
+);
+
+-- t_digest_by builds a digest per key, returned as a JSON object of digests
+CREATE AGGREGATE t_digest_by(size int, key text, value DOUBLE PRECISION)
+(
+    sfunc=tdigest_by_trans,
+    stype=internal,
+    finalfunc=tdigest_by_final,
+    combinefunc=tdigest_by_combine,
+    serialfunc = tdigest_by_serialize,
+    deserialfunc = tdigest_by_deserialize,
+    parallel = safe
+);
+
+-- t_digest_json digests the number at path in each payload, skipping rows
+-- without one
+CREATE AGGREGATE t_digest_json(size int, payload jsonb, path text)
+(
+    sfunc=tdigest_json_trans,
+    stype=internal,
+    finalfunc=tdigest_json_final,
+    combinefunc=tdigest_json_combine,
+    serialfunc = tdigest_json_serialize,
+    deserialfunc = tdigest_json_deserialize,
+    parallel = safe
+);
+
+-- t_digest over numeric keeps an exact sum, see tdigest_numeric_sum
+CREATE AGGREGATE t_digest(size int, value numeric)
+(
+    sfunc=tdigest_numeric_trans,
+    stype=internal,
+    finalfunc=tdigest_numeric_final,
+    combinefunc=tdigest_numeric_combine,
+    serialfunc = tdigest_numeric_serialize,
+    deserialfunc = tdigest_numeric_deserialize,
+    parallel = safe
+);
+
+-- merge stored digests, e.g. the partial results of a continuous aggregate
+CREATE AGGREGATE rollup(digest TimescaleTDigest)
+(
+    sfunc=tdigest_rollup_trans,
+    stype=internal,
+    finalfunc=tdigest_rollup_final,
+    combinefunc=tdigest_rollup_combine,
+    serialfunc = tdigest_rollup_serialize,
+    deserialfunc = tdigest_rollup_deserialize,
+    parallel = safe
+);
+
+-- like t_digest, but also tracks the exact mode, see tdigest_exact_mode
+CREATE AGGREGATE t_digest_with_exact_mode(size int, value DOUBLE PRECISION)
+(
+    sfunc=tdigest_mode_trans,
+    stype=internal,
+    finalfunc=tdigest_mode_final,
+    combinefunc=tdigest_mode_combine,
+    serialfunc = tdigest_mode_serialize,
+    deserialfunc = tdigest_mode_deserialize,
+    parallel = safe
+);
+
+-- like t_digest, but also tracks the earliest and latest timestamps, see tdigest_range
+CREATE AGGREGATE t_digest_ranged(size int, ts timestamptz, value DOUBLE PRECISION)
+(
+    sfunc=tdigest_ranged_trans,
+    stype=internal,
+    finalfunc=tdigest_final,
+    combinefunc=tdigest_combine,
+    serialfunc = tdigest_serialize,
+    deserialfunc = tdigest_deserialize,
+    parallel = safe
+);
+
+-- like rollup, but keeps the digests apart until they are read, see tdigest_lazy_merge
+CREATE AGGREGATE lazy_rollup(digest TimescaleTDigest)
+(
+    sfunc=tdigest_lazy_rollup_trans,
+    stype=internal,
+    finalfunc=tdigest_lazy_rollup_final,
+    combinefunc=tdigest_lazy_rollup_combine,
+    serialfunc = tdigest_lazy_rollup_serialize,
+    deserialfunc = tdigest_lazy_rollup_deserialize,
+    parallel = safe
+);
+
+-- like t_digest, but with a grid of quantiles for fast reads, see tdigest_gridded_quantile
+CREATE AGGREGATE t_digest_gridded(size int, value DOUBLE PRECISION)
+(
+    sfunc=tdigest_trans,
+    stype=internal,
+    finalfunc=tdigest_gridded_final,
+    combinefunc=tdigest_combine,
+    serialfunc = tdigest_serialize,
+    deserialfunc = tdigest_deserialize,
+    parallel = safe
+);
+
+-- rollup over digests stored as bytea, see tdigest_rollup_bytea_trans
+CREATE AGGREGATE rollup(serialized bytea)
+(
+    sfunc=tdigest_rollup_bytea_trans,
+    stype=internal,
+    finalfunc=tdigest_rollup_final,
+    combinefunc=tdigest_rollup_combine,
+    serialfunc = tdigest_rollup_serialize,
+    deserialfunc = tdigest_rollup_deserialize,
+    parallel = safe
+);
@@ -0,0 +1,53 @@
+CREATE OR REPLACE FUNCTION tdigest_centroid_array(digest TimescaleTDigest) RETURNS centroid[] IMMUTABLE STRICT PARALLEL SAFE LANGUAGE SQL AS
+$$ SELECT array_agg(ROW(mean, weight)::centroid ORDER BY mean) FROM tdigest_centroids(digest) $$;
+
+CREATE OR REPLACE FUNCTION tdigest_from_centroids(centroids centroid[], size int) RETURNS TimescaleTDigest IMMUTABLE STRICT PARALLEL SAFE LANGUAGE SQL AS
+$$ SELECT tdigest_from_centroids(array_agg(mean), array_agg(weight), size) FROM unnest(centroids) $$;
+
+-- lets digests be written as d::jsonb, e.g. by layers turning query results into JSON
+CREATE OR REPLACE FUNCTION tdigest_to_jsonb(digest TimescaleTDigest) RETURNS jsonb IMMUTABLE STRICT PARALLEL SAFE LANGUAGE SQL AS
+$$ SELECT tdigest_to_json(digest)::jsonb $$;
+
+CREATE CAST (TimescaleTDigest AS jsonb) WITH FUNCTION tdigest_to_jsonb(TimescaleTDigest);
+
+-- and read back with payload::TimescaleTDigest, with the checks of tdigest_from_json
+CREATE OR REPLACE FUNCTION tdigest_from_jsonb(json jsonb) RETURNS TimescaleTDigest IMMUTABLE STRICT PARALLEL SAFE LANGUAGE SQL AS
+$$ SELECT tdigest_from_json(json::json) $$;
+
+CREATE CAST (jsonb AS TimescaleTDigest) WITH FUNCTION tdigest_from_jsonb(jsonb);
+
+-- how many of the digests in a column are byte-for-byte copies of another,
+-- by fingerprint, to tell whether storing them deduplicated would pay off
+CREATE OR REPLACE FUNCTION tdigest_dedup_stats(tbl regclass, col name, OUT total bigint, OUT "distinct" bigint) STABLE STRICT LANGUAGE plpgsql AS
+$$ BEGIN
+    EXECUTE format('SELECT count(%1$I), count(DISTINCT tdigest_fingerprint(%1$I)) FROM %2$s', col, tbl) INTO total, "distinct";
+END $$;
+
+CREATE OR REPLACE FUNCTION tdigest_quantile(digest TimescaleTDigest, quantile DOUBLE PRECISION, extrapolate bool) RETURNS DOUBLE PRECISION IMMUTABLE STRICT PARALLEL SAFE LANGUAGE SQL AS
+$$ SELECT tdigest_quantile_extrapolated(digest, quantile, extrapolate) $$;
+
+-- the timestamps a digest built by t_digest_ranged covers, NULL for other digests
+CREATE OR REPLACE FUNCTION tdigest_range(digest TimescaleTDigest) RETURNS tstzrange IMMUTABLE STRICT PARALLEL SAFE LANGUAGE SQL AS
+$$ SELECT tstzrange(lower, upper, '[]') FROM (SELECT tdigest_range_lower(digest) AS lower, tdigest_range_upper(digest) AS upper) r WHERE lower IS NOT NULL $$;
+
+CREATE OR REPLACE FUNCTION tdigest_lazy_merge(rollup TimescaleTDigestLazyRollup, size int) RETURNS TimescaleTDigest IMMUTABLE STRICT PARALLEL SAFE LANGUAGE SQL AS
+$$ SELECT tdigest_lazy_merge_resized(rollup, size) $$;
+
+-- lazy rollups are merged on demand by the quantile accessors
+CREATE OR REPLACE FUNCTION tdigest_quantile(rollup TimescaleTDigestLazyRollup, quantile DOUBLE PRECISION) RETURNS DOUBLE PRECISION IMMUTABLE STRICT PARALLEL SAFE LANGUAGE SQL AS
+$$ SELECT tdigest_quantile(tdigest_lazy_merge(rollup), quantile) $$;
+
+CREATE OR REPLACE FUNCTION tdigest_quantile_at_value(rollup TimescaleTDigestLazyRollup, value DOUBLE PRECISION) RETURNS DOUBLE PRECISION IMMUTABLE STRICT PARALLEL SAFE LANGUAGE SQL AS
+$$ SELECT tdigest_quantile_at_value(tdigest_lazy_merge(rollup), value) $$;
+
+-- the percentiles SLAs are usually written in, in one row
+CREATE OR REPLACE FUNCTION tdigest_sla(digest TimescaleTDigest,
+    OUT p50 DOUBLE PRECISION, OUT p90 DOUBLE PRECISION, OUT p95 DOUBLE PRECISION, OUT p99 DOUBLE PRECISION, OUT p999 DOUBLE PRECISION)
+IMMUTABLE STRICT PARALLEL SAFE LANGUAGE SQL AS
+$$ SELECT q[1], q[2], q[3], q[4], q[5] FROM tdigest_quantiles(digest, ARRAY[0.5, 0.9, 0.95, 0.99, 0.999]::DOUBLE PRECISION[]) q $$;
+
+CREATE OR REPLACE FUNCTION tdigest_quantile(gridded TimescaleTDigestGridded, quantile DOUBLE PRECISION) RETURNS DOUBLE PRECISION IMMUTABLE STRICT PARALLEL SAFE LANGUAGE SQL AS
+$$ SELECT tdigest_gridded_quantile(gridded, quantile) $$;
+
+-- the other accessors read the digest itself
+CREATE CAST (TimescaleTDigestGridded AS TimescaleTDigest) WITH FUNCTION tdigest_gridded_digest(TimescaleTDigestGridded);
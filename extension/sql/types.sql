@@ -9,3 +9,29 @@ CREATE TYPE TimescaleTDigest (
     OUTPUT = TimescaleTDigest_out,
     STORAGE = extended
 );
+
+CREATE TYPE TimescaleTDigestLazyRollup;
+
+CREATE OR REPLACE FUNCTION TimescaleTDigestLazyRollup_in(cstring) RETURNS TimescaleTDigestLazyRollup IMMUTABLE STRICT PARALLEL SAFE LANGUAGE C AS 'MODULE_PATHNAME', 'timescaletdigestlazyrollup_in_wrapper';
+CREATE OR REPLACE FUNCTION TimescaleTDigestLazyRollup_out(TimescaleTDigestLazyRollup) RETURNS CString IMMUTABLE STRICT PARALLEL SAFE LANGUAGE C AS 'MODULE_PATHNAME', 'timescaletdigestlazyrollup_out_wrapper';
+
+CREATE TYPE TimescaleTDigestLazyRollup (
+    INTERNALLENGTH = variable,
+    INPUT = TimescaleTDigestLazyRollup_in,
+    OUTPUT = TimescaleTDigestLazyRollup_out,
+    STORAGE = extended
+);
+
+CREATE TYPE TimescaleTDigestGridded;
+
+CREATE OR REPLACE FUNCTION TimescaleTDigestGridded_in(cstring) RETURNS TimescaleTDigestGridded IMMUTABLE STRICT PARALLEL SAFE LANGUAGE C AS 'MODULE_PATHNAME', 'timescaletdigestgridded_in_wrapper';
+CREATE OR REPLACE FUNCTION TimescaleTDigestGridded_out(TimescaleTDigestGridded) RETURNS CString IMMUTABLE STRICT PARALLEL SAFE LANGUAGE C AS 'MODULE_PATHNAME', 'timescaletdigestgridded_out_wrapper';
+
+CREATE TYPE TimescaleTDigestGridded (
+    INTERNALLENGTH = variable,
+    INPUT = TimescaleTDigestGridded_in,
+    OUTPUT = TimescaleTDigestGridded_out,
+    STORAGE = extended
+);
+
+CREATE TYPE centroid AS (mean DOUBLE PRECISION, weight BIGINT);
@@ -5,6 +5,11 @@ mod type_builder;
 
 pgx::pg_module_magic!();
 
+#[pgx::pg_guard]
+pub extern "C" fn _PG_init() {
+    tdigest::register_gucs();
+}
+
 #[cfg(test)]
 pub mod pg_test {
     pub fn setup(_options: Vec<&str>) {
@@ -1,8 +1,8 @@
 
 use std::{
+    collections::{HashMap, HashSet},
     convert::TryInto,
     cmp::min,
-    mem::replace,
     slice,
 };
 
@@ -15,7 +15,6 @@ use flat_serialize::*;
 
 use crate::{
     aggregate_utils::{aggregate_mctx, in_aggregate_context},
-    debug_inout_funcs,
     flatten,
     palloc::{Internal, in_memory_context}, pg_type
 };
@@ -23,34 +22,229 @@ use crate::{
 use tdigest::{
     TDigest,
     Centroid,
+    CentroidSlices,
 };
 
+// The ways digest operations can fail. Every error is raised through `raise`,
+// which prefixes the message with the error's code, so that each kind of error
+// can be told apart, and grepped for, however its details vary.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TDigestError {
+    // a transition or combine function called outside of an aggregate
+    NotAggregate,
+    // a quantile outside of [0, 1]
+    BadQuantile(f64),
+    // a digest size of 0, named by the argument that gave it
+    SizeZero(&'static str),
+    // any other argument a function can't accept
+    BadArgument(String),
+    // a value too large to be stored in a digest
+    OutOfRange(String),
+    // stored or input data that isn't a valid digest
+    CorruptData(String),
+    // a digest that can't be converted to another representation
+    Internal(String),
+}
+
+impl TDigestError {
+    pub fn code(&self) -> &'static str {
+        use TDigestError::*;
+        match self {
+            NotAggregate => "TD001",
+            BadQuantile(_) => "TD002",
+            SizeZero(_) => "TD003",
+            BadArgument(_) => "TD004",
+            OutOfRange(_) => "TD005",
+            CorruptData(_) => "TD006",
+            Internal(_) => "TD007",
+        }
+    }
+
+    pub fn raise(self) -> ! {
+        pgx::error!("{}: {}", self.code(), self)
+    }
+}
+
+impl std::fmt::Display for TDigestError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use TDigestError::*;
+        match self {
+            NotAggregate => write!(f, "cannot call as non-aggregate"),
+            BadQuantile(quantile) => write!(f, "quantile must be between 0 and 1, got {}", quantile),
+            SizeZero(size) => write!(f, "{} must be at least 1", size),
+            BadArgument(problem) | OutOfRange(problem) | Internal(problem) => write!(f, "{}", problem),
+            CorruptData(problem) => write!(f, "invalid TimescaleTDigest: {}", problem),
+        }
+    }
+}
+
 // Intermediate state kept in postgres.  This is a tdigest object paired
-// with a vector of values that still need to be inserted.
+// with a vector of values that still need to be inserted. Other aggregates
+// can build a digest the way t_digest does with new(), push() and finish().
 #[derive(Serialize, Deserialize, Clone)]
 pub struct TDigestTransState {
-    #[serde(skip_serializing)]
-    buffer: Vec<f64>,
+    // Values are buffered as runs of (value, repetitions), so that the long
+    // runs of identical readings common in time-series take up a single
+    // entry. Data without runs pays for it with twice the buffer size.
+    #[serde(skip)]
+    buffer: Vec<(f64, u64)>,
+    // the number of values in the buffer, i.e. the sum of its repetitions
+    #[serde(skip)]
+    buffered: usize,
     digested: TDigest,
+    // the first and last values pushed, in the aggregate's input order; last
+    // is only meaningful once there is a first
+    first: Option<f64>,
+    last: f64,
+    // the earliest and latest timestamps seen by t_digest_ranged
+    range: Option<(i64, i64)>,
+    // the rows t_digest_sampled has seen, kept or not
+    #[serde(skip)]
+    sampled_rows: u64,
+}
+
+// The range stored for digests without timestamps, chosen so that it is
+// empty, and so that its union with any range leaves that range as it is.
+const NO_RANGE: (i64, i64) = (std::i64::MAX, std::i64::MIN);
+
+fn union_ranges(a: Option<(i64, i64)>, b: Option<(i64, i64)>) -> Option<(i64, i64)> {
+    match (a, b) {
+        (Some((lower_a, upper_a)), Some((lower_b, upper_b))) => Some((lower_a.min(lower_b), upper_a.max(upper_b))),
+        (a, b) => a.or(b),
+    }
 }
 
 impl TDigestTransState {
+    // The buffer is allocated up-front at the size at which it will be
+    // flushed, and reused after every flush, so a group only ever allocates
+    // it once. This trades a little memory for small groups against the
+    // repeated reallocations of growing the buffer from empty. Under a total
+    // buffer budget the buffer grows as needed instead, so that the many
+    // small groups the budget is meant for don't each take a full buffer.
+    pub fn new(size: usize) -> Self {
+        let capacity = match max_total_buffer() {
+            0 => min(size, max_buffer()),
+            _ => 0,
+        };
+        TDigestTransState {
+            buffer: Vec::with_capacity(capacity),
+            buffered: 0,
+            digested: TDigest::new_with_size(size),
+            first: None,
+            last: std::f64::NAN,
+            range: None,
+            sampled_rows: 0,
+        }
+    }
+
+    // A state for a stored digest, keeping its first and last values.
+    fn from_digest(digest: &TimescaleTDigest) -> Self {
+        let (first, last) = match digest.ends() {
+            Some((first, last)) => (Some(first), last),
+            None => (None, std::f64::NAN),
+        };
+        TDigestTransState {
+            buffer: vec![],
+            buffered: 0,
+            digested: digest.to_tdigest(),
+            first,
+            last,
+            range: digest.range(),
+            sampled_rows: 0,
+        }
+    }
+
+    fn ends(&self) -> Option<(f64, f64)> {
+        self.first.map(|first| (first, self.last))
+    }
+
+    // Set the first and last values to those of `earlier` followed by `later`,
+    // and the range to the union of theirs.
+    fn set_ends(&mut self, earlier: &TDigestTransState, later: &TDigestTransState) {
+        self.first = earlier.first.or(later.first);
+        self.last = if later.first.is_some() { later.last } else { earlier.last };
+        self.range = union_ranges(earlier.range, later.range);
+    }
+
+    // Widen the range to include `time`.
+    pub fn add_time(&mut self, time: i64) {
+        self.range = union_ranges(self.range, Some((time, time)));
+    }
+
     // Add a new value, recalculate the digest if we've crossed a threshold.
-    // TODO threshold is currently set to number of digest buckets, should this be adjusted
-    fn push(&mut self, value: f64) {
-        self.buffer.push(value);
-        if self.buffer.len() >= self.digested.max_size() {
+    // The threshold is the number of digest buckets, limited by the
+    // tdigest_max_buffer GUC so that huge digests can't buffer without bound,
+    // unless the tdigest_flush_mode GUC leaves it all for the end.
+    // Since the threshold counts values rather than buffer entries, the
+    // resulting digest is the same as if the values were buffered one by one.
+    pub fn push(&mut self, value: f64) {
+        // NaN is skipped like NULL: it has no place among the centroids, and a
+        // NaN min or max would sort above every value in SQL while estimates
+        // treat it as missing. Aggregates with bookkeeping of their own skip
+        // it before doing any.
+        if value.is_nan() {
+            return
+        }
+        // -0 equals 0, but ordering the two depends on which comes first, so
+        // e.g. the max could come out as -0 while the min is 0
+        let value = if value == 0.0 { 0.0 } else { value };
+        if self.first.is_none() {
+            self.first = Some(value);
+        }
+        self.last = value;
+        let capacity = self.buffer.capacity();
+        match self.buffer.last_mut() {
+            Some((last, repetitions)) if *last == value => *repetitions += 1,
+            _ => self.buffer.push((value, 1)),
+        }
+        self.buffered += 1;
+        if self.buffered >= min(self.digested.max_size(), max_buffer()) && flush_mode() == FlushMode::Eager {
             self.digest()
         }
+
+        let budget = max_total_buffer();
+        if budget > 0 && capacity != self.buffer.capacity() {
+            let total = account_buffer(capacity, self.buffer.capacity());
+            if total > budget {
+                // flush early, and give the memory back for other groups
+                self.digest();
+                account_buffer(self.buffer.capacity(), 0);
+                self.buffer = vec![];
+            }
+        }
+    }
+
+    // A copy of the state in the current memory context, e.g. a combined one,
+    // with its buffer counted against tdigest_max_total_buffer like those of
+    // the states values are pushed into.
+    fn clone_accounted(&self) -> Self {
+        let copy = self.clone();
+        if max_total_buffer() > 0 {
+            account_buffer(0, copy.buffer.capacity());
+        }
+        copy
+    }
+
+    // Whether no values have been added to this state.
+    fn is_empty(&self) -> bool {
+        self.buffer.is_empty() && self.digested.count() == 0.0
     }
 
     // Update the digest with all accumulated values.
-    fn digest(&mut self) {
+    pub fn digest(&mut self) {
         if self.buffer.is_empty() {
             return
         }
-        let new = replace(&mut self.buffer, vec![]);
-        self.digested = self.digested.merge_unsorted(new)
+        self.digested = self.digested.merge_unsorted_runs_in_place(&mut self.buffer);
+        self.buffer.clear();
+        self.buffered = 0;
+    }
+
+    // The digest of all the values pushed so far, as t_digest would return it.
+    // The state can keep taking values afterwards.
+    pub fn finish(&mut self) -> TimescaleTDigest<'static> {
+        self.digest();
+        TimescaleTDigest::from_tdigest_with(&self.digested, None, None, self.ends(), self.range)
     }
 }
 
@@ -68,7 +262,7 @@ pub fn tdigest_trans(
 ) -> Option<Internal<TDigestTransState>> {
     let mctx = aggregate_mctx(fcinfo);
     let mctx = match mctx {
-        None => pgx::error!("cannot call as non-aggregate"),
+        None => TDigestError::NotAggregate.raise(),
         Some(mctx) => mctx,
     };
     unsafe {
@@ -78,10 +272,8 @@ pub fn tdigest_trans(
                 Some(value) => value,
             };
             let mut state = match state {
-                None => TDigestTransState{
-                    buffer: vec![],
-                    digested: TDigest::new_with_size(size as _),
-                }.into(),
+                None if size == 0 => TDigestError::SizeZero("t_digest size").raise(),
+                None => TDigestTransState::new(size as _).into(),
                 Some(state) => state,
             };
             state.push(value);
@@ -90,6 +282,448 @@ pub fn tdigest_trans(
     }
 }
 
+// Transition function for t_digest_sampled, which only adds about a fraction
+// `sample_rate` of the rows to the digest, in exchange for being faster on
+// large inputs. Each row is kept or dropped by hashing the seed with the row's
+// position among those the state has seen, so every row is as likely to be
+// kept as any other, whatever its value, and the same query over the same rows
+// in the same order gives the same digest every time. Rows arriving in another
+// order, e.g. under a different parallel plan, give a different sample. The
+// count and sum are those of the sample.
+#[pg_extern]
+pub fn tdigest_sampled_trans(
+    state: Option<Internal<TDigestTransState>>,
+    size: int,
+    sample_rate: f64,
+    seed: i64,
+    value: Option<f64>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Internal<TDigestTransState>> {
+    if !(sample_rate > 0.0 && sample_rate <= 1.0) {
+        TDigestError::BadArgument(format!("sample_rate must be greater than 0 and at most 1, got {}", sample_rate)).raise()
+    }
+    let value = match value {
+        None => return state,
+        Some(value) => value,
+    };
+    // the state has to exist to count the row in it, even if it's dropped
+    let mut state = match state {
+        None if size == 0 => TDigestError::SizeZero("t_digest_sampled size").raise(),
+        None => {
+            let mctx = aggregate_mctx(fcinfo).unwrap_or_else(|| TDigestError::NotAggregate.raise());
+            unsafe { in_memory_context(mctx, || TDigestTransState::new(size as _).into()) }
+        },
+        Some(state) => state,
+    };
+    let row = state.sampled_rows;
+    state.sampled_rows += 1;
+    if sampled(row, seed, sample_rate) {
+        tdigest_trans(Some(state), size, Some(value), fcinfo)
+    } else {
+        Some(state)
+    }
+}
+
+// Whether to keep the row at position `row` when sampling at `rate`, by
+// hashing it with the seed using splitmix64's finalizer, which unlike std's
+// hashers is guaranteed to stay the same.
+fn sampled(row: u64, seed: i64, rate: f64) -> bool {
+    let mut hash = row ^ (seed as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+    hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    hash ^= hash >> 31;
+    (hash >> 11) as f64 / (1u64 << 53) as f64 < rate
+}
+
+// Transition function for t_digest_log, which digests the natural logarithms
+// of the values. Interpolating between centroids then follows the geometric
+// spacing of data spanning orders of magnitude, such as latencies, giving much
+// better estimates of the far tail. The digest stores the logarithms, so it is
+// read with tdigest_log_quantile and tdigest_log_quantile_at_value; the other
+// accessors describe the logarithms.
+#[pg_extern]
+pub fn tdigest_log_trans(
+    state: Option<Internal<TDigestTransState>>,
+    size: int,
+    value: Option<f64>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Internal<TDigestTransState>> {
+    match value {
+        // NaN stays NaN, which push skips
+        Some(value) if value <= 0.0 =>
+            TDigestError::BadArgument(format!("t_digest_log values must be positive, got {}", value)).raise(),
+        value => tdigest_trans(state, size, value.map(f64::ln), fcinfo),
+    }
+}
+
+// Transition function for t_digest_ranged, which also records the earliest and
+// latest timestamps of the rows, see tdigest_range. Rows with a NULL or NaN
+// value are ignored entirely; a NULL timestamp only leaves the range as it is.
+#[pg_extern]
+pub fn tdigest_ranged_trans(
+    state: Option<Internal<TDigestTransState>>,
+    size: int,
+    time: Option<TimestampWithTimeZone>,
+    value: Option<f64>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Internal<TDigestTransState>> {
+    if value.map_or(true, f64::is_nan) {
+        return state
+    }
+    let mut state = tdigest_trans(state, size, value, fcinfo);
+    if let (Some(state), Some(time)) = (state.as_mut(), time) {
+        // timestamptz datums are microseconds since 2000-01-01, by value
+        state.add_time(time.into_datum().unwrap() as i64);
+    }
+    state
+}
+
+// Transition function for t_digest_continue, which adds values to an existing
+// digest, e.g. to append today's data to yesterday's digest in one query. The
+// state starts out as the seed, at the seed's size; the seed's exact sum and
+// mode, if any, are not carried over. Null values are ignored.
+#[pg_extern]
+pub fn tdigest_continue_trans(
+    state: Option<Internal<TDigestTransState>>,
+    seed: Option<TimescaleTDigest>,
+    value: Option<f64>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Internal<TDigestTransState>> {
+    let mctx = aggregate_mctx(fcinfo);
+    let mctx = match mctx {
+        None => TDigestError::NotAggregate.raise(),
+        Some(mctx) => mctx,
+    };
+    unsafe {
+        in_memory_context(mctx, || {
+            let mut state = match state {
+                Some(state) => state,
+                None => {
+                    let seed = seed.unwrap_or_else(|| TDigestError::BadArgument("t_digest_continue requires a seed digest".to_string()).raise());
+                    TDigestTransState::from_digest(&seed).into()
+                },
+            };
+            if let Some(value) = value {
+                state.push(value);
+            }
+            Some(state)
+        })
+    }
+}
+
+// Upper bound on the number of centroids a digest may keep while being merged,
+// 0 for no limit. Larger digests are re-compressed to this size before they're
+// merged, so a rollup over many large digests can't use more than about twice
+// this many centroids at once. The price is accuracy: the result is only as
+// good as a digest of this size, regardless of the size it was created with.
+static TDIGEST_MAX_CENTROIDS: GucSetting<i32> = GucSetting::new(0);
+
+// Upper bound on the number of values a transition state buffers before
+// merging them into its digest. Normally the buffer is flushed once it holds
+// as many values as the digest has buckets, which for very large digests
+// would let a single group hold a great deal of unmerged input. A smaller
+// buffer bounds that memory, but it's flushed more often, and every flush
+// has to walk all of the digest's centroids.
+static TDIGEST_MAX_BUFFER: GucSetting<i32> = GucSetting::new(65536);
+
+fn max_buffer() -> usize {
+    TDIGEST_MAX_BUFFER.get() as usize
+}
+
+// Upper bound on the buffer entries allocated by all the transition states of
+// an aggregate's groups together, 0 for no limit. Once they've allocated more,
+// the group that just grew its buffer flushes it early and frees it. This is
+// what bounds the memory of a GROUP BY over many groups, where
+// tdigest_max_buffer only bounds each group's. Each entry takes 16 bytes.
+static TDIGEST_MAX_TOTAL_BUFFER: GucSetting<i32> = GucSetting::new(0);
+
+fn max_total_buffer() -> usize {
+    TDIGEST_MAX_TOTAL_BUFFER.get() as usize
+}
+
+// How rollup merges digests, `fast` or `stable`. `fast` merges each digest
+// into the result as it arrives, compressing after every step, so the result
+// varies slightly with the order of the digests. `stable` keeps every digest's
+// centroids until the final function, which sorts them all together and
+// compresses them once, giving the same result in any order at the cost of
+// holding all of the digests in memory at once.
+static TDIGEST_MERGE_STRATEGY: GucSetting<Option<&'static str>> = GucSetting::new(Some("fast"));
+
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+enum MergeStrategy {
+    Fast,
+    Stable,
+}
+
+fn merge_strategy() -> MergeStrategy {
+    match TDIGEST_MERGE_STRATEGY.get().as_deref() {
+        None | Some("fast") => MergeStrategy::Fast,
+        Some("stable") => MergeStrategy::Stable,
+        Some(other) => TDigestError::BadArgument(format!(
+            "timescale_analytics.tdigest_merge_strategy must be fast or stable, got {}", other)).raise(),
+    }
+}
+
+// When transition states merge their buffers into their digests, `eager` or
+// `final_only`. `eager` does so whenever the buffer fills, see push.
+// `final_only` buffers every value until the digest is needed, so they're all
+// merged in one pass, which is more accurate, but takes 16 bytes per value for
+// as long as the aggregate runs; only tdigest_max_total_buffer still flushes
+// early.
+static TDIGEST_FLUSH_MODE: GucSetting<Option<&'static str>> = GucSetting::new(Some("eager"));
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum FlushMode {
+    Eager,
+    FinalOnly,
+}
+
+fn flush_mode() -> FlushMode {
+    match TDIGEST_FLUSH_MODE.get().as_deref() {
+        None | Some("eager") => FlushMode::Eager,
+        Some("final_only") => FlushMode::FinalOnly,
+        Some(other) => TDigestError::BadArgument(format!(
+            "timescale_analytics.tdigest_flush_mode must be eager or final_only, got {}", other)).raise(),
+    }
+}
+
+// The buffer entries allocated by the states in a memory context, which for
+// transition functions is the aggregate's, shared by all of its groups. Each
+// account lives in the context it counts, and is kept in a list alongside
+// those of the other contexts, e.g. when a query has two separate aggregation
+// steps, until its context is reset, which frees the states it counted.
+#[repr(C)]
+struct BufferAccount {
+    callback: pg_sys::MemoryContextCallback,
+    context: pg_sys::MemoryContext,
+    entries: usize,
+    next: *mut BufferAccount,
+}
+
+// postgres backends are single-threaded, so there is no concurrent access
+static mut BUFFER_ACCOUNTS: *mut BufferAccount = std::ptr::null_mut();
+
+// Record a state's buffer going from `before` to `after` entries, returning
+// the total across the current memory context.
+fn account_buffer(before: usize, after: usize) -> usize {
+    unsafe {
+        let account = &mut *buffer_account(pg_sys::CurrentMemoryContext);
+        account.entries = (account.entries + after).saturating_sub(before);
+        account.entries
+    }
+}
+
+// The account of a context, opened on the first buffer allocated in it.
+unsafe fn buffer_account(context: pg_sys::MemoryContext) -> *mut BufferAccount {
+    let mut account = BUFFER_ACCOUNTS;
+    while !account.is_null() {
+        if (*account).context == context {
+            return account
+        }
+        account = (*account).next;
+    }
+
+    // palloc0 allocates in the current context, the one being counted, and
+    // its reset callback is registered once, along with the account
+    let account = pg_sys::palloc0(std::mem::size_of::<BufferAccount>()) as *mut BufferAccount;
+    (*account).callback.func = Some(forget_buffer_account);
+    (*account).callback.arg = account as *mut _;
+    (*account).context = context;
+    (*account).next = BUFFER_ACCOUNTS;
+    pg_sys::MemoryContextRegisterResetCallback(context, &mut (*account).callback);
+    BUFFER_ACCOUNTS = account;
+    account
+}
+
+// Unlink an account whose context is being reset, before it's freed along
+// with the context's other memory.
+unsafe extern "C" fn forget_buffer_account(account: *mut std::os::raw::c_void) {
+    let account = account as *mut BufferAccount;
+    if BUFFER_ACCOUNTS == account {
+        BUFFER_ACCOUNTS = (*account).next;
+        return
+    }
+    let mut previous = BUFFER_ACCOUNTS;
+    while !previous.is_null() {
+        if (*previous).next == account {
+            (*previous).next = (*account).next;
+            return
+        }
+        previous = (*previous).next;
+    }
+}
+
+pub(crate) fn register_gucs() {
+    GucRegistry::define_int_guc(
+        "timescale_analytics.tdigest_max_centroids",
+        "Maximum number of centroids kept while merging t-digests.",
+        "Digests with more centroids are re-compressed before merging, bounding memory use at \
+        the cost of accuracy. 0 means no limit.",
+        &TDIGEST_MAX_CENTROIDS,
+        0,
+        std::i32::MAX,
+        GucContext::Userset,
+    );
+    GucRegistry::define_int_guc(
+        "timescale_analytics.tdigest_max_buffer",
+        "Maximum number of values buffered by a t-digest aggregate before they're merged.",
+        "Values are merged once the buffer holds as many values as the digest has buckets, \
+        or this many, whichever is smaller.",
+        &TDIGEST_MAX_BUFFER,
+        1,
+        std::i32::MAX,
+        GucContext::Userset,
+    );
+    GucRegistry::define_int_guc(
+        "timescale_analytics.tdigest_max_total_buffer",
+        "Maximum number of values buffered by all the groups of a t-digest aggregate together.",
+        "Once the groups' buffers have room for more values, each group that grows its buffer \
+        merges it early and frees it. 0 means no limit.",
+        &TDIGEST_MAX_TOTAL_BUFFER,
+        0,
+        std::i32::MAX,
+        GucContext::Userset,
+    );
+    GucRegistry::define_string_guc(
+        "timescale_analytics.tdigest_merge_strategy",
+        "How rollup merges t-digests, fast or stable.",
+        "fast merges digests one at a time, so the result depends slightly on their order. \
+        stable merges all of them at once in the final function, giving the same result in any order.",
+        &TDIGEST_MERGE_STRATEGY,
+        GucContext::Userset,
+    );
+    GucRegistry::define_string_guc(
+        "timescale_analytics.tdigest_flush_mode",
+        "When t-digest aggregates merge buffered values, eager or final_only.",
+        "eager merges them whenever the buffer fills. final_only keeps every value buffered \
+        until the end and merges them at once, which is more accurate but holds all of them in memory.",
+        &TDIGEST_FLUSH_MODE,
+        GucContext::Userset,
+    );
+}
+
+fn cap_centroids(digest: TDigest) -> TDigest {
+    match TDIGEST_MAX_CENTROIDS.get() {
+        cap if cap > 0 && digest.max_size() > cap as usize => digest.compressed_to(cap as usize),
+        _ => digest,
+    }
+}
+
+// Merge two transition states, if either is missing the other is used as-is.
+fn combine(
+    state1: Option<&TDigestTransState>,
+    state2: Option<&TDigestTransState>,
+) -> Option<TDigestTransState> {
+    match (state1, state2) {
+        (None, None) => None,
+        (None, Some(state2)) => Some(state2.clone_accounted()),
+        (Some(state1), None) => Some(state1.clone_accounted()),
+        // a partial that never saw any values (e.g. a worker whose inputs were
+        // all NULL) has nothing to add, merging it would only disturb the
+        // other side's centroids
+        // the first and last values are still kept, a rollup under the
+        // stable strategy combines states holding nothing else
+        (Some(state1), Some(state2)) if state1.is_empty() || state2.is_empty() => {
+            let mut combined = if state1.is_empty() { state2.clone_accounted() } else { state1.clone_accounted() };
+            combined.set_ends(state1, state2);
+            Some(combined)
+        },
+        (Some(state1), Some(state2)) => {
+            // states of different sizes, e.g. when rolling up digests built
+            // with different sizes, are merged at the larger one, by folding
+            // the smaller into a copy of it
+            let (larger, smaller) = if state2.digested.max_size() > state1.digested.max_size() {
+                (state2, state1)
+            } else {
+                (state1, state2)
+            };
+            let mut combined = larger.clone_accounted();
+            fold_into(&mut combined, smaller);
+            combined.set_ends(state1, state2);
+            Some(combined)
+        }
+    }
+}
+
+// Merge other into state, reusing state's centroid storage. The result is the
+// same as merging the two with merge_digests, at state's size, unless their
+// ranges don't overlap and the centroids fit, in which case they are simply
+// concatenated. The first and last values are left to the caller, as the order
+// of the two varies.
+fn fold_into(state: &mut TDigestTransState, other: &TDigestTransState) {
+    state.digest();
+    state.digested = cap_centroids(std::mem::take(&mut state.digested));
+
+    let mut other_state;
+    let other = if other.buffer.is_empty() {
+        other
+    } else {
+        other_state = other.clone();
+        other_state.digest();
+        &other_state
+    };
+    let capped;
+    let other = match TDIGEST_MAX_CENTROIDS.get() {
+        cap if cap > 0 && other.digested.max_size() > cap as usize => {
+            capped = other.digested.compressed_to(cap as usize);
+            &capped
+        },
+        _ => &other.digested,
+    };
+
+    state.digested.merge_digest_in_place(other);
+    debug_assert!(state.digested.raw_centroids().len() <= state.digested.max_size());
+}
+
+// Like combine, but takes ownership of state1, which already lives in the
+// aggregate's memory context. In the common case of a parallel worker that
+// saw no rows, state1 is handed back as-is, without copying or merging
+// anything. Otherwise, when state1 is at least as large as state2, state2 is
+// folded into it in place, so a deep rollup keeps reusing state1's centroid
+// storage instead of allocating a new digest at every step. state2 may be in a
+// shorter-lived context (e.g. if it was just deserialized), so it is only read.
+fn combine_into(
+    state1: Option<Internal<TDigestTransState>>,
+    state2: Option<&TDigestTransState>,
+) -> Option<Internal<TDigestTransState>> {
+    match (state1, state2) {
+        (Some(state1), None) => Some(state1),
+        (Some(state1), Some(state2)) if state2.is_empty() => Some(state1),
+        (Some(mut state1), Some(state2))
+        if !state1.is_empty() && state1.digested.max_size() >= state2.digested.max_size() => {
+            fold_into(&mut state1, state2);
+            state1.first = state1.first.or(state2.first);
+            if state2.first.is_some() {
+                state1.last = state2.last;
+            }
+            state1.range = union_ranges(state1.range, state2.range);
+            Some(state1)
+        },
+        (state1, state2) => combine(state1.as_deref(), state2).map(Internal::from),
+    }
+}
+
+// Like combine_into, for a state that can't be handed over, e.g. the one inside
+// rollup's state: later is folded into state in place, in the order given,
+// unless later is the larger of the two, the only case that still copies.
+// Rolling up one digest at a time, this saves copying the running result at
+// every step; merging batches of digests at once instead turned out slower
+// than folding them in one by one.
+fn combine_in_place(state: &mut TDigestTransState, later: &TDigestTransState) {
+    if !later.is_empty() {
+        if state.is_empty() || state.digested.max_size() < later.digested.max_size() {
+            *state = combine(Some(&*state), Some(later)).unwrap();
+            return
+        }
+        fold_into(state, later);
+    }
+    state.first = state.first.or(later.first);
+    if later.first.is_some() {
+        state.last = later.last;
+    }
+    state.range = union_ranges(state.range, later.range);
+}
+
 // PG function for merging digests.
 #[pg_extern]
 pub fn tdigest_combine(
@@ -99,38 +733,43 @@ pub fn tdigest_combine(
 ) -> Option<Internal<TDigestTransState>> {
     let mctx = aggregate_mctx(fcinfo);
     let mctx = match mctx {
-        None => pgx::error!("cannot call as non-aggregate"),
+        None => TDigestError::NotAggregate.raise(),
         Some(mctx) => mctx,
     };
     unsafe {
         in_memory_context(mctx, || {
-            match (state1, state2) {
-                (None, None) => None,
-                (None, Some(state2)) => Some(state2.clone().into()),
-                (Some(state1), None) => Some(state1.clone().into()),
-                (Some(state1), Some(state2)) => {
-                    let digvec = vec![state1.digested.clone(), state2.digested.clone()];
-                    if !state1.buffer.is_empty() {
-                        digvec[0].merge_unsorted(state1.buffer.clone());  // merge_unsorted should take a reference
-                    }
-                    if !state2.buffer.is_empty() {
-                        digvec[1].merge_unsorted(state2.buffer.clone());
-                    }
-
-                    Some(TDigestTransState {
-                            buffer: vec![],
-                            digested: TDigest::merge_digests(digvec),
-                        }.into()
-                    )
-                }
-            }
+            combine_into(state1, state2.as_deref())
         })
     }
 }
 
+// The combine function of t_digest_ordered. Postgres never aggregates in
+// parallel with an ORDER BY, so this only runs for t_digest_ordered without
+// one, and then which worker saw the first or last value isn't known, so
+// rather than picking one both are dropped.
+#[pg_extern]
+pub fn tdigest_ordered_combine(
+    state1: Option<Internal<TDigestTransState>>,
+    state2: Option<Internal<TDigestTransState>>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Internal<TDigestTransState>> {
+    let mut combined = tdigest_combine(state1, state2, fcinfo)?;
+    if combined.first.is_some() {
+        notice!("t_digest_ordered ran in parallel, its first and last values are not known");
+        combined.first = None;
+        combined.last = std::f64::NAN;
+    }
+    Some(combined)
+}
+
 #[allow(non_camel_case_types)]
 type bytea = pg_sys::Datum;
 
+// The transition state is serialized with bincode, like every other state,
+// rather than in the flat layout of the final TimescaleTDigest, as the layout
+// limits the count to u32::MAX, which only the final digest has to meet;
+// bench_serialize compares the two. Only the buffer is lost, and it is always
+// flushed first.
 #[pg_extern]
 pub fn tdigest_serialize(
     mut state: Internal<TDigestTransState>,
@@ -144,7 +783,30 @@ pub fn tdigest_deserialize(
     bytes: bytea,
     _internal: Option<Internal<()>>,
 ) -> Internal<TDigestTransState> {
-    crate::do_deserialize!(bytes, TDigestTransState)
+    let state: Internal<TDigestTransState> = crate::do_deserialize!(bytes, TDigestTransState);
+    if let Err(problem) = check_centroids(state.digested.raw_centroids()) {
+        TDigestError::CorruptData(format!("bad serialized state, {}", problem)).raise()
+    }
+    state
+}
+
+// Check that centroids make sense: every weight is positive, every mean is
+// finite, and the means are in order. Everything that builds a digest out of
+// user-supplied input goes through this, so that estimates never have to deal
+// with e.g. dividing by a zero weight.
+fn check_centroids(cents: &[Centroid]) -> Result<(), String> {
+    for (i, cent) in cents.iter().enumerate() {
+        if !(cent.weight() > 0.0) {
+            return Err(format!("centroid {} has non-positive weight {}", i, cent.weight()))
+        }
+        if !cent.mean().is_finite() {
+            return Err(format!("centroid {} has non-finite mean {}", i, cent.mean()))
+        }
+        if i > 0 && cent.mean() < cents[i - 1].mean() {
+            return Err(format!("centroid {} has a smaller mean than the one before it", i))
+        }
+    }
+    Ok(())
 }
 
 // PG object for the digest.
@@ -156,200 +818,6803 @@ pg_type! {
         sum: f64,
         min: f64,
         max: f64,
+        // since version 2, the exact most common value if it was tracked, NaN
+        // otherwise
+        mode: [f64; if self.version >= 2 { 1 } else { 0 }],
+        // since version 3, the first and last values in input order, NaN if
+        // not known
+        first: [f64; if self.version >= 3 { 1 } else { 0 }],
+        last: [f64; if self.version >= 3 { 1 } else { 0 }],
+        // since version 4, the earliest and latest timestamps of the values,
+        // as postgres stores timestamps; a lower bound after the upper one,
+        // as written for digests without timestamps, means not known
+        range_lower: [i64; if self.version >= 4 { 1 } else { 0 }],
+        range_upper: [i64; if self.version >= 4 { 1 } else { 0 }],
         means: [f64; std::cmp::min(self.buckets, self.count)],
         weights: [u32; std::cmp::min(self.buckets, self.count)],
+        // since version 2, the exact sum of numeric input as decimal text,
+        // empty otherwise
+        exact_sum_len: [u32; if self.version >= 2 { 1 } else { 0 }],
+        exact_sum: [u8; if self.version >= 2 { self.exact_sum_len[0] } else { 0 }],
+        // since version 5, a CRC-32 of everything before it but the varlena
+        // header, see tdigest_verify_checksum
+        checksum: [u32; if self.version >= 5 { 1 } else { 0 }],
     }
 }
 
-debug_inout_funcs!(TimescaleTDigest);
+// The text format is the same JSON as tdigest_to_json's, which always includes
+// the size the digest was built with.
+impl<'input> InOutFuncs for TimescaleTDigest<'input> {
+    fn output(&self, buffer: &mut StringInfo) {
+        use std::io::Write;
+        let json = serde_json::to_string(self)
+            .unwrap_or_else(|e| TDigestError::Internal(format!("cannot output TimescaleTDigest: {}", e)).raise());
+        let _ = write!(buffer, "{}", json);
+    }
+
+    fn input(input: &std::ffi::CStr) -> Self
+    where
+        Self: Sized,
+    {
+        let input = input.to_str()
+            .unwrap_or_else(|e| TDigestError::CorruptData(e.to_string()).raise());
+        serde_json::from_str::<TimescaleTDigest<'static>>(input)
+            .unwrap_or_else(|e| TDigestError::CorruptData(e.to_string()).raise())
+    }
+}
 
 impl<'input> TimescaleTDigest<'input> {
-    fn to_tdigest(&self) -> TDigest {
-        let size = min(*self.buckets, *self.count) as usize;
-        let mut cents: Vec<Centroid> = Vec::new();
+    // Version of the layout written by this build, readers accept any version
+    // up to it. Fields added in later versions must be absent from data of
+    // earlier ones, e.g. as arrays whose length is 0 unless the version is
+    // recent enough, so that digests stored by older builds remain readable.
+    // Version 1 is the layout of the first release, buckets to max and the
+    // centroids; 2 added the mode and exact sum, 3 the first and last values,
+    // 4 the range and 5 the checksum.
+    pub const CURRENT_VERSION: u8 = 5;
 
-        for i in 0..size {
-            cents.push(Centroid::new(self.means[i], self.weights[i] as f64));
-        }
+    // The digest as a tdigest::TDigest, for code in other modules that wants
+    // to keep merging into or estimating from it without going through SQL.
+    pub fn to_tdigest(&self) -> TDigest {
+        // centroids_iter() can't tell how many centroids there are, so collect()
+        // would grow the vector step by step; there are at most as many as slots
+        let mut cents: Vec<Centroid> = Vec::with_capacity(self.means.len());
+        cents.extend(self.centroids_iter().map(|(mean, weight)| Centroid::new(mean, weight as f64)));
 
         TDigest::new(cents, *self.sum, *self.count as f64, *self.max, *self.0.min, *self.buckets as usize)
     }
-}
 
-// PG function to generate a user-facing TimescaleTDigest object from an internal TDigestTransState.
-#[pg_extern]
-fn tdigest_final(
-    state: Option<Internal<TDigestTransState>>,
-    fcinfo: pg_sys::FunctionCallInfo,
-) -> Option<TimescaleTDigest<'static>> {
-    unsafe {
-        in_aggregate_context(fcinfo, || {
-            let mut state = match state {
-                None => return None,
-                Some(state) => state,
-            };
-            state.digest();
+    // The (mean, weight) of each centroid, read straight from the stored
+    // arrays. A digest usually has fewer centroids than there are slots, the
+    // unused slots at the end are zeroed and skipped.
+    pub fn centroids_iter(&self) -> impl Iterator<Item = (f64, u64)> + '_ {
+        self.means.iter().zip(self.weights.iter())
+            .take_while(|(_, &weight)| weight > 0)
+            .map(|(&mean, &weight)| (mean, weight as u64))
+    }
+
+    // Whether every centroid holds a single value, see tdigest_is_exact.
+    fn is_exact(&self) -> bool {
+        self.centroids_iter().all(|(_, weight)| weight == 1)
+    }
+
+    // The subset of validate() needed for the digest to be usable at all, see
+    // check_centroids(). Used whenever a digest comes from outside input.
+    fn check_centroids(&self) -> Result<(), String> {
+        let slots = min(*self.buckets, *self.count) as usize;
+        if self.means.len() != slots || self.weights.len() != slots {
+            return Err(format!("expected {} centroid slots, found {} means and {} weights",
+                slots, self.means.len(), self.weights.len()))
+        }
+
+        let centroids = self.weights.iter().take_while(|&&weight| weight > 0).count();
+        if self.weights[centroids..].iter().any(|&weight| weight != 0) {
+            return Err(format!("centroid {} has zero weight", centroids))
+        }
+        let cents: Vec<Centroid> = (0..centroids)
+            .map(|i| Centroid::new(self.means[i], self.weights[i] as f64))
+            .collect();
+        check_centroids(&cents)
+    }
+
+    // Check the internal invariants of the digest, describing the first one
+    // found to be broken.
+    fn validate(&self) -> Result<(), String> {
+        self.check_centroids()?;
+        let centroids = self.centroids_iter().count();
+
+        let total_weight: u64 = self.weights.iter().map(|&weight| weight as u64).sum();
+        if total_weight != *self.count as u64 {
+            return Err(format!("centroid weights sum to {} but count is {}", total_weight, self.count))
+        }
+
+        if let Some(mode) = self.exact_mode() {
+            if !(*self.0.min <= mode && mode <= *self.max) {
+                return Err(format!("mode {} is outside of the range {} to {}", mode, self.0.min, self.max))
+            }
+        }
+
+        if let Some((first, last)) = self.ends() {
+            for &value in &[first, last] {
+                if !(*self.0.min <= value && value <= *self.max) {
+                    return Err(format!("first or last value {} is outside of the range {} to {}",
+                        value, self.0.min, self.max))
+                }
+            }
+        }
+
+        if let (Some(&lower), Some(&upper)) = (self.range_lower.get(0), self.range_upper.get(0)) {
+            if lower > upper && (lower, upper) != NO_RANGE {
+                return Err(format!("range lower bound {} is after the upper bound {}", lower, upper))
+            }
+        }
+
+        if !self.exact_sum.is_empty() {
+            std::str::from_utf8(self.exact_sum).ok()
+                .and_then(ExactSum::parse)
+                .ok_or_else(|| format!("exact sum {:?} is not a valid number", self.exact_sum))?;
+        }
+
+        if centroids > 0 {
+            if !(*self.0.min <= self.means[0]) {
+                return Err(format!("min {} is greater than the smallest mean {}", self.0.min, self.means[0]))
+            }
+            if !(*self.max >= self.means[centroids - 1]) {
+                return Err(format!("max {} is less than the largest mean {}", self.max, self.means[centroids - 1]))
+            }
+        }
+        Ok(())
+    }
+
+    // Like to_tdigest(), but only borrows the stored arrays; estimating a
+    // few quantiles from this is several times faster than copying them first.
+    fn centroid_slices(&self) -> CentroidSlices<'_, u32> {
+        let centroids = self.centroids_iter().count();
+        CentroidSlices::new(
+            &self.means[..centroids],
+            &self.weights[..centroids],
+            *self.count as f64,
+            *self.0.min,
+            *self.max,
+        )
+    }
+
+    fn parsed_exact_sum(&self) -> Option<ExactSum> {
+        if self.exact_sum.is_empty() {
+            return None
+        }
+        std::str::from_utf8(self.exact_sum).ok().and_then(ExactSum::parse)
+    }
 
-            let buckets : u32 = state.digested.max_size().try_into().unwrap();
-            let count = state.digested.count() as u32;
-            let vec_size = min(buckets as usize, count as usize);
-            let mut means = vec!(0.0; vec_size);
-            let mut weights = vec!(0; vec_size);
+    fn exact_mode(&self) -> Option<f64> {
+        self.mode.get(0).copied().filter(|mode| !mode.is_nan())
+    }
+
+    // The means of the centroids on either side of the quantile, where the
+    // min and max stand in for the ones beyond the first and last centroids.
+    // Centroid i is centered at the weight before it plus half its own weight,
+    // estimates are interpolated between the centers either side of the
+    // quantile's cumulative weight.
+    fn bracketing_means(&self, quantile: f64) -> (f64, f64) {
+        let target = quantile * *self.count as f64;
+        let centroids = self.centroids_iter().count();
 
-            for (i, cent) in state.digested.raw_centroids().iter().enumerate() {
-                means[i] = cent.mean();
-                weights[i] = cent.weight() as u32;
+        let mut lo = *self.0.min;
+        let mut weight_before = 0.0;
+        for i in 0..centroids {
+            let weight = self.weights[i] as f64;
+            if target < weight_before + weight / 2.0 {
+                return (lo, self.means[i])
             }
+            lo = self.means[i];
+            weight_before += weight;
+        }
+        (lo, *self.max)
+    }
+
+    // The first and last values the digest was built from, if they were
+    // tracked, which digests stored before version 3 didn't.
+    fn ends(&self) -> Option<(f64, f64)> {
+        match (self.first.get(0), self.last.get(0)) {
+            (Some(first), Some(last)) if !first.is_nan() => Some((*first, *last)),
+            _ => None,
+        }
+    }
+
+    // The earliest and latest timestamps of the values, if the digest was
+    // built with them, see t_digest_ranged.
+    fn range(&self) -> Option<(i64, i64)> {
+        match (self.range_lower.get(0), self.range_upper.get(0)) {
+            (Some(&lower), Some(&upper)) if lower <= upper => Some((lower, upper)),
+            _ => None,
+        }
+    }
+
+    // Store a tdigest::TDigest, the inverse of to_tdigest(). The result has no
+    // exact sum or mode, see from_tdigest_with() for those.
+    pub fn from_tdigest(digest: &TDigest) -> TimescaleTDigest<'static> {
+        Self::from_tdigest_with(digest, None, None, None, None)
+    }
+
+    fn from_tdigest_with(
+        digest: &TDigest,
+        exact_sum: Option<&ExactSum>,
+        exact_mode: Option<f64>,
+        ends: Option<(f64, f64)>,
+        range: Option<(i64, i64)>,
+    ) -> TimescaleTDigest<'static> {
+        let mode = exact_mode.unwrap_or(std::f64::NAN);
+        let (first, last) = ends.unwrap_or((std::f64::NAN, std::f64::NAN));
+        let (range_lower, range_upper) = range.unwrap_or(NO_RANGE);
+        let exact_sum = exact_sum.map(ExactSum::to_string).unwrap_or_default();
+        let exact_sum_len: u32 = exact_sum.len().try_into().unwrap();
+        let buckets: u32 = digest.max_size().try_into()
+            .unwrap_or_else(|_| TDigestError::OutOfRange(format!("digest size {} is too large, must be at most {}",
+                digest.max_size(), u32::MAX)).raise());
+        if !(digest.count() <= u32::MAX as f64) {
+            TDigestError::OutOfRange(format!("digest count {} is too large, must be at most {}", digest.count(), u32::MAX)).raise()
+        }
+        let count = digest.count() as u32;
+        let vec_size = min(buckets as usize, count as usize);
+        // push skips NaN, so only an empty digest has a NaN min and max
+        debug_assert!(count == 0 || !(digest.min().is_nan() || digest.max().is_nan()));
+
+        // The digest keeps its centroids as (mean, weight) pairs, but the
+        // layout stores all the means and then all the weights, so they have
+        // to be split out; flatten copies them from there straight into the
+        // final buffer. Each is allocated once at its final size, and written
+        // once, with the unused slots at the end zeroed.
+        let mut means = Vec::with_capacity(vec_size);
+        let mut weights = Vec::with_capacity(vec_size);
+        for cent in digest.raw_centroids() {
+            means.push(cent.mean());
+            weights.push(cent.weight() as u32);
+        }
+        means.resize(vec_size, 0.0);
+        weights.resize(vec_size, 0);
 
-            // we need to flatten the vector to a single buffer that contains
-            // both the size, the data, and the varlen header
+        // we need to flatten the vector to a single buffer that contains
+        // both the size, the data, and the varlen header
+        unsafe {
             flatten!(
                 TimescaleTDigest {
                     buckets: &buckets,
                     count: &count,
-                    sum: &state.digested.sum(),
-                    min: &state.digested.min(),
-                    max: &state.digested.max(),
+                    sum: &digest.sum(),
+                    min: &digest.min(),
+                    max: &digest.max(),
+                    mode: &[mode],
+                    first: &[first],
+                    last: &[last],
+                    range_lower: &[range_lower],
+                    range_upper: &[range_upper],
                     means: &means,
                     weights: &weights,
-                }
-            ).into()
+                    exact_sum_len: &[exact_sum_len],
+                    exact_sum: exact_sum.as_bytes(),
+                    checksum: &[0],
+                },
+                write_checksum
+            )
+        }
+    }
+
+    fn compute_checksum(&self) -> u32 {
+        layout_checksum(self.pg_bytes())
+    }
+}
+
+// The checksum of a digest's varlena bytes. It's the last field of the
+// layout, so it covers all the bytes before it but the header.
+fn layout_checksum(bytes: &[u8]) -> u32 {
+    crc32(&bytes[4..bytes.len() - 4])
+}
+
+// Fill in the checksum of a digest being flattened with a blank one, before
+// its bytes are parsed.
+fn write_checksum(bytes: &mut [u8]) {
+    let len = bytes.len();
+    let checksum = layout_checksum(bytes);
+    bytes[len - 4..].copy_from_slice(&checksum.to_ne_bytes());
+}
+
+// An owned copy of a TimescaleTDigest's fields, so that digests can be
+// embedded in other serde types or kept in Rust collections. The NaN that
+// stands in for a missing min, max or mode is represented as None, since
+// formats like JSON have no NaN. Equality and hashing compare the floats
+// bit for bit, so that Eq holds, meaning e.g. that 0 and -0 differ.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TimescaleTDigestOwned {
+    buckets: u32,
+    count: u32,
+    sum: f64,
+    min: Option<f64>,
+    max: Option<f64>,
+    mode: Option<f64>,
+    // missing from digests exported before they were tracked
+    #[serde(default)]
+    first: Option<f64>,
+    #[serde(default)]
+    last: Option<f64>,
+    // in postgres' microseconds since 2000, missing if not known
+    #[serde(default)]
+    range_lower: Option<i64>,
+    #[serde(default)]
+    range_upper: Option<i64>,
+    means: Vec<f64>,
+    weights: Vec<u32>,
+    exact_sum: String,
+}
+
+fn nan_to_none(value: f64) -> Option<f64> {
+    if value.is_nan() { None } else { Some(value) }
+}
+
+impl<'input> From<&TimescaleTDigest<'input>> for TimescaleTDigestOwned {
+    fn from(digest: &TimescaleTDigest<'input>) -> Self {
+        TimescaleTDigestOwned {
+            buckets: *digest.buckets,
+            count: *digest.count,
+            sum: *digest.sum,
+            min: nan_to_none(*digest.0.min),
+            max: nan_to_none(*digest.max),
+            mode: digest.exact_mode(),
+            first: digest.ends().map(|(first, _)| first),
+            last: digest.ends().map(|(_, last)| last),
+            range_lower: digest.range().map(|(lower, _)| lower),
+            range_upper: digest.range().map(|(_, upper)| upper),
+            means: digest.means.to_vec(),
+            weights: digest.weights.to_vec(),
+            exact_sum: String::from_utf8_lossy(digest.exact_sum).into_owned(),
+        }
+    }
+}
+
+impl TimescaleTDigestOwned {
+    // Every float, in field order, for comparing and hashing by bit pattern.
+    fn float_bits(&self) -> impl Iterator<Item = Option<u64>> + '_ {
+        let scalars = vec![Some(self.sum), self.min, self.max, self.mode, self.first, self.last];
+        scalars.into_iter()
+            .chain(self.means.iter().copied().map(Some))
+            .map(|value| value.map(f64::to_bits))
+    }
+
+    pub fn to_digest(&self) -> Result<TimescaleTDigest<'static>, String> {
+        let slots = min(self.buckets, self.count) as usize;
+        if self.means.len() != slots || self.weights.len() != slots {
+            return Err(format!("expected {} centroid slots, found {} means and {} weights",
+                slots, self.means.len(), self.weights.len()))
+        }
+        let exact_sum_len: u32 = self.exact_sum.len().try_into()
+            .map_err(|_| "exact sum too long".to_string())?;
+        let (first, last) = match (self.first, self.last) {
+            (Some(first), Some(last)) => (first, last),
+            (None, None) => (std::f64::NAN, std::f64::NAN),
+            _ => return Err("first and last must either both be present or both be missing".to_string()),
+        };
+        let (range_lower, range_upper) = match (self.range_lower, self.range_upper) {
+            (Some(lower), Some(upper)) if lower <= upper => (lower, upper),
+            (None, None) => NO_RANGE,
+            _ => return Err("range_lower and range_upper must either both be missing or both be present, in order".to_string()),
+        };
+
+        let digest = unsafe {
+            flatten!(
+                TimescaleTDigest {
+                    buckets: &self.buckets,
+                    count: &self.count,
+                    sum: &self.sum,
+                    min: &self.min.unwrap_or(std::f64::NAN),
+                    max: &self.max.unwrap_or(std::f64::NAN),
+                    mode: &[self.mode.unwrap_or(std::f64::NAN)],
+                    first: &[first],
+                    last: &[last],
+                    range_lower: &[range_lower],
+                    range_upper: &[range_upper],
+                    means: &self.means,
+                    weights: &self.weights,
+                    exact_sum_len: &[exact_sum_len],
+                    exact_sum: self.exact_sum.as_bytes(),
+                    checksum: &[0],
+                },
+                write_checksum
+            )
+        };
+        digest.check_centroids()?;
+        Ok(digest)
+    }
+}
+
+impl PartialEq for TimescaleTDigestOwned {
+    fn eq(&self, other: &Self) -> bool {
+        self.buckets == other.buckets
+            && self.count == other.count
+            && self.weights == other.weights
+            && self.exact_sum == other.exact_sum
+            && self.range_lower == other.range_lower
+            && self.range_upper == other.range_upper
+            && self.means.len() == other.means.len()
+            && self.float_bits().eq(other.float_bits())
+    }
+}
+
+impl Eq for TimescaleTDigestOwned {}
+
+impl std::hash::Hash for TimescaleTDigestOwned {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.buckets.hash(state);
+        self.count.hash(state);
+        self.weights.hash(state);
+        self.exact_sum.hash(state);
+        self.range_lower.hash(state);
+        self.range_upper.hash(state);
+        for bits in self.float_bits() {
+            bits.hash(state);
+        }
+    }
+}
+
+impl<'input> Serialize for TimescaleTDigest<'input> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        TimescaleTDigestOwned::from(self).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for TimescaleTDigest<'static> {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        TimescaleTDigestOwned::deserialize(deserializer)?
+            .to_digest()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+// PG function to generate a user-facing TimescaleTDigest object from an internal TDigestTransState.
+//
+// The result of a parallel aggregate depends on how the rows were split
+// between the workers, since every partial digest compresses its own share of
+// the data before it is merged. Re-merging the centroids in a fixed order here
+// would not change that: compression can't be undone, and a serial digest
+// re-merged with itself is unchanged, so the final digests would still only
+// agree while no partial had anything to compress.
+#[pg_extern]
+fn tdigest_final(
+    state: Option<Internal<TDigestTransState>>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<TimescaleTDigest<'static>> {
+    unsafe {
+        in_aggregate_context(fcinfo, || {
+            let mut state = match state {
+                None => return None,
+                Some(state) => state,
+            };
+            state.finish().into()
         })
     }
 }
 
-//---- Available PG operations on the digest
+//---- quantile_agg: build a digest and only return a single quantile from it
+
+// Intermediate state for quantile_agg. This is the same state t_digest builds,
+// along with the quantile to estimate at the end.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct TDigestQuantileTransState {
+    quantile: f64,
+    digest_state: TDigestTransState,
+}
 
-// Approximate the value at the given quantile (0.0-1.0)
 #[pg_extern]
-pub fn tdigest_quantile(
-    digest: TimescaleTDigest,
+pub fn tdigest_quantile_trans(
+    state: Option<Internal<TDigestQuantileTransState>>,
     quantile: f64,
-    _fcinfo: pg_sys::FunctionCallInfo,
-) -> f64 {
-    digest.to_tdigest().estimate_quantile(quantile)
+    size: int,
+    value: Option<f64>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Internal<TDigestQuantileTransState>> {
+    let mctx = aggregate_mctx(fcinfo);
+    let mctx = match mctx {
+        None => TDigestError::NotAggregate.raise(),
+        Some(mctx) => mctx,
+    };
+    unsafe {
+        in_memory_context(mctx, || {
+            let value = match value {
+                None => return state,
+                Some(value) => value,
+            };
+            let mut state = match state {
+                None => {
+                    if !(0.0..=1.0).contains(&quantile) {
+                        TDigestError::BadQuantile(quantile).raise()
+                    }
+                    TDigestQuantileTransState {
+                        quantile,
+                        digest_state: TDigestTransState::new(size as _),
+                    }.into()
+                },
+                Some(state) => state,
+            };
+            state.digest_state.push(value);
+            Some(state)
+        })
+    }
 }
 
-// Approximate the quantile at the given value
 #[pg_extern]
-pub fn tdigest_quantile_at_value(
-    digest: TimescaleTDigest,
-    value: f64,
-    _fcinfo: pg_sys::FunctionCallInfo,
-) -> f64 {
-    digest.to_tdigest().estimate_quantile_at_value(value)
+pub fn tdigest_quantile_combine(
+    state1: Option<Internal<TDigestQuantileTransState>>,
+    state2: Option<Internal<TDigestQuantileTransState>>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Internal<TDigestQuantileTransState>> {
+    let mctx = aggregate_mctx(fcinfo);
+    let mctx = match mctx {
+        None => TDigestError::NotAggregate.raise(),
+        Some(mctx) => mctx,
+    };
+    unsafe {
+        in_memory_context(mctx, || {
+            let quantile = state1.as_ref().or(state2.as_ref())?.quantile;
+            let digest_state = combine(
+                state1.as_deref().map(|s| &s.digest_state),
+                state2.as_deref().map(|s| &s.digest_state),
+            )?;
+            Some(TDigestQuantileTransState { quantile, digest_state }.into())
+        })
+    }
 }
 
-// Number of elements from which the digest was built.
 #[pg_extern]
-pub fn tdigest_count(
-    digest: TimescaleTDigest,
-    _fcinfo: pg_sys::FunctionCallInfo,
-) -> f64 {
-    *digest.count as f64
+pub fn tdigest_quantile_serialize(
+    mut state: Internal<TDigestQuantileTransState>,
+) -> bytea {
+    state.digest_state.digest();
+    crate::do_serialize!(state)
 }
 
-// Minimum value entered in the digest.
 #[pg_extern]
-pub fn tdigest_min(
-    digest: TimescaleTDigest,
-    _fcinfo: pg_sys::FunctionCallInfo,
-) -> f64 {
-    *digest.min
+pub fn tdigest_quantile_deserialize(
+    bytes: bytea,
+    _internal: Option<Internal<()>>,
+) -> Internal<TDigestQuantileTransState> {
+    crate::do_deserialize!(bytes, TDigestQuantileTransState)
 }
 
-// Maximum value entered in the digest.
+// Unlike tdigest_final this never builds a TimescaleTDigest, the quantile is
+// estimated straight from the internal digest.
 #[pg_extern]
-pub fn tdigest_max(
-    digest: TimescaleTDigest,
+fn tdigest_quantile_final(
+    state: Option<Internal<TDigestQuantileTransState>>,
     _fcinfo: pg_sys::FunctionCallInfo,
-) -> f64 {
-    *digest.max
+) -> Option<f64> {
+    let mut state = state?;
+    state.digest_state.digest();
+    Some(state.digest_state.digested.estimate_quantile(state.quantile))
+}
+
+//---- t_digest_resized: build at one size, store at another
+
+// Intermediate state for t_digest_resized. The digest is built at the full
+// size, and only compressed down to target_size by the final function.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct TDigestResizedTransState {
+    target_size: usize,
+    digest_state: TDigestTransState,
 }
 
-// Average of all the values entered in the digest.
-// Note that this is not an approximation, though there may be loss of precision.
 #[pg_extern]
-pub fn tdigest_mean(
-    digest: TimescaleTDigest,
-    _fcinfo: pg_sys::FunctionCallInfo,
-) -> f64 {
-    if *digest.count > 0 {
-        *digest.sum / *digest.count as f64
-    } else {
-        0.0
+pub fn tdigest_resized_trans(
+    state: Option<Internal<TDigestResizedTransState>>,
+    size: int,
+    target_size: int,
+    value: Option<f64>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Internal<TDigestResizedTransState>> {
+    let mctx = aggregate_mctx(fcinfo);
+    let mctx = match mctx {
+        None => TDigestError::NotAggregate.raise(),
+        Some(mctx) => mctx,
+    };
+    unsafe {
+        in_memory_context(mctx, || {
+            let value = match value {
+                None => return state,
+                Some(value) => value,
+            };
+            let mut state = match state {
+                None => {
+                    if target_size == 0 {
+                        TDigestError::SizeZero("t_digest_resized target size").raise()
+                    }
+                    TDigestResizedTransState {
+                        target_size: target_size as _,
+                        digest_state: TDigestTransState::new(size as _),
+                    }.into()
+                },
+                Some(state) => state,
+            };
+            state.digest_state.push(value);
+            Some(state)
+        })
     }
 }
 
-// Sum of all the values entered in the digest.
 #[pg_extern]
-pub fn tdigest_sum(
-    digest: TimescaleTDigest,
-    _fcinfo: pg_sys::FunctionCallInfo,
-) -> f64 {
-    *digest.sum
+pub fn tdigest_resized_combine(
+    state1: Option<Internal<TDigestResizedTransState>>,
+    state2: Option<Internal<TDigestResizedTransState>>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Internal<TDigestResizedTransState>> {
+    let mctx = aggregate_mctx(fcinfo);
+    let mctx = match mctx {
+        None => TDigestError::NotAggregate.raise(),
+        Some(mctx) => mctx,
+    };
+    unsafe {
+        in_memory_context(mctx, || {
+            let target_size = state1.as_ref().or(state2.as_ref())?.target_size;
+            let digest_state = combine(
+                state1.as_deref().map(|s| &s.digest_state),
+                state2.as_deref().map(|s| &s.digest_state),
+            )?;
+            Some(TDigestResizedTransState { target_size, digest_state }.into())
+        })
+    }
 }
 
-#[cfg(any(test, feature = "pg_test"))]
-mod tests {
-    use pgx::*;
+#[pg_extern]
+pub fn tdigest_resized_serialize(
+    mut state: Internal<TDigestResizedTransState>,
+) -> bytea {
+    state.digest_state.digest();
+    crate::do_serialize!(state)
+}
 
-    // Assert equality between two floats, within some fixed error range.
-    fn apx_eql(value: f64, expected: f64, error: f64) {
-        assert!((value - expected).abs() < error, "Float value {} differs from expected {} by more than {}", value, expected, error);
-    }
+#[pg_extern]
+pub fn tdigest_resized_deserialize(
+    bytes: bytea,
+    _internal: Option<Internal<()>>,
+) -> Internal<TDigestResizedTransState> {
+    crate::do_deserialize!(bytes, TDigestResizedTransState)
+}
 
-    // Assert equality between two floats, within an error expressed as a fraction of the expected value.
-    fn pct_eql(value: f64, expected: f64, pct_error: f64) {
-        apx_eql(value, expected, pct_error * expected);
+// Compresses the internal digest directly, rather than building a
+// TimescaleTDigest at the full size and reading it back like tdigest_resize
+// would have to.
+#[pg_extern]
+fn tdigest_resized_final(
+    state: Option<Internal<TDigestResizedTransState>>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<TimescaleTDigest<'static>> {
+    unsafe {
+        in_aggregate_context(fcinfo, || {
+            let mut state = state?;
+            state.digest_state.digest();
+            let resized = state.digest_state.digested.compressed_to(state.target_size);
+            TimescaleTDigest::from_tdigest_with(
+                &resized,
+                None,
+                None,
+                state.digest_state.ends(),
+                state.digest_state.range,
+            ).into()
+        })
     }
+}
 
-    #[pg_test]
-    fn test_aggregate() {
-        Spi::execute(|client| {
-            client.select("CREATE TABLE test (data DOUBLE PRECISION)", None, None);
-            client.select("INSERT INTO test SELECT generate_series(0.01, 100, 0.01)", None, None);
+//---- t_digest over numeric: exact sums for arbitrary precision input
 
-            let sanity = client
-                .select("SELECT COUNT(*) FROM test", None, None)
-                .first()
-                .get_one::<i32>();
-            assert_eq!(10000, sanity.unwrap());
+// An exact decimal sum, stored as an integer number of units of 10^-scale.
+// This is far less general than postgres' numeric, but keeps the transition
+// cheap; sums that don't fit in 38 digits are an error rather than rounded.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct ExactSum {
+    digits: i128,
+    scale: u32,
+    nan: bool,
+}
 
-            client.select("CREATE VIEW digest AS SELECT t_digest(100, data) FROM test", None, None);
-            let (min, max, count) = client
-                .select("SELECT tdigest_min(t_digest), tdigest_max(t_digest), tdigest_count(t_digest) FROM digest", None, None)
-                .first()
-                .get_three::<f64, f64, f64>();
+impl ExactSum {
+    // Parse numeric's text output, e.g. "-12.340" or "NaN".
+    fn parse(value: &str) -> Option<ExactSum> {
+        if value == "NaN" {
+            return Some(ExactSum { nan: true, ..ExactSum::default() })
+        }
+        let (negative, value) = match value.as_bytes().first() {
+            Some(b'-') => (true, &value[1..]),
+            _ => (false, value),
+        };
+        let (int, frac) = match value.find('.') {
+            Some(point) => (&value[..point], &value[point + 1..]),
+            None => (value, ""),
+        };
+        if int.is_empty() {
+            return None
+        }
 
-            apx_eql(min.unwrap(), 0.01, 0.000001);
-            apx_eql(max.unwrap(), 100.0, 0.000001);
-            apx_eql(count.unwrap(), 10000.0, 0.000001);
+        let mut digits: i128 = 0;
+        for c in int.chars().chain(frac.chars()) {
+            let digit = c.to_digit(10)? as i128;
+            digits = digits.checked_mul(10)?.checked_add(digit)?;
+        }
+        Some(ExactSum {
+            digits: if negative { -digits } else { digits },
+            scale: frac.len() as u32,
+            nan: false,
+        })
+    }
 
-            let (mean, sum) = client
-                .select("SELECT tdigest_mean(t_digest), tdigest_sum(t_digest) FROM digest", None, None)
-                .first()
-                .get_two::<f64, f64>();
+    fn rescaled(&self, scale: u32) -> Option<i128> {
+        10i128.checked_pow(scale - self.scale)?.checked_mul(self.digits)
+    }
 
-            apx_eql(mean.unwrap(), 50.005, 0.0001);
-            apx_eql(sum.unwrap(), 500050.0, 0.0001);
+    fn add(&mut self, other: &ExactSum) {
+        if self.nan || other.nan {
+            self.nan = true;
+            return
+        }
+        let scale = std::cmp::max(self.scale, other.scale);
+        let digits = self.rescaled(scale)
+            .and_then(|digits| digits.checked_add(other.rescaled(scale)?));
+        match digits {
+            Some(digits) => *self = ExactSum { digits, scale, nan: false },
+            None => TDigestError::OutOfRange("numeric sum out of range for t_digest".to_string()).raise(),
+        }
+    }
+}
 
-            for i in 0..=100 {
-                let value = i as f64;
-                let quantile = value / 100.0;
+impl std::fmt::Display for ExactSum {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.nan {
+            return write!(f, "NaN")
+        }
+        let digits = self.digits.to_string();
+        let (sign, digits) = match digits.strip_prefix('-') {
+            Some(digits) => ("-", digits),
+            None => ("", &*digits),
+        };
+        let scale = self.scale as usize;
+        if scale == 0 {
+            return write!(f, "{}{}", sign, digits)
+        }
+        let digits = format!("{:0>width$}", digits, width = scale + 1);
+        let (int, frac) = digits.split_at(digits.len() - scale);
+        write!(f, "{}{}.{}", sign, int, frac)
+    }
+}
 
-                let (est_val, est_quant) = client
-                    .select(&format!("SELECT tdigest_quantile(t_digest, {}), tdigest_quantile_at_value(t_digest, {}) FROM digest", quantile, value), None, None)
-                    .first()
-                    .get_two::<f64, f64>();
+// Intermediate state for t_digest over numeric. The digest itself is built
+// from the values rounded to the nearest double precision, so quantiles are no
+// more precise than with double precision input, but the sum is kept exactly
+// and can be read back with tdigest_numeric_sum.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct TDigestNumericTransState {
+    exact_sum: ExactSum,
+    digest_state: TDigestTransState,
+}
 
-                if i == 0 {
-                    pct_eql(est_val.unwrap(), 0.01, 1.0);
-                    apx_eql(est_quant.unwrap(), quantile, 0.0001);
-                } else {
-                    pct_eql(est_val.unwrap(), value, 1.0);
-                    pct_eql(est_quant.unwrap(), quantile, 1.0);
-                }
+#[pg_extern]
+pub fn tdigest_numeric_trans(
+    state: Option<Internal<TDigestNumericTransState>>,
+    size: int,
+    value: Option<Numeric>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Internal<TDigestNumericTransState>> {
+    let mctx = aggregate_mctx(fcinfo);
+    let mctx = match mctx {
+        None => TDigestError::NotAggregate.raise(),
+        Some(mctx) => mctx,
+    };
+    unsafe {
+        in_memory_context(mctx, || {
+            let value = match value {
+                None => return state,
+                Some(value) if value.0 == "NaN" => return state,
+                Some(value) => value,
+            };
+            let exact = ExactSum::parse(&value.0)
+                .unwrap_or_else(|| TDigestError::BadArgument(format!("cannot add numeric {} to a t_digest", value.0)).raise());
+            let rounded: f64 = value.0.parse()
+                .unwrap_or_else(|_| TDigestError::BadArgument(format!("cannot add numeric {} to a t_digest", value.0)).raise());
+
+            let mut state = match state {
+                None => TDigestNumericTransState {
+                    exact_sum: ExactSum::default(),
+                    digest_state: TDigestTransState::new(size as _),
+                }.into(),
+                Some(state) => state,
+            };
+            state.exact_sum.add(&exact);
+            state.digest_state.push(rounded);
+            Some(state)
+        })
+    }
+}
+
+#[pg_extern]
+pub fn tdigest_numeric_combine(
+    state1: Option<Internal<TDigestNumericTransState>>,
+    state2: Option<Internal<TDigestNumericTransState>>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Internal<TDigestNumericTransState>> {
+    let mctx = aggregate_mctx(fcinfo);
+    let mctx = match mctx {
+        None => TDigestError::NotAggregate.raise(),
+        Some(mctx) => mctx,
+    };
+    unsafe {
+        in_memory_context(mctx, || {
+            let mut exact_sum = ExactSum::default();
+            for state in state1.iter().chain(state2.iter()) {
+                exact_sum.add(&state.exact_sum);
+            }
+            let digest_state = combine(
+                state1.as_deref().map(|s| &s.digest_state),
+                state2.as_deref().map(|s| &s.digest_state),
+            )?;
+            Some(TDigestNumericTransState { exact_sum, digest_state }.into())
+        })
+    }
+}
+
+#[pg_extern]
+pub fn tdigest_numeric_serialize(
+    mut state: Internal<TDigestNumericTransState>,
+) -> bytea {
+    state.digest_state.digest();
+    crate::do_serialize!(state)
+}
+
+#[pg_extern]
+pub fn tdigest_numeric_deserialize(
+    bytes: bytea,
+    _internal: Option<Internal<()>>,
+) -> Internal<TDigestNumericTransState> {
+    crate::do_deserialize!(bytes, TDigestNumericTransState)
+}
+
+#[pg_extern]
+fn tdigest_numeric_final(
+    state: Option<Internal<TDigestNumericTransState>>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<TimescaleTDigest<'static>> {
+    unsafe {
+        in_aggregate_context(fcinfo, || {
+            let mut state = state?;
+            state.digest_state.digest();
+            TimescaleTDigest::from_tdigest_with(
+                &state.digest_state.digested,
+                Some(&state.exact_sum),
+                None,
+                state.digest_state.ends(),
+                state.digest_state.range,
+            ).into()
+        })
+    }
+}
+
+//---- rollup: merge stored digests, e.g. from a continuous aggregate
+
+// Intermediate state for rollup. The exact sum is only kept as long as every
+// digest rolled up so far has one, i.e. was built from numeric input. Under
+// the stable merge strategy the digests wait in `pending` for the final
+// function, and digest_state only tracks the first and last values. The
+// strategy is read once, by the first row, rather than parsing the GUC again
+// for every digest.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct TDigestRollupTransState {
+    exact_sum: Option<ExactSum>,
+    digest_state: TDigestTransState,
+    pending: Vec<TDigest>,
+    strategy: MergeStrategy,
+}
+
+impl TDigestRollupTransState {
+    fn new(digest: &TimescaleTDigest, strategy: MergeStrategy) -> Self {
+        let mut digest_state = TDigestTransState::from_digest(digest);
+        let mut pending = vec![];
+        if strategy == MergeStrategy::Stable {
+            let size = digest_state.digested.max_size();
+            pending.push(std::mem::replace(&mut digest_state.digested, TDigest::new_with_size(size)));
+        }
+        TDigestRollupTransState {
+            exact_sum: digest.parsed_exact_sum(),
+            digest_state,
+            pending,
+            strategy,
+        }
+    }
+
+    fn add(&mut self, other: &TDigestRollupTransState) {
+        self.exact_sum = add_exact_sums(self.exact_sum.take(), other.exact_sum.as_ref());
+        combine_in_place(&mut self.digest_state, &other.digest_state);
+        self.pending.extend(other.pending.iter().cloned());
+    }
+}
+
+fn add_exact_sums(a: Option<ExactSum>, b: Option<&ExactSum>) -> Option<ExactSum> {
+    let mut a = a?;
+    a.add(b?);
+    Some(a)
+}
+
+#[pg_extern]
+pub fn tdigest_rollup_trans(
+    state: Option<Internal<TDigestRollupTransState>>,
+    digest: Option<TimescaleTDigest>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Internal<TDigestRollupTransState>> {
+    let mctx = aggregate_mctx(fcinfo);
+    let mctx = match mctx {
+        None => TDigestError::NotAggregate.raise(),
+        Some(mctx) => mctx,
+    };
+    unsafe {
+        in_memory_context(mctx, || {
+            let digest = match digest {
+                None => return state,
+                Some(digest) => digest,
+            };
+            let strategy = state.as_ref().map_or_else(merge_strategy, |state| state.strategy);
+            let incoming = TDigestRollupTransState::new(&digest, strategy);
+            let state = match state {
+                None => incoming.into(),
+                Some(mut state) => {
+                    state.add(&incoming);
+                    state
+                },
+            };
+            Some(state)
+        })
+    }
+}
+
+// Transition function for rollup(bytea), which rolls up digests stored as
+// bytea in their binary layout, e.g. `decode(tdigest_to_base64(digest),
+// 'base64')`, checking each the way tdigest_deserialize_array does. Storing
+// the TimescaleTDigest type itself is preferred, as it is only checked once,
+// when it's written. Null values are ignored.
+#[pg_extern]
+pub fn tdigest_rollup_bytea_trans(
+    state: Option<Internal<TDigestRollupTransState>>,
+    serialized: Option<&[u8]>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Internal<TDigestRollupTransState>> {
+    let serialized = match serialized {
+        None => return state,
+        Some(serialized) => serialized,
+    };
+    let digest = digest_from_bytes(serialized).unwrap_or_else(|e| e.raise());
+    tdigest_rollup_trans(state, Some(digest), fcinfo)
+}
+
+#[pg_extern]
+pub fn tdigest_rollup_combine(
+    state1: Option<Internal<TDigestRollupTransState>>,
+    state2: Option<Internal<TDigestRollupTransState>>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Internal<TDigestRollupTransState>> {
+    let mctx = aggregate_mctx(fcinfo);
+    let mctx = match mctx {
+        None => TDigestError::NotAggregate.raise(),
+        Some(mctx) => mctx,
+    };
+    unsafe {
+        in_memory_context(mctx, || {
+            let state = match (state1, state2) {
+                (None, None) => return None,
+                (None, Some(state)) | (Some(state), None) => state.clone().into(),
+                (Some(mut state1), Some(state2)) => {
+                    state1.add(&state2);
+                    state1
+                },
+            };
+            Some(state)
+        })
+    }
+}
+
+#[pg_extern]
+pub fn tdigest_rollup_serialize(
+    mut state: Internal<TDigestRollupTransState>,
+) -> bytea {
+    state.digest_state.digest();
+    crate::do_serialize!(state)
+}
+
+#[pg_extern]
+pub fn tdigest_rollup_deserialize(
+    bytes: bytea,
+    _internal: Option<Internal<()>>,
+) -> Internal<TDigestRollupTransState> {
+    crate::do_deserialize!(bytes, TDigestRollupTransState)
+}
+
+#[pg_extern]
+fn tdigest_rollup_final(
+    state: Option<Internal<TDigestRollupTransState>>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<TimescaleTDigest<'static>> {
+    unsafe {
+        in_aggregate_context(fcinfo, || {
+            let mut state = state?;
+            state.digest_state.digest();
+            if !state.pending.is_empty() {
+                let mut digests = std::mem::take(&mut state.pending);
+                digests.push(std::mem::take(&mut state.digest_state.digested));
+                state.digest_state.digested = cap_centroids(TDigest::merge_digests_unordered(digests));
+            }
+            TimescaleTDigest::from_tdigest_with(
+                &state.digest_state.digested,
+                state.exact_sum.as_ref(),
+                None,
+                state.digest_state.ends(),
+                state.digest_state.range,
+            ).into()
+        })
+    }
+}
+
+//---- lazy_rollup: keep the digests apart until they are read
+
+// A rollup that stores its component digests as they are instead of merging
+// them right away, so that e.g. a daily rollup of hourly digests can still be
+// merged all at once, or at a larger size, when it's read. It takes about as
+// much space as the components together. Their binary layouts, without the
+// varlena headers, are stored back to back.
+pg_type! {
+    #[derive(Debug)]
+    struct TimescaleTDigestLazyRollup {
+        components: u32,
+        lengths: [u32; self.components],
+        layouts_len: u32,
+        layouts: [u8; self.layouts_len],
+    }
+}
+
+// The text format is a JSON array of the components, each in the text format
+// of TimescaleTDigest.
+impl<'input> InOutFuncs for TimescaleTDigestLazyRollup<'input> {
+    fn output(&self, buffer: &mut StringInfo) {
+        use std::io::Write;
+        let components = self.components().unwrap_or_else(|e| e.raise());
+        let json = serde_json::to_string(&components)
+            .unwrap_or_else(|e| TDigestError::Internal(format!("cannot output TimescaleTDigestLazyRollup: {}", e)).raise());
+        let _ = write!(buffer, "{}", json);
+    }
+
+    fn input(input: &std::ffi::CStr) -> Self
+    where
+        Self: Sized,
+    {
+        let input = input.to_str()
+            .unwrap_or_else(|e| TDigestError::CorruptData(e.to_string()).raise());
+        let components = serde_json::from_str::<Vec<TimescaleTDigest<'static>>>(input)
+            .unwrap_or_else(|e| TDigestError::CorruptData(e.to_string()).raise());
+        let layouts: Vec<&[u8]> = components.iter().map(TimescaleTDigestLazyRollup::layout).collect();
+        TimescaleTDigestLazyRollup::from_layouts(&layouts)
+    }
+}
+
+impl<'input> TimescaleTDigestLazyRollup<'input> {
+    pub const CURRENT_VERSION: u8 = 1;
+
+    fn layout<'a>(digest: &TimescaleTDigest<'a>) -> &'a [u8] {
+        &digest.pg_bytes()[4..]
+    }
+
+    fn from_layouts<L: std::borrow::Borrow<[u8]>>(layouts: &[L]) -> TimescaleTDigestLazyRollup<'static> {
+        let lengths: Vec<u32> = layouts.iter().map(|layout| layout.borrow().len() as u32).collect();
+        let bytes = layouts.concat();
+        let total_size = 16 + 4 * lengths.len() + bytes.len();
+        if total_size > 0x3FFF_FFFF {
+            TDigestError::OutOfRange(format!("TimescaleTDigestLazyRollup too large: {} bytes", total_size)).raise()
+        }
+        let components = lengths.len() as u32;
+        let layouts_len = bytes.len() as u32;
+        unsafe {
+            flatten!(
+                TimescaleTDigestLazyRollup {
+                    components: &components,
+                    lengths: &lengths,
+                    layouts_len: &layouts_len,
+                    layouts: &bytes,
+                }
+            )
+        }
+    }
+
+    // The component digests, in the order they were rolled up, checked the
+    // way any digest from outside input is.
+    fn components(&self) -> Result<Vec<TimescaleTDigest<'static>>, TDigestError> {
+        let total: u64 = self.lengths.iter().map(|&len| len as u64).sum();
+        if total != *self.layouts_len as u64 {
+            return Err(TDigestError::CorruptData(format!("component lengths sum to {} but there are {} bytes",
+                total, self.layouts_len)))
+        }
+        let mut start = 0;
+        self.lengths.iter()
+            .enumerate()
+            .map(|(i, &len)| {
+                let layout = &self.layouts[start..start + len as usize];
+                start += len as usize;
+                digest_from_bytes(layout).map_err(|e| match e {
+                    TDigestError::CorruptData(problem) => TDigestError::CorruptData(format!("component {}, {}", i + 1, problem)),
+                    e => e,
+                })
+            })
+            .collect()
+    }
+
+    // Merge the components all at once, at the largest of their sizes, or at
+    // `size` if given.
+    fn merge(&self, size: Option<usize>) -> Option<TimescaleTDigest<'static>> {
+        let components = self.components().unwrap_or_else(|e| e.raise());
+        let (first, rest) = components.split_first()?;
+        let mut exact_sum = first.parsed_exact_sum();
+        for component in rest {
+            exact_sum = add_exact_sums(exact_sum, component.parsed_exact_sum().as_ref());
+        }
+        let range = components.iter().fold(None, |range, component| union_ranges(range, component.range()));
+        let mut known_ends = components.iter().filter_map(|component| component.ends());
+        let ends = known_ends.next().map(|(first, last)| (first, known_ends.last().map_or(last, |(_, last)| last)));
+
+        let mut digests: Vec<TDigest> = components.iter().map(|component| component.to_tdigest()).collect();
+        if let Some(size) = size {
+            digests.push(TDigest::new_with_size(size));
+        }
+        let merged = TDigest::merge_digests_unordered(digests);
+        let merged = match size {
+            Some(size) => merged.compressed_to(size),
+            None => cap_centroids(merged),
+        };
+        TimescaleTDigest::from_tdigest_with(&merged, exact_sum.as_ref(), None, ends, range).into()
+    }
+}
+
+// Intermediate state for lazy_rollup, the layouts of the digests so far.
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct TDigestLazyRollupTransState {
+    layouts: Vec<Vec<u8>>,
+}
+
+#[pg_extern]
+pub fn tdigest_lazy_rollup_trans(
+    state: Option<Internal<TDigestLazyRollupTransState>>,
+    digest: Option<TimescaleTDigest>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Internal<TDigestLazyRollupTransState>> {
+    let mctx = aggregate_mctx(fcinfo);
+    let mctx = match mctx {
+        None => TDigestError::NotAggregate.raise(),
+        Some(mctx) => mctx,
+    };
+    unsafe {
+        in_memory_context(mctx, || {
+            let digest = match digest {
+                None => return state,
+                Some(digest) => digest,
+            };
+            let mut state = state.unwrap_or_else(|| TDigestLazyRollupTransState::default().into());
+            // the input only lives for this call, so the state keeps a copy
+            state.layouts.push(TimescaleTDigestLazyRollup::layout(&digest).to_vec());
+            Some(state)
+        })
+    }
+}
+
+#[pg_extern]
+pub fn tdigest_lazy_rollup_combine(
+    state1: Option<Internal<TDigestLazyRollupTransState>>,
+    state2: Option<Internal<TDigestLazyRollupTransState>>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Internal<TDigestLazyRollupTransState>> {
+    let mctx = aggregate_mctx(fcinfo);
+    let mctx = match mctx {
+        None => TDigestError::NotAggregate.raise(),
+        Some(mctx) => mctx,
+    };
+    unsafe {
+        in_memory_context(mctx, || {
+            let state = match (state1, state2) {
+                (None, None) => return None,
+                (None, Some(state)) | (Some(state), None) => state.clone().into(),
+                (Some(mut state1), Some(state2)) => {
+                    state1.layouts.extend(state2.layouts.iter().cloned());
+                    state1
+                },
+            };
+            Some(state)
+        })
+    }
+}
+
+#[pg_extern]
+pub fn tdigest_lazy_rollup_serialize(
+    state: Internal<TDigestLazyRollupTransState>,
+) -> bytea {
+    crate::do_serialize!(state)
+}
+
+#[pg_extern]
+pub fn tdigest_lazy_rollup_deserialize(
+    bytes: bytea,
+    _internal: Option<Internal<()>>,
+) -> Internal<TDigestLazyRollupTransState> {
+    crate::do_deserialize!(bytes, TDigestLazyRollupTransState)
+}
+
+#[pg_extern]
+fn tdigest_lazy_rollup_final(
+    state: Option<Internal<TDigestLazyRollupTransState>>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<TimescaleTDigestLazyRollup<'static>> {
+    unsafe {
+        in_aggregate_context(fcinfo, || {
+            let state = state?;
+            TimescaleTDigestLazyRollup::from_layouts(&state.layouts).into()
+        })
+    }
+}
+
+// Merge the components of a lazy rollup into a single digest. Unlike rollup,
+// which merges the digests one at a time and compresses after every step,
+// they are all merged at once, so the result doesn't depend on the order they
+// were rolled up in. The size is the largest of the components' sizes, like
+// rollup's. The quantile accessors also take lazy rollups, merging them this
+// way first.
+#[pg_extern(immutable, parallel_safe)]
+pub fn tdigest_lazy_merge(
+    rollup: TimescaleTDigestLazyRollup,
+    _fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<TimescaleTDigest<'static>> {
+    rollup.merge(None)
+}
+
+// Like tdigest_lazy_merge, but at the given size, which may be larger than
+// the components' to keep more of their detail. This is the SQL function
+// tdigest_lazy_merge(rollup, size).
+#[pg_extern(immutable, parallel_safe)]
+pub fn tdigest_lazy_merge_resized(
+    rollup: TimescaleTDigestLazyRollup,
+    size: int,
+    _fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<TimescaleTDigest<'static>> {
+    if size < 1 {
+        TDigestError::SizeZero("tdigest_lazy_merge size").raise()
+    }
+    rollup.merge(Some(size as usize))
+}
+
+// The number of digests in a lazy rollup.
+#[pg_extern(immutable, parallel_safe)]
+pub fn tdigest_lazy_components(
+    rollup: TimescaleTDigestLazyRollup,
+    _fcinfo: pg_sys::FunctionCallInfo,
+) -> i64 {
+    *rollup.components as i64
+}
+
+//---- t_digest_gridded: answer quantiles from a grid computed up front
+
+// A digest together with its estimates at GRID_POINTS evenly spaced
+// quantiles, computed once when it's built, for read-heavy workloads: a
+// quantile is then interpolated between the two nearest grid points instead
+// of being estimated from the centroids. It's a type of its own so that plain
+// digests don't carry the grid; casting it to TimescaleTDigest gives the
+// digest for the other accessors. The digest's layout, without the varlena
+// header, follows the grid. An empty digest has an empty grid.
+pg_type! {
+    #[derive(Debug)]
+    struct TimescaleTDigestGridded {
+        digest_len: u32,
+        points: u32,
+        grid: [f64; self.points],
+        digest: [u8; self.digest_len],
+    }
+}
+
+// The text format is that of TimescaleTDigest, the grid is recomputed on input.
+impl<'input> InOutFuncs for TimescaleTDigestGridded<'input> {
+    fn output(&self, buffer: &mut StringInfo) {
+        self.to_digest().output(buffer)
+    }
+
+    fn input(input: &std::ffi::CStr) -> Self
+    where
+        Self: Sized,
+    {
+        TimescaleTDigestGridded::from_digest(&TimescaleTDigest::input(input))
+    }
+}
+
+impl<'input> TimescaleTDigestGridded<'input> {
+    pub const CURRENT_VERSION: u8 = 1;
+    const GRID_POINTS: u32 = 101;
+
+    fn from_digest(digest: &TimescaleTDigest) -> TimescaleTDigestGridded<'static> {
+        let grid: Vec<f64> = if *digest.count == 0 {
+            vec![]
+        } else {
+            let slices = digest.centroid_slices();
+            let last = (Self::GRID_POINTS - 1) as f64;
+            let mut floor = std::f64::NEG_INFINITY;
+            (0..Self::GRID_POINTS)
+                .map(|i| {
+                    floor = floor.max(slices.estimate_quantile(i as f64 / last));
+                    floor
+                })
+                .collect()
+        };
+        let layout = &digest.pg_bytes()[4..];
+        let digest_len = layout.len() as u32;
+        let points = grid.len() as u32;
+        unsafe {
+            flatten!(
+                TimescaleTDigestGridded {
+                    digest_len: &digest_len,
+                    points: &points,
+                    grid: &grid,
+                    digest: layout,
+                }
+            )
+        }
+    }
+
+    fn to_digest(&self) -> TimescaleTDigest<'static> {
+        digest_from_bytes(self.digest).unwrap_or_else(|e| e.raise())
+    }
+
+    // Interpolate linearly between the grid points on either side.
+    fn quantile(&self, quantile: f64) -> Option<f64> {
+        if !(0.0..=1.0).contains(&quantile) {
+            TDigestError::BadQuantile(quantile).raise()
+        }
+        match self.grid.len() {
+            0 => return None,
+            1 => return Some(self.grid[0]),
+            _ => (),
+        }
+        let position = quantile * (self.grid.len() - 1) as f64;
+        let below = (position.floor() as usize).min(self.grid.len() - 2);
+        let fraction = position - below as f64;
+        Some(self.grid[below] + (self.grid[below + 1] - self.grid[below]) * fraction)
+    }
+}
+
+#[pg_extern]
+fn tdigest_gridded_final(
+    state: Option<Internal<TDigestTransState>>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<TimescaleTDigestGridded<'static>> {
+    unsafe {
+        in_aggregate_context(fcinfo, || {
+            let mut state = state?;
+            TimescaleTDigestGridded::from_digest(&state.finish()).into()
+        })
+    }
+}
+
+// Add a grid to a stored digest, see t_digest_gridded.
+#[pg_extern(immutable, parallel_safe)]
+pub fn tdigest_gridded(
+    digest: TimescaleTDigest,
+    _fcinfo: pg_sys::FunctionCallInfo,
+) -> TimescaleTDigestGridded<'static> {
+    TimescaleTDigestGridded::from_digest(&digest)
+}
+
+// The digest without the grid, as the cast to TimescaleTDigest.
+#[pg_extern(immutable, parallel_safe)]
+pub fn tdigest_gridded_digest(
+    gridded: TimescaleTDigestGridded,
+    _fcinfo: pg_sys::FunctionCallInfo,
+) -> TimescaleTDigest<'static> {
+    gridded.to_digest()
+}
+
+// Approximate the value at the given quantile from the grid, without looking
+// at the centroids. This is the SQL function tdigest_quantile(gridded,
+// quantile). Between grid points it interpolates linearly, where
+// tdigest_quantile interpolates between centroids, so the two can differ by
+// about as much as the values change within 1% of the quantiles.
+#[pg_extern(immutable, parallel_safe)]
+pub fn tdigest_gridded_quantile(
+    gridded: TimescaleTDigestGridded,
+    quantile: f64,
+    _fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<f64> {
+    gridded.quantile(quantile)
+}
+
+//---- t_digest_with_exact_mode: also track the exact most common value
+
+// Number of distinct values t_digest_with_exact_mode counts before giving up
+// on the exact mode. Each counted value takes 16 bytes plus the map's overhead.
+const MAX_EXACT_MODE_VALUES: usize = 1000;
+
+// Intermediate state for t_digest_with_exact_mode. The counts of each
+// distinct value are kept alongside the digest, until there are more than
+// MAX_EXACT_MODE_VALUES of them.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct TDigestModeTransState {
+    // keyed by bit pattern, since f64 can't be hashed directly
+    counts: Option<HashMap<u64, u64>>,
+    digest_state: TDigestTransState,
+}
+
+impl TDigestModeTransState {
+    fn count(&mut self, bits: u64, count: u64) {
+        if let Some(counts) = &mut self.counts {
+            *counts.entry(bits).or_insert(0) += count;
+            if counts.len() > MAX_EXACT_MODE_VALUES {
+                self.counts = None
+            }
+        }
+    }
+
+    // The most common value, the smallest one in case of ties.
+    fn mode(&self) -> Option<f64> {
+        self.counts.as_ref()?
+            .iter()
+            .map(|(&bits, &count)| (count, f64::from_bits(bits)))
+            .max_by(|(count1, value1), (count2, value2)| {
+                count1.cmp(count2).then(value2.partial_cmp(value1).unwrap_or(std::cmp::Ordering::Equal))
+            })
+            .map(|(_, value)| value)
+    }
+}
+
+#[pg_extern]
+pub fn tdigest_mode_trans(
+    state: Option<Internal<TDigestModeTransState>>,
+    size: int,
+    value: Option<f64>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Internal<TDigestModeTransState>> {
+    let mctx = aggregate_mctx(fcinfo);
+    let mctx = match mctx {
+        None => TDigestError::NotAggregate.raise(),
+        Some(mctx) => mctx,
+    };
+    unsafe {
+        in_memory_context(mctx, || {
+            let value = match value {
+                None => return state,
+                Some(value) if value.is_nan() => return state,
+                // count values the way SQL compares them: -0 equals 0
+                Some(value) if value == 0.0 => 0.0,
+                Some(value) => value,
+            };
+            let mut state = match state {
+                None => TDigestModeTransState {
+                    counts: Some(HashMap::new()),
+                    digest_state: TDigestTransState::new(size as _),
+                }.into(),
+                Some(state) => state,
+            };
+            state.count(value.to_bits(), 1);
+            state.digest_state.push(value);
+            Some(state)
+        })
+    }
+}
+
+#[pg_extern]
+pub fn tdigest_mode_combine(
+    state1: Option<Internal<TDigestModeTransState>>,
+    state2: Option<Internal<TDigestModeTransState>>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Internal<TDigestModeTransState>> {
+    let mctx = aggregate_mctx(fcinfo);
+    let mctx = match mctx {
+        None => TDigestError::NotAggregate.raise(),
+        Some(mctx) => mctx,
+    };
+    unsafe {
+        in_memory_context(mctx, || {
+            let digest_state = combine(
+                state1.as_deref().map(|s| &s.digest_state),
+                state2.as_deref().map(|s| &s.digest_state),
+            )?;
+            let mut state = TDigestModeTransState {
+                counts: Some(HashMap::new()),
+                digest_state,
+            };
+            for other in state1.iter().chain(state2.iter()) {
+                match &other.counts {
+                    None => state.counts = None,
+                    Some(counts) => for (&bits, &count) in counts {
+                        state.count(bits, count)
+                    },
+                }
+            }
+            Some(state.into())
+        })
+    }
+}
+
+#[pg_extern]
+pub fn tdigest_mode_serialize(
+    mut state: Internal<TDigestModeTransState>,
+) -> bytea {
+    state.digest_state.digest();
+    crate::do_serialize!(state)
+}
+
+#[pg_extern]
+pub fn tdigest_mode_deserialize(
+    bytes: bytea,
+    _internal: Option<Internal<()>>,
+) -> Internal<TDigestModeTransState> {
+    crate::do_deserialize!(bytes, TDigestModeTransState)
+}
+
+#[pg_extern]
+fn tdigest_mode_final(
+    state: Option<Internal<TDigestModeTransState>>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<TimescaleTDigest<'static>> {
+    unsafe {
+        in_aggregate_context(fcinfo, || {
+            let mut state = state?;
+            state.digest_state.digest();
+            TimescaleTDigest::from_tdigest_with(
+                &state.digest_state.digested,
+                None,
+                state.mode(),
+                state.digest_state.ends(),
+                state.digest_state.range,
+            ).into()
+        })
+    }
+}
+
+//---- t_digest_distinct: a digest of the distinct values only
+
+// Number of distinct values t_digest_distinct keeps before flushing them to
+// its digest. Each kept value takes 8 bytes plus the set's overhead.
+const MAX_DISTINCT_VALUES: usize = 10_000;
+
+// Intermediate state for t_digest_distinct. Because the same value may turn up
+// in several partial states, we can't push values into a digest as they
+// arrive; instead the distinct values themselves are kept until the final
+// function, or until there are MAX_DISTINCT_VALUES of them. Then they are
+// flushed to the digest, and values seen again after that are counted again,
+// so only that many distinct values are counted exactly.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct TDigestDistinctTransState {
+    // stored as bit patterns, since f64 can't be hashed directly
+    values: HashSet<u64>,
+    digested: TDigest,
+}
+
+impl TDigestDistinctTransState {
+    // Flush the values to the digest once there are too many to keep.
+    fn limit(&mut self) {
+        if self.values.len() >= MAX_DISTINCT_VALUES {
+            let values = self.values.drain().map(f64::from_bits).collect();
+            self.digested = self.digested.merge_unsorted(values);
+        }
+    }
+}
+
+#[pg_extern]
+pub fn tdigest_distinct_trans(
+    state: Option<Internal<TDigestDistinctTransState>>,
+    size: int,
+    value: Option<f64>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Internal<TDigestDistinctTransState>> {
+    let mctx = aggregate_mctx(fcinfo);
+    let mctx = match mctx {
+        None => TDigestError::NotAggregate.raise(),
+        Some(mctx) => mctx,
+    };
+    unsafe {
+        in_memory_context(mctx, || {
+            let value = match value {
+                None => return state,
+                // NaN is skipped, as by t_digest
+                Some(value) if value.is_nan() => return state,
+                // compare values the way SQL does: -0 equals 0
+                Some(value) if value == 0.0 => 0.0,
+                Some(value) => value,
+            };
+            let mut state = match state {
+                None => TDigestDistinctTransState {
+                    values: HashSet::new(),
+                    digested: TDigest::new_with_size(size as _),
+                }.into(),
+                Some(state) => state,
+            };
+            state.values.insert(value.to_bits());
+            state.limit();
+            Some(state)
+        })
+    }
+}
+
+#[pg_extern]
+pub fn tdigest_distinct_combine(
+    state1: Option<Internal<TDigestDistinctTransState>>,
+    state2: Option<Internal<TDigestDistinctTransState>>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Internal<TDigestDistinctTransState>> {
+    let mctx = aggregate_mctx(fcinfo);
+    let mctx = match mctx {
+        None => TDigestError::NotAggregate.raise(),
+        Some(mctx) => mctx,
+    };
+    unsafe {
+        in_memory_context(mctx, || {
+            match (state1, state2) {
+                (None, None) => None,
+                (None, Some(state)) | (Some(state), None) => Some(state.clone().into()),
+                (Some(state1), Some(state2)) => {
+                    let mut state = state1.clone();
+                    state.values.extend(state2.values.iter().copied());
+                    state.digested.merge_digest_in_place(&state2.digested);
+                    state.limit();
+                    Some(state.into())
+                }
+            }
+        })
+    }
+}
+
+#[pg_extern]
+pub fn tdigest_distinct_serialize(
+    state: Internal<TDigestDistinctTransState>,
+) -> bytea {
+    crate::do_serialize!(state)
+}
+
+#[pg_extern]
+pub fn tdigest_distinct_deserialize(
+    bytes: bytea,
+    _internal: Option<Internal<()>>,
+) -> Internal<TDigestDistinctTransState> {
+    crate::do_deserialize!(bytes, TDigestDistinctTransState)
+}
+
+#[pg_extern]
+fn tdigest_distinct_final(
+    state: Option<Internal<TDigestDistinctTransState>>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<TimescaleTDigest<'static>> {
+    unsafe {
+        in_aggregate_context(fcinfo, || {
+            let state = state?;
+            if state.digested.count() > 0.0 {
+                notice!("t_digest_distinct got more than {} distinct values, values repeated past that may be counted more than once",
+                    MAX_DISTINCT_VALUES);
+            }
+            let values = state.values.iter().map(|&bits| f64::from_bits(bits)).collect();
+            let digest = state.digested.merge_unsorted(values);
+            TimescaleTDigest::from_tdigest(&digest).into()
+        })
+    }
+}
+
+//---- t_digest_by: one digest per key, in a single aggregate
+
+// Number of distinct keys t_digest_by accepts. Every key holds a digest of its
+// own, so this bounds the state to that many times t_digest's.
+const MAX_DIGEST_KEYS: usize = 1000;
+
+// Intermediate state for t_digest_by, the state t_digest would build for each
+// key seen so far.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct TDigestByTransState {
+    size: usize,
+    digests: HashMap<String, TDigestTransState>,
+}
+
+impl TDigestByTransState {
+    fn check_keys(&self) {
+        if self.digests.len() > MAX_DIGEST_KEYS {
+            TDigestError::OutOfRange(format!("t_digest_by got more than {} distinct keys", MAX_DIGEST_KEYS)).raise()
+        }
+    }
+}
+
+// Rows with a NULL key or value are ignored.
+#[pg_extern]
+pub fn tdigest_by_trans(
+    state: Option<Internal<TDigestByTransState>>,
+    size: int,
+    key: Option<String>,
+    value: Option<f64>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Internal<TDigestByTransState>> {
+    let mctx = aggregate_mctx(fcinfo);
+    let mctx = match mctx {
+        None => TDigestError::NotAggregate.raise(),
+        Some(mctx) => mctx,
+    };
+    unsafe {
+        in_memory_context(mctx, || {
+            let (key, value) = match (key, value) {
+                (Some(key), Some(value)) if !value.is_nan() => (key, value),
+                _ => return state,
+            };
+            let mut state = match state {
+                None => TDigestByTransState {
+                    size: size as _,
+                    digests: HashMap::new(),
+                }.into(),
+                Some(state) => state,
+            };
+            let size = state.size;
+            state.digests.entry(key)
+                .or_insert_with(|| TDigestTransState::new(size))
+                .push(value);
+            state.check_keys();
+            Some(state)
+        })
+    }
+}
+
+#[pg_extern]
+pub fn tdigest_by_combine(
+    state1: Option<Internal<TDigestByTransState>>,
+    state2: Option<Internal<TDigestByTransState>>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Internal<TDigestByTransState>> {
+    let mctx = aggregate_mctx(fcinfo);
+    let mctx = match mctx {
+        None => TDigestError::NotAggregate.raise(),
+        Some(mctx) => mctx,
+    };
+    unsafe {
+        in_memory_context(mctx, || {
+            match (state1, state2) {
+                (None, None) => None,
+                (None, Some(state)) | (Some(state), None) => Some(state.clone().into()),
+                (Some(state1), Some(state2)) => {
+                    let mut state = state1.clone();
+                    for (key, other) in &state2.digests {
+                        let combined = combine(state.digests.get(key), Some(other)).unwrap();
+                        state.digests.insert(key.clone(), combined);
+                    }
+                    state.check_keys();
+                    Some(state.into())
+                }
+            }
+        })
+    }
+}
+
+#[pg_extern]
+pub fn tdigest_by_serialize(
+    mut state: Internal<TDigestByTransState>,
+) -> bytea {
+    for digest_state in state.digests.values_mut() {
+        digest_state.digest();
+    }
+    crate::do_serialize!(state)
+}
+
+#[pg_extern]
+pub fn tdigest_by_deserialize(
+    bytes: bytea,
+    _internal: Option<Internal<()>>,
+) -> Internal<TDigestByTransState> {
+    crate::do_deserialize!(bytes, TDigestByTransState)
+}
+
+// The digests as a JSON object from each key to its digest, in the form of
+// tdigest_to_json, so they can be read back with tdigest_from_json.
+#[pg_extern]
+fn tdigest_by_final(
+    state: Option<Internal<TDigestByTransState>>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<JsonB> {
+    unsafe {
+        in_aggregate_context(fcinfo, || {
+            let mut state = state?;
+            let mut digests = serde_json::Map::new();
+            for (key, digest_state) in state.digests.iter_mut() {
+                let json = serde_json::to_value(&digest_state.finish())
+                    .unwrap_or_else(|e| TDigestError::Internal(format!("cannot convert TimescaleTDigest to JSON: {}", e)).raise());
+                digests.insert(key.clone(), json);
+            }
+            Some(JsonB(serde_json::Value::Object(digests)))
+        })
+    }
+}
+
+//---- t_digest_json: t_digest over a number inside each row's jsonb
+
+// Intermediate state for t_digest_json, the state t_digest would build for
+// the numbers found, and how many rows had none at the path.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct TDigestJsonTransState {
+    path: String,
+    skipped: u64,
+    digest_state: TDigestTransState,
+}
+
+// The number at `path` in `json`, where the path is a list of keys separated
+// by `.`, e.g. `request.latency`, and a key that is a number indexes into an
+// array. Only JSON numbers count, a string like "12" is not one.
+fn json_path_number(json: &serde_json::Value, path: &str) -> Option<f64> {
+    let mut value = json;
+    for key in path.split('.') {
+        value = match value {
+            serde_json::Value::Object(object) => object.get(key)?,
+            serde_json::Value::Array(array) => array.get(key.parse::<usize>().ok()?)?,
+            _ => return None,
+        };
+    }
+    value.as_f64()
+}
+
+// Rows with a NULL payload are ignored, like rows with a NULL value are by
+// t_digest. Rows without a number at the path are skipped and counted, the
+// final function reports how many there were.
+#[pg_extern]
+pub fn tdigest_json_trans(
+    state: Option<Internal<TDigestJsonTransState>>,
+    size: int,
+    payload: Option<JsonB>,
+    path: String,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Internal<TDigestJsonTransState>> {
+    let mctx = aggregate_mctx(fcinfo);
+    let mctx = match mctx {
+        None => TDigestError::NotAggregate.raise(),
+        Some(mctx) => mctx,
+    };
+    unsafe {
+        in_memory_context(mctx, || {
+            let payload = match payload {
+                None => return state,
+                Some(payload) => payload,
+            };
+            let mut state = match state {
+                None => {
+                    if path.is_empty() {
+                        TDigestError::BadArgument("t_digest_json path must not be empty".to_string()).raise()
+                    }
+                    TDigestJsonTransState {
+                        path,
+                        skipped: 0,
+                        digest_state: TDigestTransState::new(size as _),
+                    }.into()
+                },
+                Some(state) => state,
+            };
+            match json_path_number(&payload.0, &state.path) {
+                Some(value) => state.digest_state.push(value),
+                None => state.skipped += 1,
+            }
+            Some(state)
+        })
+    }
+}
+
+#[pg_extern]
+pub fn tdigest_json_combine(
+    state1: Option<Internal<TDigestJsonTransState>>,
+    state2: Option<Internal<TDigestJsonTransState>>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Internal<TDigestJsonTransState>> {
+    let mctx = aggregate_mctx(fcinfo);
+    let mctx = match mctx {
+        None => TDigestError::NotAggregate.raise(),
+        Some(mctx) => mctx,
+    };
+    unsafe {
+        in_memory_context(mctx, || {
+            let path = state1.as_ref().or(state2.as_ref())?.path.clone();
+            let skipped = state1.as_ref().map_or(0, |s| s.skipped)
+                + state2.as_ref().map_or(0, |s| s.skipped);
+            let digest_state = combine(
+                state1.as_deref().map(|s| &s.digest_state),
+                state2.as_deref().map(|s| &s.digest_state),
+            )?;
+            Some(TDigestJsonTransState { path, skipped, digest_state }.into())
+        })
+    }
+}
+
+#[pg_extern]
+pub fn tdigest_json_serialize(
+    mut state: Internal<TDigestJsonTransState>,
+) -> bytea {
+    state.digest_state.digest();
+    crate::do_serialize!(state)
+}
+
+#[pg_extern]
+pub fn tdigest_json_deserialize(
+    bytes: bytea,
+    _internal: Option<Internal<()>>,
+) -> Internal<TDigestJsonTransState> {
+    crate::do_deserialize!(bytes, TDigestJsonTransState)
+}
+
+#[pg_extern]
+fn tdigest_json_final(
+    state: Option<Internal<TDigestJsonTransState>>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<TimescaleTDigest<'static>> {
+    unsafe {
+        in_aggregate_context(fcinfo, || {
+            let mut state = state?;
+            if state.skipped > 0 {
+                notice!("t_digest_json skipped {} rows without a number at \"{}\"", state.skipped, state.path);
+            }
+            state.digest_state.finish().into()
+        })
+    }
+}
+
+//---- Available PG operations on the digest
+
+// The estimates below are NULL for an empty digest, the way SQL aggregates
+// over no rows are.
+
+// Approximate the value at the given quantile (0.0-1.0)
+#[pg_extern(immutable, parallel_safe)]
+pub fn tdigest_quantile(
+    digest: TimescaleTDigest,
+    quantile: f64,
+    _fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<f64> {
+    if !(0.0..=1.0).contains(&quantile) {
+        TDigestError::BadQuantile(quantile).raise()
+    }
+    if *digest.count == 0 {
+        return None
+    }
+    // the ends are stored exactly, so dashboards asking for p0 and p100 don't
+    // need to look at the centroids at all
+    if quantile == 0.0 {
+        return Some(*digest.min)
+    }
+    if quantile == 1.0 {
+        return Some(*digest.max)
+    }
+    digest.centroid_slices().estimate_quantile(quantile).into()
+}
+
+// Approximate the value at the given quantile like tdigest_quantile, but if
+// `extrapolate` is set, the quantiles beyond the middles of the first and
+// last centroids are projected along the line through the two centroids at
+// that end, instead of being interpolated towards the min and max. The
+// estimate can then fall outside of the range of values ever seen, which is
+// the point when forecasting, but it's only as good as the assumption that the
+// tail continues in a straight line. This is the SQL function
+// tdigest_quantile(digest, quantile, extrapolate).
+#[pg_extern(immutable, parallel_safe)]
+pub fn tdigest_quantile_extrapolated(
+    digest: TimescaleTDigest,
+    quantile: f64,
+    extrapolate: bool,
+    _fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<f64> {
+    if !(0.0..=1.0).contains(&quantile) {
+        TDigestError::BadQuantile(quantile).raise()
+    }
+    if *digest.count == 0 {
+        return None
+    }
+    let digest = digest.to_tdigest();
+    let centroids = digest.raw_centroids();
+    if extrapolate && centroids.len() >= 2 {
+        let rank = quantile * digest.count();
+        let line = |a: &Centroid, b: &Centroid| (b.mean() - a.mean()) / ((a.weight() + b.weight()) / 2.0);
+
+        let (first, second) = (&centroids[0], &centroids[1]);
+        let start = first.weight() / 2.0;
+        if rank < start {
+            return Some(first.mean() - (start - rank) * line(first, second))
+        }
+
+        let (last, second_last) = (&centroids[centroids.len() - 1], &centroids[centroids.len() - 2]);
+        let end = digest.count() - last.weight() / 2.0;
+        if rank > end {
+            return Some(last.mean() + (rank - end) * line(second_last, last))
+        }
+    }
+    Some(digest.estimate_quantile(quantile))
+}
+
+// Approximate the value at the given quantile of each digest in an array
+// separately, rather than of their combination. NULL or empty digests give
+// NULL results.
+#[pg_extern(immutable, parallel_safe)]
+pub fn tdigest_quantile_each(
+    digests: Array<TimescaleTDigest>,
+    quantile: f64,
+    _fcinfo: pg_sys::FunctionCallInfo,
+) -> Vec<Option<f64>> {
+    if !(0.0..=1.0).contains(&quantile) {
+        TDigestError::BadQuantile(quantile).raise()
+    }
+    digests.iter()
+        .map(|digest| {
+            let digest = digest?;
+            if *digest.count == 0 {
+                return None
+            }
+            Some(digest.centroid_slices().estimate_quantile(quantile))
+        })
+        .collect()
+}
+
+// Approximate the values at each of an array of quantiles, reconstructing the
+// digest only once. Interpolating within neighbouring centroids can make an
+// estimate come out below that of a smaller quantile, e.g. a p95 below the
+// p90, so each result is raised to at least the results of all the smaller
+// quantiles. NULL quantiles give NULL results.
+#[pg_extern(immutable, parallel_safe)]
+pub fn tdigest_quantiles(
+    digest: TimescaleTDigest,
+    quantiles: Array<f64>,
+    _fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Vec<Option<f64>>> {
+    if *digest.count == 0 {
+        return None
+    }
+    let quantiles: Vec<Option<f64>> = quantiles.iter().collect();
+    for &quantile in quantiles.iter().flatten() {
+        if !(0.0..=1.0).contains(&quantile) {
+            TDigestError::BadQuantile(quantile).raise()
+        }
+    }
+
+    let mut order: Vec<(usize, f64)> = quantiles.iter()
+        .enumerate()
+        .filter_map(|(i, quantile)| Some((i, (*quantile)?)))
+        .collect();
+    order.sort_by(|(_, q1), (_, q2)| q1.partial_cmp(q2).unwrap());
+
+    let slices = digest.centroid_slices();
+    let mut values = vec![None; quantiles.len()];
+    let mut floor = std::f64::NEG_INFINITY;
+    for (i, quantile) in order {
+        floor = floor.max(slices.estimate_quantile(quantile));
+        values[i] = Some(floor);
+    }
+    Some(values)
+}
+
+// Approximate the value at the given integer percentile (0-100)
+#[pg_extern(immutable, parallel_safe)]
+pub fn tdigest_percentile(
+    digest: TimescaleTDigest,
+    percentile: i32,
+    _fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<f64> {
+    if percentile < 0 || percentile > 100 {
+        TDigestError::BadArgument(format!("percentile must be between 0 and 100, got {}", percentile)).raise()
+    }
+    if *digest.count == 0 {
+        return None
+    }
+    digest.centroid_slices().estimate_quantile(percentile as f64 / 100.0).into()
+}
+
+// Approximate the value at a percentile given as text, e.g. '99.9%', the way
+// some BI tools pass them. The % sign is optional, the number is a percentage
+// either way.
+#[pg_extern(immutable, parallel_safe)]
+pub fn tdigest_percentile_str(
+    digest: TimescaleTDigest,
+    pct: &str,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<f64> {
+    let trimmed = pct.trim();
+    let number = trimmed.strip_suffix('%').unwrap_or(trimmed).trim_end();
+    let percentile: f64 = number.parse()
+        .ok()
+        .filter(|percentile: &f64| percentile.is_finite())
+        .unwrap_or_else(|| TDigestError::BadArgument(format!("invalid percentile {:?}, expected a number like '99.9%'", pct)).raise());
+    if !(0.0..=100.0).contains(&percentile) {
+        TDigestError::BadArgument(format!("percentile must be between 0 and 100, got {}", percentile)).raise()
+    }
+    tdigest_quantile(digest, percentile / 100.0, fcinfo)
+}
+
+// Approximate the quantile at the given value
+#[pg_extern(immutable, parallel_safe)]
+pub fn tdigest_quantile_at_value(
+    digest: TimescaleTDigest,
+    value: f64,
+    _fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<f64> {
+    if *digest.count == 0 {
+        return None
+    }
+    digest.to_tdigest().estimate_quantile_at_value(value).into()
+}
+
+// Approximate the value at the given quantile of a digest built by
+// t_digest_log.
+#[pg_extern(immutable, parallel_safe)]
+pub fn tdigest_log_quantile(
+    digest: TimescaleTDigest,
+    quantile: f64,
+    _fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<f64> {
+    if !(0.0..=1.0).contains(&quantile) {
+        TDigestError::BadQuantile(quantile).raise()
+    }
+    if *digest.count == 0 {
+        return None
+    }
+    digest.centroid_slices().estimate_quantile(quantile).exp().into()
+}
+
+// Estimate what quantile a value is located at in a digest built by
+// t_digest_log. Such a digest only holds positive values, so every value
+// that isn't is below all of them.
+#[pg_extern(immutable, parallel_safe)]
+pub fn tdigest_log_quantile_at_value(
+    digest: TimescaleTDigest,
+    value: f64,
+    _fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<f64> {
+    if *digest.count == 0 {
+        return None
+    }
+    if !(value > 0.0) {
+        return Some(0.0)
+    }
+    digest.to_tdigest().estimate_quantile_at_value(value.ln()).into()
+}
+
+// Approximate the value below which the first `n` of the digest's values fall,
+// i.e. the value at the quantile n / count.
+#[pg_extern(immutable, parallel_safe)]
+pub fn tdigest_value_at_count(
+    digest: TimescaleTDigest,
+    n: i64,
+    _fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<f64> {
+    if !(0 <= n && n <= *digest.count as i64) {
+        TDigestError::BadArgument(format!("n must be between 0 and the digest's count of {}, got {}", digest.count, n)).raise()
+    }
+    if *digest.count == 0 {
+        return None
+    }
+    let quantile = n as f64 / *digest.count as f64;
+    digest.centroid_slices().estimate_quantile(quantile).into()
+}
+
+// the most rows tdigest_quantile_series will produce for one digest
+const MAX_QUANTILE_SERIES_ROWS: u64 = 1_000_000;
+
+// Approximate the values at evenly spaced quantiles from `start` to `stop`
+// (inclusive), one row per quantile. The digest is only reconstructed once.
+// An empty digest has no quantiles, so gives no rows.
+#[pg_extern(immutable, parallel_safe)]
+pub fn tdigest_quantile_series(
+    digest: TimescaleTDigest,
+    start: f64,
+    stop: f64,
+    step: f64,
+    _fcinfo: pg_sys::FunctionCallInfo,
+) -> impl std::iter::Iterator<Item = (name!(quantile, f64), name!(value, f64))> {
+    if !(step > 0.0) {
+        TDigestError::BadArgument(format!("tdigest_quantile_series step must be greater than 0, got {}", step)).raise()
+    }
+    for &quantile in &[start, stop] {
+        if !(0.0..=1.0).contains(&quantile) {
+            TDigestError::BadQuantile(quantile).raise()
+        }
+    }
+    if !(start <= stop) {
+        TDigestError::BadArgument(format!("tdigest_quantile_series start ({}) must not be greater than stop ({})", start, stop)).raise()
+    }
+    // allow a little slack so that a `stop` that is a multiple of `step`
+    // isn't dropped due to rounding
+    let steps = ((stop - start) / step + 1e-9).floor();
+    if steps >= MAX_QUANTILE_SERIES_ROWS as f64 {
+        TDigestError::OutOfRange(format!("tdigest_quantile_series step is too small, gives more than {} rows from {} to {}",
+            MAX_QUANTILE_SERIES_ROWS, start, stop)).raise()
+    }
+
+    let rows = if *digest.count == 0 { 0 } else { steps as u64 + 1 };
+    let digest = digest.to_tdigest();
+    (0..rows).map(move |i| {
+        let quantile = (start + i as f64 * step).min(stop);
+        (quantile, digest.estimate_quantile(quantile))
+    })
+}
+
+// Approximate the range of values covering the central `coverage` fraction of
+// the data, e.g. a coverage of 0.9 gives the 5th and 95th percentiles. Both
+// ends are NULL for an empty digest.
+#[pg_extern(immutable, parallel_safe)]
+pub fn tdigest_central_range(
+    digest: TimescaleTDigest,
+    coverage: f64,
+    _fcinfo: pg_sys::FunctionCallInfo,
+) -> impl std::iter::Iterator<Item = (name!(low, Option<f64>), name!(high, Option<f64>))> {
+    if !(coverage > 0.0 && coverage < 1.0) {
+        TDigestError::BadArgument(format!("coverage must be strictly between 0 and 1, got {}", coverage)).raise()
+    }
+    if *digest.count == 0 {
+        return std::iter::once((None, None))
+    }
+    let digest = digest.centroid_slices();
+    let low = digest.estimate_quantile((1.0 - coverage) / 2.0);
+    let high = digest.estimate_quantile((1.0 + coverage) / 2.0);
+    std::iter::once((Some(low), Some(high)))
+}
+
+// Approximate how many values lie within Tukey's fences, that is at most k
+// interquartile ranges below the first quartile or above the third. This is
+// the count with the outliers trimmed; k is usually 1.5, or 3 to only trim the
+// far outliers.
+#[pg_extern(immutable, parallel_safe)]
+pub fn tdigest_count_within_iqr(
+    digest: TimescaleTDigest,
+    k: f64,
+    _fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<f64> {
+    if !(k >= 0.0 && k.is_finite()) {
+        TDigestError::BadArgument(format!("k must be finite and at least 0, got {}", k)).raise()
+    }
+    if *digest.count == 0 {
+        return None
+    }
+    let digest = digest.to_tdigest();
+    let q1 = digest.estimate_quantile(0.25);
+    let q3 = digest.estimate_quantile(0.75);
+    let iqr = q3 - q1;
+    let low = digest.estimate_quantile_at_value(q1 - k * iqr);
+    let high = digest.estimate_quantile_at_value(q3 + k * iqr);
+    Some((high - low) * digest.count())
+}
+
+// Approximate the value at the given quantile, along with the means of the two
+// centroids it is interpolated between (or the min or max, beyond the first or
+// last centroid). lo and hi show how finely the digest resolves the data
+// around the quantile: they are close together where the data is dense and far
+// apart where it is sparse. They are not a statistical confidence interval.
+// An empty digest gives no row.
+#[pg_extern(immutable, parallel_safe)]
+pub fn tdigest_quantile_ci(
+    digest: TimescaleTDigest,
+    quantile: f64,
+    _fcinfo: pg_sys::FunctionCallInfo,
+) -> impl std::iter::Iterator<Item = (name!(value, f64), name!(lo, f64), name!(hi, f64))> {
+    if !(0.0..=1.0).contains(&quantile) {
+        TDigestError::BadQuantile(quantile).raise()
+    }
+    if *digest.count == 0 {
+        return None.into_iter()
+    }
+
+    let value = digest.centroid_slices().estimate_quantile(quantile);
+    let (lo, hi) = digest.bracketing_means(quantile);
+    Some((value, lo.min(value), hi.max(value))).into_iter()
+}
+
+// The distance between the two centroid means the estimate at the given
+// quantile is interpolated between, as for tdigest_quantile_ci. Values closer
+// together than this can't be told apart there, e.g. if it's large around the
+// 0.999 quantile, the digest's size is too small for p99.9 to mean much.
+#[pg_extern(immutable, parallel_safe)]
+pub fn tdigest_quantile_resolution(
+    digest: TimescaleTDigest,
+    quantile: f64,
+    _fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<f64> {
+    if !(0.0..=1.0).contains(&quantile) {
+        TDigestError::BadQuantile(quantile).raise()
+    }
+    if *digest.count == 0 {
+        return None
+    }
+    let (lo, hi) = digest.bracketing_means(quantile);
+    Some(hi - lo)
+}
+
+// Approximate the value at the given quantile of several digests combined,
+// where each digest's centroids count `weight` times as much as they
+// otherwise would. With all weights 1 this is the same as merging the digests.
+#[pg_extern(immutable, parallel_safe)]
+pub fn tdigest_weighted_quantile(
+    digests: Array<TimescaleTDigest>,
+    weights: Array<f64>,
+    quantile: f64,
+    _fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<f64> {
+    if !(0.0..=1.0).contains(&quantile) {
+        TDigestError::BadQuantile(quantile).raise()
+    }
+    if digests.len() != weights.len() {
+        TDigestError::BadArgument(format!("got {} digests but {} weights", digests.len(), weights.len())).raise()
+    }
+
+    let mut scaled = Vec::with_capacity(digests.len());
+    for (digest, weight) in digests.iter().zip(weights.iter()) {
+        let (digest, weight) = match (digest, weight) {
+            (Some(digest), Some(weight)) => (digest, weight),
+            _ => TDigestError::BadArgument("digests and weights cannot be NULL".to_string()).raise(),
+        };
+        if !(weight > 0.0 && weight.is_finite()) {
+            TDigestError::BadArgument(format!("digest weights must be positive, got {}", weight)).raise()
+        }
+        if *digest.count == 0 {
+            continue
+        }
+
+        let digest = digest.to_tdigest();
+        let cents = digest.raw_centroids()
+            .iter()
+            .map(|cent| Centroid::new(cent.mean(), cent.weight() * weight))
+            .collect();
+        scaled.push(TDigest::new(
+            cents,
+            digest.sum() * weight,
+            digest.count() * weight,
+            digest.max(),
+            digest.min(),
+            digest.max_size(),
+        ));
+    }
+
+    // merge_digests merges at the size of the first digest
+    let largest = (0..scaled.len()).max_by_key(|&i| scaled[i].max_size())?;
+    scaled.swap(0, largest);
+    TDigest::merge_digests(scaled).estimate_quantile(quantile).into()
+}
+
+// Approximate the value at the given quantile of several digests combined,
+// e.g. the p99 across a few regions, without building the combined digest the
+// way rollup would. The digests are merged at the largest of their sizes, like
+// rollup, but all at once instead of one at a time, so the estimate can differ
+// slightly from rollup's. NULL digests are ignored, and if no digest has any
+// values the result is NULL.
+#[pg_extern(immutable, parallel_safe)]
+pub fn tdigest_merged_quantile(
+    digests: Array<TimescaleTDigest>,
+    quantile: f64,
+    _fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<f64> {
+    if !(0.0..=1.0).contains(&quantile) {
+        TDigestError::BadQuantile(quantile).raise()
+    }
+    let mut digests: Vec<TDigest> = digests.iter()
+        .flatten()
+        .filter(|digest| *digest.count > 0)
+        .map(|digest| digest.to_tdigest())
+        .collect();
+    // merge_digests merges at the size of the first digest
+    let largest = (0..digests.len()).max_by_key(|&i| digests[i].max_size())?;
+    digests.swap(0, largest);
+    TDigest::merge_digests(digests).estimate_quantile(quantile).into()
+}
+
+// Approximate the Kolmogorov–Smirnov statistic between two digests, i.e. the
+// largest difference between their CDFs. Instead of searching for the true
+// supremum, the CDFs are only compared at the values of `resolution + 1`
+// evenly spaced quantiles (0, 1/resolution, ..., 1) of each digest, so a
+// difference confined to a region narrower than that grid may be missed.
+// NULL if either digest is empty.
+#[pg_extern(immutable, parallel_safe)]
+pub fn tdigest_distance(
+    a: TimescaleTDigest,
+    b: TimescaleTDigest,
+    resolution: default!(i32, 100),
+    _fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<f64> {
+    if resolution < 1 {
+        TDigestError::BadArgument(format!("tdigest_distance resolution must be at least 1, got {}", resolution)).raise()
+    }
+    if *a.count == 0 || *b.count == 0 {
+        return None
+    }
+
+    let a = a.to_tdigest();
+    let b = b.to_tdigest();
+    let mut distance: f64 = 0.0;
+    for i in 0..=resolution {
+        let quantile = i as f64 / resolution as f64;
+        for value in &[a.estimate_quantile(quantile), b.estimate_quantile(quantile)] {
+            let difference = a.estimate_quantile_at_value(*value) - b.estimate_quantile_at_value(*value);
+            distance = distance.max(difference.abs());
+        }
+    }
+    Some(distance)
+}
+
+// Approximate the earth mover's (1st Wasserstein) distance between two
+// digests, i.e. the area between their CDFs, which is also the area between
+// their quantile functions: how far, on average, the values of one have to
+// move to match the other, in the units of the values. Unlike
+// tdigest_distance, which only tells how large the largest gap between the
+// CDFs is, this grows with how far apart they are, so e.g. a shift by 10 gives
+// 10. The integral is taken with the midpoint rule, comparing the estimates at
+// `resolution` evenly spaced quantiles (0.5/resolution, 1.5/resolution, ...).
+// NULL if either digest is empty.
+#[pg_extern(immutable, parallel_safe)]
+pub fn tdigest_emd(
+    a: TimescaleTDigest,
+    b: TimescaleTDigest,
+    resolution: default!(i32, 100),
+    _fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<f64> {
+    if resolution < 1 {
+        TDigestError::BadArgument(format!("tdigest_emd resolution must be at least 1, got {}", resolution)).raise()
+    }
+    if *a.count == 0 || *b.count == 0 {
+        return None
+    }
+
+    let (a, b) = (a.centroid_slices(), b.centroid_slices());
+    let area: f64 = (0..resolution)
+        .map(|i| {
+            let quantile = (i as f64 + 0.5) / resolution as f64;
+            (a.estimate_quantile(quantile) - b.estimate_quantile(quantile)).abs()
+        })
+        .sum();
+    Some(area / resolution as f64)
+}
+
+// The fraction of the digest's values falling into each of `bins` equal-width
+// bins between its min and max, as estimated from its CDF.
+fn histogram(digest: &TDigest, bins: usize) -> impl Iterator<Item = f64> + '_ {
+    let (min, max) = (digest.min(), digest.max());
+    let width = (max - min) / bins as f64;
+    let cdf = move |bin: usize| match bin {
+        0 => 0.0,
+        bin if bin == bins => 1.0,
+        bin => digest.estimate_quantile_at_value(min + bin as f64 * width),
+    };
+    (0..bins).map(move |bin| cdf(bin + 1) - cdf(bin))
+}
+
+// How far the digest's distribution is from the normal distribution with the
+// same mean and standard deviation, as the largest difference between their
+// CDFs at the quantiles 0.01, 0.02, ..., 0.99. Values near 0 suggest the data
+// is roughly normal. This is a heuristic for whether statistics that assume
+// normality are reasonable, not a formal test: the standard deviation is
+// estimated from the centroids, which hides the spread within each of them.
+// NULL for an empty digest, or one whose values are all the same.
+#[pg_extern(immutable, parallel_safe)]
+pub fn tdigest_normality(
+    digest: TimescaleTDigest,
+    _fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<f64> {
+    if *digest.count == 0 {
+        return None
+    }
+    let centroids: Vec<(f64, f64)> = digest.centroids_iter()
+        .map(|(mean, weight)| (mean, weight as f64))
+        .collect();
+    let count = *digest.count as f64;
+    let mean = centroids.iter().map(|(m, w)| m * w).sum::<f64>() / count;
+    let variance = centroids.iter().map(|(m, w)| w * (m - mean).powi(2)).sum::<f64>() / count;
+    let stddev = variance.sqrt();
+    if !(stddev > 0.0) {
+        return None
+    }
+
+    let slices = digest.centroid_slices();
+    let deviation = (1..100)
+        .map(|i| {
+            let quantile = i as f64 / 100.0;
+            let z = (slices.estimate_quantile(quantile) - mean) / stddev;
+            (quantile - normal_cdf(z)).abs()
+        })
+        .fold(0.0, f64::max);
+    Some(deviation)
+}
+
+// The standard normal CDF, using Abramowitz and Stegun's approximation 7.1.26
+// of erf, which is accurate to about 1e-7.
+fn normal_cdf(z: f64) -> f64 {
+    let x = z.abs() / std::f64::consts::SQRT_2;
+    let t = 1.0 / (1.0 + 0.3275911 * x);
+    let poly = t * (0.254829592 + t * (-0.284496736 + t * (1.421413741 + t * (-1.453152027 + t * 1.061405429))));
+    let erf = 1.0 - poly * (-x * x).exp();
+    if z >= 0.0 {
+        0.5 * (1.0 + erf)
+    } else {
+        0.5 * (1.0 - erf)
+    }
+}
+
+// Estimate the number of modes of the distribution, e.g. to flag bimodal
+// latencies. This is a heuristic: the digest's CDF is used to build a
+// histogram of MODALITY_BINS equal-width bins between the min and max, and a
+// local maximum of that histogram only counts as a mode if it falls by more
+// than a factor of `1 + threshold` on both sides. Lower thresholds find more
+// modes, but also start mistaking noise for them, and modes closer together
+// than a bin's width, e.g. because of distant outliers, can't be told apart.
+#[pg_extern(immutable, parallel_safe)]
+pub fn tdigest_modality(
+    digest: TimescaleTDigest,
+    threshold: default!(f64, 0.5),
+    _fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<i32> {
+    const MODALITY_BINS: usize = 20;
+
+    if !(threshold >= 0.0) {
+        TDigestError::BadArgument(format!("tdigest_modality threshold must be non-negative, got {}", threshold)).raise()
+    }
+    if *digest.count == 0 {
+        return None
+    }
+    let digest = digest.to_tdigest();
+    if !(digest.max() > digest.min()) {
+        return Some(1)
+    }
+    let bins = histogram(&digest, MODALITY_BINS);
+
+    // the density is zero outside the digest, so we start out looking for a
+    // peak, and reaching the end confirms the one we're in
+    let mut modes = 0;
+    let (mut low, mut high) = (0.0, 0.0);
+    let mut rising = true;
+    for density in bins {
+        if rising {
+            if density > high {
+                high = density
+            } else if density * (1.0 + threshold) < high {
+                modes += 1;
+                rising = false;
+                low = density;
+            }
+        } else if density < low {
+            low = density
+        } else if density > low * (1.0 + threshold) {
+            rising = true;
+            high = density;
+        }
+    }
+    if rising {
+        modes += 1
+    }
+    Some(modes)
+}
+
+// The quantiles at which the distribution jumps, where the means of
+// neighbouring centroids are more than min_jump apart, e.g. the step between
+// the two plateaus of a bimodal distribution, or below a saturated reading.
+// Each is the share of the values up to and including the lower of the two
+// centroids. A centroid straddling the step splits it into two close cliffs,
+// or hides it if neither half is more than min_jump. NULL for an empty
+// digest.
+#[pg_extern(immutable, parallel_safe)]
+pub fn tdigest_cliffs(
+    digest: TimescaleTDigest,
+    min_jump: f64,
+    _fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Vec<f64>> {
+    if !(min_jump >= 0.0) {
+        TDigestError::BadArgument(format!("tdigest_cliffs min_jump must be non-negative, got {}", min_jump)).raise()
+    }
+    if *digest.count == 0 {
+        return None
+    }
+    let count = *digest.count as f64;
+    let mut cliffs = vec![];
+    let mut below = 0;
+    let mut previous = None;
+    for (mean, weight) in digest.centroids_iter() {
+        if let Some(previous) = previous {
+            if mean - previous > min_jump {
+                cliffs.push(below as f64 / count);
+            }
+        }
+        below += weight;
+        previous = Some(mean);
+    }
+    Some(cliffs)
+}
+
+// The most common value in a digest built by t_digest_with_exact_mode, as long
+// as it saw few enough distinct values to track them. Otherwise this falls
+// back to an approximation, the center of the most populated of
+// APPROXIMATE_MODE_BINS equal-width bins between the min and max.
+#[pg_extern(immutable, parallel_safe)]
+pub fn tdigest_exact_mode(
+    digest: TimescaleTDigest,
+    _fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<f64> {
+    const APPROXIMATE_MODE_BINS: usize = 100;
+
+    if *digest.count == 0 {
+        return None
+    }
+    if let Some(mode) = digest.exact_mode() {
+        return Some(mode)
+    }
+
+    let digest = digest.to_tdigest();
+    if !(digest.max() > digest.min()) {
+        return Some(digest.min())
+    }
+    let mut fullest = (0, 0.0);
+    for (bin, fraction) in histogram(&digest, APPROXIMATE_MODE_BINS).enumerate() {
+        if fraction > fullest.1 {
+            fullest = (bin, fraction)
+        }
+    }
+    let width = (digest.max() - digest.min()) / APPROXIMATE_MODE_BINS as f64;
+    Some(digest.min() + (fullest.0 as f64 + 0.5) * width)
+}
+
+// Whether the digest uses more than `threshold` (0-1) of its centroids, for
+// maintenance jobs looking for digests worth shrinking with tdigest_resize. A
+// digest never has more centroids than its size, but after rolling up many
+// digests it is typically close to that.
+#[pg_extern(immutable, parallel_safe)]
+pub fn tdigest_needs_compaction(
+    digest: TimescaleTDigest,
+    threshold: f64,
+    _fcinfo: pg_sys::FunctionCallInfo,
+) -> bool {
+    if !(threshold >= 0.0 && threshold <= 1.0) {
+        TDigestError::BadArgument(format!("threshold must be between 0 and 1, got {}", threshold)).raise()
+    }
+    let centroids = digest.centroids_iter().count();
+    centroids as f64 > threshold * *digest.buckets as f64
+}
+
+// Shrink a digest to `size` buckets, e.g. to save space when archiving old
+// data, at the cost of accuracy. The count, sum, min and max are kept exactly.
+// A digest can't regain accuracy it has already lost, so resizing to a larger
+// size returns it unchanged.
+#[pg_extern(immutable, parallel_safe)]
+pub fn tdigest_resize(
+    digest: TimescaleTDigest,
+    size: int,
+    _fcinfo: pg_sys::FunctionCallInfo,
+) -> TimescaleTDigest<'static> {
+    if size == 0 {
+        TDigestError::SizeZero("tdigest_resize size").raise()
+    }
+
+    let original = digest.to_tdigest();
+    let compressed = original.compressed_to(size as usize);
+    let resized = TDigest::new(
+        compressed.raw_centroids().to_vec(),
+        original.sum(),
+        original.count(),
+        original.max(),
+        original.min(),
+        compressed.max_size(),
+    );
+    TimescaleTDigest::from_tdigest_with(&resized, digest.parsed_exact_sum().as_ref(), digest.exact_mode(), digest.ends(), digest.range())
+}
+
+// The centroids making up the digest, in order of increasing mean.
+#[pg_extern(immutable, parallel_safe)]
+pub fn tdigest_centroids(
+    digest: TimescaleTDigest,
+    _fcinfo: pg_sys::FunctionCallInfo,
+) -> impl std::iter::Iterator<Item = (name!(mean, f64), name!(weight, i64))> {
+    let centroids: Vec<_> = digest.centroids_iter()
+        .map(|(mean, weight)| (mean, weight as i64))
+        .collect();
+    centroids.into_iter()
+}
+
+// Build a digest from a set of centroids, such as those output by
+// tdigest_centroids. The true minimum and maximum are not known, so the
+// smallest and largest means stand in for them.
+#[pg_extern(immutable, parallel_safe)]
+pub fn tdigest_from_centroids(
+    means: Array<f64>,
+    weights: Array<i64>,
+    size: int,
+    _fcinfo: pg_sys::FunctionCallInfo,
+) -> TimescaleTDigest<'static> {
+    if size == 0 {
+        TDigestError::SizeZero("tdigest_from_centroids size").raise()
+    }
+    if means.len() != weights.len() {
+        TDigestError::BadArgument(format!("got {} centroid means but {} weights", means.len(), weights.len())).raise()
+    }
+
+    let mut cents: Vec<Centroid> = Vec::with_capacity(means.len());
+    for (mean, weight) in means.iter().zip(weights.iter()) {
+        let (mean, weight) = match (mean, weight) {
+            (Some(mean), Some(weight)) => (mean, weight),
+            _ => TDigestError::BadArgument("centroids cannot be NULL".to_string()).raise(),
+        };
+        cents.push(Centroid::new(mean, weight as f64));
+    }
+    // the centroids are a set, their order doesn't matter
+    cents.sort();
+    if let Err(problem) = check_centroids(&cents) {
+        TDigestError::BadArgument(format!("invalid centroids: {}", problem)).raise()
+    }
+
+    let count = cents.iter().map(|cent| cent.weight()).sum();
+    let sum = cents.iter().map(|cent| cent.mean() * cent.weight()).sum();
+    let (min, max) = match (cents.first(), cents.last()) {
+        (Some(first), Some(last)) => (first.mean(), last.mean()),
+        _ => (std::f64::NAN, std::f64::NAN),
+    };
+
+    TimescaleTDigest::from_tdigest(&TDigest::new(cents, sum, count, max, min, size as usize))
+}
+
+// Build a digest from a histogram, e.g. one exported by Prometheus, where
+// counts[i] values fell between edges[i] and edges[i + 1]. The counts are per
+// bucket, not cumulative. Every non-empty bucket becomes a centroid at its
+// midpoint, and the outer edges of the first and last non-empty buckets stand
+// in for the minimum and maximum, so estimates can't be any more precise than
+// the buckets were.
+#[pg_extern(immutable, parallel_safe)]
+pub fn tdigest_from_histogram(
+    edges: Array<f64>,
+    counts: Array<i64>,
+    size: int,
+    _fcinfo: pg_sys::FunctionCallInfo,
+) -> TimescaleTDigest<'static> {
+    if size == 0 {
+        TDigestError::SizeZero("tdigest_from_histogram size").raise()
+    }
+    if edges.len() != counts.len() + 1 {
+        TDigestError::BadArgument(format!("a histogram with {} bucket counts needs {} edges, got {}",
+            counts.len(), counts.len() + 1, edges.len())).raise()
+    }
+    let edges: Vec<f64> = edges.iter()
+        .map(|edge| edge.unwrap_or_else(|| TDigestError::BadArgument("histogram edges cannot be NULL".to_string()).raise()))
+        .collect();
+    for pair in edges.windows(2) {
+        if !(pair[0].is_finite() && pair[1].is_finite() && pair[0] < pair[1]) {
+            TDigestError::BadArgument(format!("histogram edges must be finite and increasing, got {} followed by {}", pair[0], pair[1])).raise()
+        }
+    }
+
+    let mut cents: Vec<Centroid> = Vec::with_capacity(counts.len());
+    let mut min = std::f64::NAN;
+    let mut max = std::f64::NAN;
+    for (i, count) in counts.iter().enumerate() {
+        let count = count.unwrap_or_else(|| TDigestError::BadArgument("histogram counts cannot be NULL".to_string()).raise());
+        if count < 0 {
+            TDigestError::BadArgument(format!("histogram counts cannot be negative, got {}", count)).raise()
+        }
+        if count == 0 {
+            continue
+        }
+        if cents.is_empty() {
+            min = edges[i];
+        }
+        max = edges[i + 1];
+        cents.push(Centroid::new((edges[i] + edges[i + 1]) / 2.0, count as f64));
+    }
+
+    let count = cents.iter().map(|cent| cent.weight()).sum();
+    let sum = cents.iter().map(|cent| cent.mean() * cent.weight()).sum();
+    TimescaleTDigest::from_tdigest(&TDigest::new(cents, sum, count, max, min, size as usize))
+}
+
+// Check whether a digest's internal invariants hold, e.g. to detect corruption
+// after a restore. With `raise_error` an invalid digest raises an error
+// describing the problem instead of returning false.
+#[pg_extern(immutable, parallel_safe)]
+pub fn tdigest_validate(
+    digest: TimescaleTDigest,
+    raise_error: default!(bool, false),
+    _fcinfo: pg_sys::FunctionCallInfo,
+) -> bool {
+    match digest.validate() {
+        Ok(()) => true,
+        Err(problem) if raise_error => TDigestError::CorruptData(problem).raise(),
+        Err(_) => false,
+    }
+}
+
+// A human-readable summary of how the digest is stored, for diagnosing
+// problems. The format is meant for people, not parsers, and may change.
+#[pg_extern(immutable, parallel_safe)]
+pub fn tdigest_debug(
+    digest: TimescaleTDigest,
+    _fcinfo: pg_sys::FunctionCallInfo,
+) -> String {
+    let centroids = digest.centroids_iter().count();
+    let bytes = match digest.1 {
+        Some(bytes) => bytes.len(),
+        None => digest.to_pg_bytes().len(),
+    };
+    let problem = match digest.validate() {
+        Ok(()) => "none".to_string(),
+        Err(problem) => problem,
+    };
+    format!(
+        "version: {}, bytes: {}, buckets: {}, count: {}, centroids: {} of {} slots, \
+        sum: {}, min: {}, max: {}, mode: {:?}, exact sum: {:?}, first and last: {:?}, range: {:?}, problem: {}",
+        digest.version, bytes, digest.buckets, digest.count, centroids, digest.weights.len(),
+        digest.sum, digest.0.min, digest.max, digest.exact_mode(), digest.parsed_exact_sum().map(|s| s.to_string()),
+        digest.ends(), digest.range(), problem,
+    )
+}
+
+// The shape of the distribution at a glance, e.g. in psql, as `width` block
+// characters, one for each of as many equal-width bins between the min and max,
+// from the lowest for the emptiest bins to the full block for the fullest.
+// Values that are all the same fill every bin. NULL for an empty digest.
+#[pg_extern(immutable, parallel_safe)]
+pub fn tdigest_sparkline(
+    digest: TimescaleTDigest,
+    width: i32,
+    _fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<String> {
+    const BLOCKS: [char; 8] = ['\u{2581}', '\u{2582}', '\u{2583}', '\u{2584}', '\u{2585}', '\u{2586}', '\u{2587}', '\u{2588}'];
+
+    if width < 1 {
+        TDigestError::BadArgument(format!("tdigest_sparkline width must be at least 1, got {}", width)).raise()
+    }
+    if *digest.count == 0 {
+        return None
+    }
+    let digest = digest.to_tdigest();
+    if !(digest.max() > digest.min()) {
+        return Some(BLOCKS[BLOCKS.len() - 1].to_string().repeat(width as usize))
+    }
+    let bins: Vec<f64> = histogram(&digest, width as usize).collect();
+    let fullest = bins.iter().cloned().fold(0.0, f64::max);
+    let top = (BLOCKS.len() - 1) as f64;
+    Some(bins.iter().map(|&bin| BLOCKS[(bin / fullest * top).round() as usize]).collect())
+}
+
+// The digest as JSON, e.g. for exporting it. `buckets` is the size it was
+// built with, and the centroids are in `means` and `weights`.
+#[pg_extern(immutable, parallel_safe)]
+pub fn tdigest_to_json(
+    digest: TimescaleTDigest,
+    _fcinfo: pg_sys::FunctionCallInfo,
+) -> Json {
+    let json = serde_json::to_value(&digest)
+        .unwrap_or_else(|e| TDigestError::Internal(format!("cannot convert TimescaleTDigest to JSON: {}", e)).raise());
+    Json(json)
+}
+
+// The centroids as CSV, a `mean,weight` header followed by one line per
+// centroid, for tools that would rather not parse JSON. Numbers are written
+// by Rust rather than by postgres, so they always use `.` as the decimal point.
+#[pg_extern(immutable, parallel_safe)]
+pub fn tdigest_to_csv(
+    digest: TimescaleTDigest,
+    _fcinfo: pg_sys::FunctionCallInfo,
+) -> String {
+    use std::fmt::Write;
+    let mut csv = String::from("mean,weight\n");
+    for (mean, weight) in digest.centroids_iter() {
+        let _ = writeln!(csv, "{},{}", mean, weight);
+    }
+    csv
+}
+
+// Rebuild a digest from the output of tdigest_to_json. Every field is required,
+// except first and last, which weren't exported by older versions.
+#[pg_extern(immutable, parallel_safe)]
+pub fn tdigest_from_json(
+    json: Json,
+    _fcinfo: pg_sys::FunctionCallInfo,
+) -> TimescaleTDigest<'static> {
+    if !json.0.is_object() {
+        TDigestError::CorruptData(format!("bad JSON, expected an object, got {}", json.0)).raise()
+    }
+    serde_json::from_value(json.0)
+        .unwrap_or_else(|e| TDigestError::CorruptData(format!("bad JSON, {}", e)).raise())
+}
+
+// The digest's binary layout as base64, e.g. for moving digests between
+// clusters with text COPY. This is much more compact than the JSON form. The
+// varlena length header is left out; the layout version comes first, so a
+// cluster running an older build rejects digests it can't read. The layout is
+// in the machine's byte order, so it can only be moved between clusters on
+// platforms with the same endianness.
+#[pg_extern(immutable, parallel_safe)]
+pub fn tdigest_to_base64(
+    digest: TimescaleTDigest,
+    _fcinfo: pg_sys::FunctionCallInfo,
+) -> String {
+    base64::encode(&digest.pg_bytes()[4..])
+}
+
+// Rebuild a digest from the output of tdigest_to_base64.
+#[pg_extern(immutable, parallel_safe)]
+pub fn tdigest_from_base64(
+    encoded: &str,
+    _fcinfo: pg_sys::FunctionCallInfo,
+) -> TimescaleTDigest<'static> {
+    let decoded = base64::decode(encoded)
+        .unwrap_or_else(|e| TDigestError::CorruptData(format!("bad base64, {}", e)).raise());
+    digest_from_bytes(&decoded).unwrap_or_else(|e| e.raise())
+}
+
+// Rebuild digests from their binary layouts, as decoded from tdigest_to_base64
+// by `decode(encoded, 'base64')`. This saves a function call per digest when
+// loading many at once. NULL elements stay NULL.
+#[pg_extern(immutable, parallel_safe)]
+pub fn tdigest_deserialize_array(
+    serialized: Array<&[u8]>,
+    _fcinfo: pg_sys::FunctionCallInfo,
+) -> Vec<Option<TimescaleTDigest<'static>>> {
+    serialized.iter()
+        .enumerate()
+        .map(|(i, bytes)| bytes.map(|bytes| digest_from_bytes(bytes).unwrap_or_else(|e| match e {
+            TDigestError::CorruptData(problem) => TDigestError::CorruptData(format!("element {}, {}", i + 1, problem)).raise(),
+            e => e.raise(),
+        })))
+        .collect()
+}
+
+// A digest from its binary layout without the varlena length header, checking
+// it the way any digest from outside input must be.
+fn digest_from_bytes(layout: &[u8]) -> Result<TimescaleTDigest<'static>, TDigestError> {
+    let len = layout.len() + 4;
+    if len > 0x3FFF_FFFF {
+        return Err(TDigestError::OutOfRange(format!("TimescaleTDigest too large: {} bytes", len)))
+    }
+    // the layout is copied once, behind the header, into a buffer that lives
+    // as long as the rest of the call's memory
+    let bytes = unsafe {
+        let bytes = pg_sys::palloc(len) as *mut u8;
+        set_varsize(bytes as *mut _, len as i32);
+        std::ptr::copy_nonoverlapping(layout.as_ptr(), bytes.add(4), layout.len());
+        slice::from_raw_parts(bytes, len)
+    };
+
+    // from_datum checks the version and that the layout parses; what parsing
+    // leaves over is anything past the layout's end
+    let digest = unsafe {
+        TimescaleTDigest::from_datum(bytes.as_ptr() as Datum, false, pg_sys::InvalidOid).unwrap()
+    };
+    let trailing = TimescaleTDigestData::try_ref(bytes).map_or(0, |(_, rest)| rest.len());
+    if trailing != 0 {
+        return Err(TDigestError::CorruptData(format!("{} trailing bytes", trailing)))
+    }
+    digest.validate().map_err(TDigestError::CorruptData)?;
+    Ok(digest)
+}
+
+// A fingerprint of the digest's binary layout: digests with the same bytes
+// get the same fingerprint, and different ones almost certainly don't. This
+// is FNV-1a, which unlike std's hashers is guaranteed to stay the same, so
+// fingerprints can be stored and compared later.
+#[pg_extern(immutable, parallel_safe)]
+pub fn tdigest_fingerprint(
+    digest: TimescaleTDigest,
+    _fcinfo: pg_sys::FunctionCallInfo,
+) -> i64 {
+    // skip the varlena header, which only holds the length
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for &byte in &digest.pg_bytes()[4..] {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    hash as i64
+}
+
+// Whether the digest still matches the checksum stored when it was written,
+// to detect e.g. silent corruption on disk. tdigest_validate only checks that
+// a digest is consistent, which a damaged one often still is. NULL for digests
+// stored before checksums were.
+#[pg_extern(immutable, parallel_safe)]
+pub fn tdigest_verify_checksum(
+    digest: TimescaleTDigest,
+    _fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<bool> {
+    let &stored = digest.checksum.get(0)?;
+    Some(stored == digest.compute_checksum())
+}
+
+// CRC-32 as used by zlib and gzip, bit by bit, as it only runs once per digest
+// written or checked.
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+// The size the digest was built with, i.e. the maximum number of centroids.
+#[pg_extern(immutable, parallel_safe)]
+pub fn tdigest_compression(
+    digest: TimescaleTDigest,
+    _fcinfo: pg_sys::FunctionCallInfo,
+) -> int {
+    *digest.buckets
+}
+
+// The bytes the digest takes in memory once read back as a TDigest, its
+// centroids plus the fixed part, for capacity planning. This is as opposed to
+// the size it's stored at, which pg_column_size reports and which may be
+// compressed.
+#[pg_extern(immutable, parallel_safe)]
+pub fn tdigest_memory_footprint(
+    digest: TimescaleTDigest,
+    _fcinfo: pg_sys::FunctionCallInfo,
+) -> i64 {
+    let centroids = min(*digest.buckets, *digest.count) as usize;
+    (std::mem::size_of::<TDigest>() + centroids * std::mem::size_of::<Centroid>()) as i64
+}
+
+// Whether the digest holds every value it was built from as a centroid of its
+// own, rather than an approximation. Having no more values than buckets
+// doesn't guarantee that: centroids around the median start to merge a little
+// before a digest fills up, e.g. from 87 values in 100 buckets. Equal values
+// sharing a centroid count as merged as well.
+#[pg_extern(immutable, parallel_safe)]
+pub fn tdigest_is_exact(
+    digest: TimescaleTDigest,
+    _fcinfo: pg_sys::FunctionCallInfo,
+) -> bool {
+    digest.is_exact()
+}
+
+// The value observed at the given quantile of an exact digest, see
+// tdigest_is_exact. Rather than interpolating, this returns one of the values
+// the digest was built from, the first whose cumulative share of the values
+// reaches the quantile, the same one percentile_disc would. Digests that
+// aren't exact are an error, since their centroids are no longer values that
+// were actually observed.
+#[pg_extern(immutable, parallel_safe)]
+pub fn tdigest_exact_quantile(
+    digest: TimescaleTDigest,
+    quantile: f64,
+    _fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<f64> {
+    if !(0.0..=1.0).contains(&quantile) {
+        TDigestError::BadQuantile(quantile).raise()
+    }
+    if !digest.is_exact() {
+        TDigestError::BadArgument(format!(
+            "tdigest_exact_quantile requires an exact digest, this one has merged some of its {} values",
+            digest.count)).raise()
+    }
+    if *digest.count == 0 {
+        return None
+    }
+
+    // computed the way percentile_disc does, so that the two round alike
+    let rank = (quantile * *digest.count as f64).ceil().max(1.0) as u64;
+    let mut below = 0;
+    for (mean, weight) in digest.centroids_iter() {
+        below += weight;
+        if below >= rank {
+            return Some(mean)
+        }
+    }
+    Some(*digest.max)
+}
+
+// Number of elements from which the digest was built.
+#[pg_extern(immutable, parallel_safe)]
+pub fn tdigest_count(
+    digest: TimescaleTDigest,
+    _fcinfo: pg_sys::FunctionCallInfo,
+) -> f64 {
+    *digest.count as f64
+}
+
+// Minimum value entered in the digest.
+#[pg_extern(immutable, parallel_safe)]
+pub fn tdigest_min(
+    digest: TimescaleTDigest,
+    _fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<f64> {
+    if *digest.count == 0 {
+        return None
+    }
+    Some(*digest.min)
+}
+
+// Maximum value entered in the digest.
+#[pg_extern(immutable, parallel_safe)]
+pub fn tdigest_max(
+    digest: TimescaleTDigest,
+    _fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<f64> {
+    if *digest.count == 0 {
+        return None
+    }
+    Some(*digest.max)
+}
+
+// The first and last values entered in the digest, in the order the aggregate
+// saw them, e.g. `t_digest(100, value ORDER BY time)`. When the aggregate runs
+// in parallel, every worker has its own first and last, and which worker's end
+// up in the result depends on the order their partial digests are combined in,
+// so these are only reliable for serial aggregates. NULL for digests stored
+// before they were tracked.
+#[pg_extern(immutable, parallel_safe)]
+pub fn tdigest_first(
+    digest: TimescaleTDigest,
+    _fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<f64> {
+    digest.ends().map(|(first, _)| first)
+}
+
+#[pg_extern(immutable, parallel_safe)]
+pub fn tdigest_last(
+    digest: TimescaleTDigest,
+    _fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<f64> {
+    digest.ends().map(|(_, last)| last)
+}
+
+// The earliest and latest timestamps of the values in a digest built by
+// t_digest_ranged, NULL for other digests. tdigest_range returns both as a
+// tstzrange.
+#[pg_extern(immutable, parallel_safe)]
+pub fn tdigest_range_lower(
+    digest: TimescaleTDigest,
+    _fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<TimestampWithTimeZone> {
+    digest.range().map(|(lower, _)| timestamp_from_micros(lower))
+}
+
+#[pg_extern(immutable, parallel_safe)]
+pub fn tdigest_range_upper(
+    digest: TimescaleTDigest,
+    _fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<TimestampWithTimeZone> {
+    digest.range().map(|(_, upper)| timestamp_from_micros(upper))
+}
+
+fn timestamp_from_micros(micros: i64) -> TimestampWithTimeZone {
+    unsafe {
+        TimestampWithTimeZone::from_datum(micros as Datum, false, pg_sys::TIMESTAMPTZOID)
+    }.unwrap()
+}
+
+// Average of all the values entered in the digest.
+// Note that this is not an approximation, though there may be loss of precision.
+#[pg_extern(immutable, parallel_safe)]
+pub fn tdigest_mean(
+    digest: TimescaleTDigest,
+    _fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<f64> {
+    if *digest.count == 0 {
+        return None
+    }
+    Some(*digest.sum / *digest.count as f64)
+}
+
+// How many standard deviations value lies above the mean, or below it if
+// negative, for e.g. scoring anomalies. The mean is exact, the standard
+// deviation is the sample one of the centroids, which misses the spread
+// within them. NULL when there's no spread to compare against, with fewer
+// than two values or all of them the same.
+#[pg_extern(immutable, parallel_safe)]
+pub fn tdigest_zscore(
+    digest: TimescaleTDigest,
+    value: f64,
+    _fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<f64> {
+    if *digest.count <= 1 {
+        return None
+    }
+    let count = *digest.count as f64;
+    let mean = *digest.sum / count;
+    let squares: f64 = digest.centroids_iter()
+        .map(|(centroid, weight)| weight as f64 * (centroid - mean).powi(2))
+        .sum();
+    let stddev = (squares / (count - 1.0)).sqrt();
+    if !(stddev > 0.0) {
+        return None
+    }
+    Some((value - mean) / stddev)
+}
+
+// Approximate the sum of the values between two quantiles, e.g. the revenue
+// from the top 10% of transactions is tdigest_partial_sum(digest, 0.9, 1.0).
+// Every centroid is taken as its weight times its mean, and those the bounds
+// fall inside contribute in proportion to how much of their weight is inside.
+#[pg_extern(immutable, parallel_safe)]
+pub fn tdigest_partial_sum(
+    digest: TimescaleTDigest,
+    low_q: f64,
+    high_q: f64,
+    _fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<f64> {
+    if !(0.0 <= low_q && low_q < high_q && high_q <= 1.0) {
+        TDigestError::BadArgument(format!("quantiles must satisfy 0 <= low_q < high_q <= 1, got {} and {}", low_q, high_q)).raise()
+    }
+    if *digest.count == 0 {
+        return None
+    }
+
+    let count = *digest.count as f64;
+    let (low, high) = (low_q * count, high_q * count);
+    let mut sum = 0.0;
+    let mut weight_before = 0.0;
+    for (&mean, &weight) in digest.means.iter().zip(digest.weights.iter()) {
+        if weight == 0 || weight_before >= high {
+            break
+        }
+        let weight = weight as f64;
+        let inside = high.min(weight_before + weight) - low.max(weight_before);
+        if inside > 0.0 {
+            sum += mean * inside;
+        }
+        weight_before += weight;
+    }
+    Some(sum)
+}
+
+// Approximate the Gini coefficient of the values, from 0 when they're all
+// equal to nearly 1 when a single one holds the entire total. The Lorenz curve
+// is integrated treating each centroid as that many copies of its mean, so
+// the inequality within centroids is lost and the result slightly
+// underestimates the true coefficient. The values must not be negative; when
+// they're all 0 they're taken to be perfectly equal.
+#[pg_extern(immutable, parallel_safe)]
+pub fn tdigest_gini(
+    digest: TimescaleTDigest,
+    _fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<f64> {
+    if *digest.count == 0 {
+        return None
+    }
+    if *digest.0.min < 0.0 {
+        TDigestError::BadArgument(format!("tdigest_gini requires non-negative values, got a minimum of {}", *digest.0.min)).raise()
+    }
+
+    let digest = digest.to_tdigest();
+    let centroids = digest.raw_centroids();
+    let total: f64 = centroids.iter().map(|c| c.mean() * c.weight()).sum();
+    if total == 0.0 {
+        return Some(0.0)
+    }
+
+    // twice the area under the Lorenz curve, by the trapezoid rule
+    let mut cumulative = 0.0;
+    let mut area = 0.0;
+    for centroid in centroids {
+        let before = cumulative / total;
+        cumulative += centroid.mean() * centroid.weight();
+        area += centroid.weight() / digest.count() * (before + cumulative / total);
+    }
+    Some(1.0 - area)
+}
+
+// Approximate the Shannon entropy, in nats, of the values binned into `nbins`
+// bins of equal width between the min and max. The probability of each bin is
+// the difference of the estimated CDF at its edges, so the result depends on
+// the binning as well as the values: it's at most ln(nbins), reached when the
+// values are spread evenly, and 0 when they all fall in a single bin.
+#[pg_extern(immutable, parallel_safe)]
+pub fn tdigest_entropy(
+    digest: TimescaleTDigest,
+    nbins: int,
+    _fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<f64> {
+    if nbins < 2 {
+        TDigestError::BadArgument(format!("nbins must be at least 2, got {}", nbins)).raise()
+    }
+    if *digest.count == 0 {
+        return None
+    }
+    let (min, max) = (*digest.0.min, *digest.max);
+    if !(max > min) {
+        return Some(0.0)
+    }
+
+    let digest = digest.to_tdigest();
+    let entropy: f64 = histogram(&digest, nbins as usize)
+        .filter(|&probability| probability > 0.0)
+        .map(|probability| -probability * probability.ln())
+        .sum();
+    Some(entropy)
+}
+
+// Approximate how many values fall into each of a series of logarithmically
+// spaced buckets, the way latencies are usually plotted. The bucket edges are
+// the powers of `base`, from the largest one at most the min up to the first
+// one above the max, and each bucket includes its lower edge but not its
+// upper one. Counts are estimated from the CDF, rounded so that they always
+// add up to the digest's count. The values must be positive.
+#[pg_extern(immutable, parallel_safe)]
+pub fn tdigest_log_histogram(
+    digest: TimescaleTDigest,
+    base: f64,
+    _fcinfo: pg_sys::FunctionCallInfo,
+) -> impl std::iter::Iterator<Item = (name!(bucket_low, f64), name!(bucket_high, f64), name!(count, i64))> {
+    if !(base > 1.0 && base.is_finite()) {
+        TDigestError::BadArgument(format!("base must be finite and greater than 1, got {}", base)).raise()
+    }
+    let mut buckets = vec![];
+    if *digest.count == 0 {
+        return buckets.into_iter()
+    }
+    let (min, max) = (*digest.0.min, *digest.max);
+    if !(min > 0.0) {
+        TDigestError::BadArgument(format!("tdigest_log_histogram requires positive values, got a minimum of {}", min)).raise()
+    }
+
+    // the logarithm can be off by a rounding error, e.g. at exact powers
+    let mut exponent = (min.ln() / base.ln()).floor() as i32;
+    while base.powi(exponent + 1) <= min {
+        exponent += 1;
+    }
+    while base.powi(exponent) > min {
+        exponent -= 1;
+    }
+
+    let count = *digest.count as i64;
+    let digest = digest.to_tdigest();
+    let below = |edge: f64| match edge {
+        edge if edge <= min => 0,
+        edge if edge > max => count,
+        edge => (count as f64 * digest.estimate_quantile_at_value(edge)).round() as i64,
+    };
+    loop {
+        let (low, high) = (base.powi(exponent), base.powi(exponent + 1));
+        buckets.push((low, high, below(high) - below(low)));
+        if high > max {
+            return buckets.into_iter()
+        }
+        exponent += 1;
+    }
+}
+
+// Sum of all the values entered in the digest. Values are added up with
+// compensated summation, so many small values aren't lost next to a few huge
+// ones, but the sum is stored as a single double and rounded whenever
+// buffered values or two digests are merged.
+#[pg_extern(immutable, parallel_safe)]
+pub fn tdigest_sum(
+    digest: TimescaleTDigest,
+    _fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<f64> {
+    if *digest.count == 0 {
+        return None
+    }
+    Some(*digest.sum)
+}
+
+// Exact sum of all the values entered in a digest built from numeric input,
+// NULL for digests built from double precision values.
+#[pg_extern(immutable, parallel_safe)]
+pub fn tdigest_numeric_sum(
+    digest: TimescaleTDigest,
+    _fcinfo: pg_sys::FunctionCallInfo,
+) -> Option<Numeric> {
+    if *digest.count == 0 || digest.exact_sum.is_empty() {
+        return None
+    }
+    let exact_sum = std::str::from_utf8(digest.exact_sum)
+        .unwrap_or_else(|_| TDigestError::CorruptData("exact sum is not valid UTF-8".to_string()).raise());
+    Some(Numeric(exact_sum.to_string()))
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+mod tests {
+    use pgx::*;
+
+    // Assert equality between two floats, within some fixed error range.
+    fn apx_eql(value: f64, expected: f64, error: f64) {
+        assert!((value - expected).abs() < error, "Float value {} differs from expected {} by more than {}", value, expected, error);
+    }
+
+    // Assert equality between two floats, within an error expressed as a fraction of the expected value.
+    fn pct_eql(value: f64, expected: f64, pct_error: f64) {
+        apx_eql(value, expected, pct_error * expected);
+    }
+
+    // Build a digest directly from its fields, without checking they make sense.
+    fn digest_from_parts(
+        buckets: u32,
+        count: u32,
+        sum: f64,
+        min: f64,
+        max: f64,
+        means: &[f64],
+        weights: &[u32],
+    ) -> super::TimescaleTDigest<'static> {
+        use super::TimescaleTDigestData;
+        unsafe {
+            crate::flatten!(
+                TimescaleTDigest {
+                    buckets: &buckets,
+                    count: &count,
+                    sum: &sum,
+                    min: &min,
+                    max: &max,
+                    mode: &[std::f64::NAN],
+                    first: &[std::f64::NAN],
+                    last: &[std::f64::NAN],
+                    range_lower: &[super::NO_RANGE.0],
+                    range_upper: &[super::NO_RANGE.1],
+                    means: means,
+                    weights: weights,
+                    exact_sum_len: &[0],
+                    exact_sum: &[],
+                    checksum: &[0],
+                }
+            )
+        }
+    }
+
+    #[pg_test]
+    fn test_aggregate() {
+        Spi::execute(|client| {
+            client.select("CREATE TABLE test (data DOUBLE PRECISION)", None, None);
+            client.select("INSERT INTO test SELECT generate_series(0.01, 100, 0.01)", None, None);
+
+            let sanity = client
+                .select("SELECT COUNT(*) FROM test", None, None)
+                .first()
+                .get_one::<i32>();
+            assert_eq!(10000, sanity.unwrap());
+
+            client.select("CREATE VIEW digest AS SELECT t_digest(100, data) FROM test", None, None);
+            let (min, max, count) = client
+                .select("SELECT tdigest_min(t_digest), tdigest_max(t_digest), tdigest_count(t_digest) FROM digest", None, None)
+                .first()
+                .get_three::<f64, f64, f64>();
+
+            apx_eql(min.unwrap(), 0.01, 0.000001);
+            apx_eql(max.unwrap(), 100.0, 0.000001);
+            apx_eql(count.unwrap(), 10000.0, 0.000001);
+
+            let (mean, sum) = client
+                .select("SELECT tdigest_mean(t_digest), tdigest_sum(t_digest) FROM digest", None, None)
+                .first()
+                .get_two::<f64, f64>();
+
+            apx_eql(mean.unwrap(), 50.005, 0.0001);
+            apx_eql(sum.unwrap(), 500050.0, 0.0001);
+
+            for i in 0..=100 {
+                let value = i as f64;
+                let quantile = value / 100.0;
+
+                let (est_val, est_quant) = client
+                    .select(&format!("SELECT tdigest_quantile(t_digest, {}), tdigest_quantile_at_value(t_digest, {}) FROM digest", quantile, value), None, None)
+                    .first()
+                    .get_two::<f64, f64>();
+
+                if i == 0 {
+                    pct_eql(est_val.unwrap(), 0.01, 1.0);
+                    apx_eql(est_quant.unwrap(), quantile, 0.0001);
+                } else {
+                    pct_eql(est_val.unwrap(), value, 1.0);
+                    pct_eql(est_quant.unwrap(), quantile, 1.0);
+                }
+            }
+        });
+    }
+
+    #[pg_test]
+    fn test_quantile_series() {
+        Spi::execute(|client| {
+            client.select("CREATE TABLE test (data DOUBLE PRECISION)", None, None);
+            client.select("INSERT INTO test SELECT generate_series(0.01, 100, 0.01)", None, None);
+            client.select("CREATE VIEW digest AS SELECT t_digest(100, data) FROM test", None, None);
+
+            let (rows, monotonic) = client
+                .select("SELECT count(*), bool_and(coalesce(value >= prev, true)) FROM (\
+                        SELECT value, lag(value) OVER (ORDER BY quantile) AS prev \
+                        FROM tdigest_quantile_series((SELECT t_digest FROM digest), 0.0, 1.0, 0.1)\
+                    ) series", None, None)
+                .first()
+                .get_two::<i64, bool>();
+
+            assert_eq!(rows.unwrap(), 11);
+            assert!(monotonic.unwrap());
+
+            let (first, last) = client
+                .select("SELECT min(value), max(value) FROM tdigest_quantile_series((SELECT t_digest FROM digest), 0.0, 1.0, 0.1)", None, None)
+                .first()
+                .get_two::<f64, f64>();
+
+            apx_eql(first.unwrap(), 0.01, 0.000001);
+            apx_eql(last.unwrap(), 100.0, 0.000001);
+        });
+    }
+
+    #[pg_test]
+    fn test_buffer_reuse() {
+        let mut state = super::TDigestTransState::new(100);
+        let buffer = state.buffer.as_ptr();
+
+        for i in 0..10_000 {
+            state.push(i as f64);
+            // the buffer should never grow past its initial allocation
+            assert_eq!(state.buffer.as_ptr(), buffer);
+            assert_eq!(state.buffer.capacity(), 100);
+        }
+
+        state.digest();
+        assert!(state.buffer.is_empty());
+        assert_eq!(state.buffer.as_ptr(), buffer);
+        apx_eql(state.digested.count(), 10000.0, 0.000001);
+        pct_eql(state.digested.estimate_quantile(0.5), 5000.0, 0.01);
+    }
+
+    #[pg_test]
+    fn test_distance() {
+        Spi::execute(|client| {
+            client.select("CREATE TABLE test (data DOUBLE PRECISION)", None, None);
+            client.select("INSERT INTO test SELECT generate_series(0.01, 100, 0.01)", None, None);
+
+            let (same, shifted) = client
+                .select("SELECT tdigest_distance(a, a), tdigest_distance(a, b) FROM \
+                        (SELECT t_digest(100, data) AS a, t_digest(100, data + 50) AS b FROM test) digests", None, None)
+                .first()
+                .get_two::<f64, f64>();
+
+            apx_eql(same.unwrap(), 0.0, 0.000001);
+            apx_eql(shifted.unwrap(), 0.5, 0.05);
+        });
+    }
+
+    #[pg_test]
+    fn test_centroid_array_round_trip() {
+        Spi::execute(|client| {
+            client.select("CREATE TABLE test (data DOUBLE PRECISION)", None, None);
+            client.select("INSERT INTO test SELECT generate_series(0.01, 100, 0.01)", None, None);
+            client.select("CREATE VIEW digests AS SELECT d, tdigest_from_centroids(tdigest_centroid_array(d), 100) AS r \
+                    FROM (SELECT t_digest(100, data) AS d FROM test) built", None, None);
+
+            let (centroids, array_len) = client
+                .select("SELECT (SELECT count(*) FROM tdigest_centroids(d)), array_length(tdigest_centroid_array(r), 1) FROM digests", None, None)
+                .first()
+                .get_two::<i64, i32>();
+            assert_eq!(centroids.unwrap(), array_len.unwrap() as i64);
+
+            let (count, round_trip_count) = client
+                .select("SELECT tdigest_count(d), tdigest_count(r) FROM digests", None, None)
+                .first()
+                .get_two::<f64, f64>();
+            apx_eql(count.unwrap(), 10000.0, 0.000001);
+            apx_eql(round_trip_count.unwrap(), 10000.0, 0.000001);
+
+            let (sum, round_trip_sum) = client
+                .select("SELECT tdigest_sum(d), tdigest_sum(r) FROM digests", None, None)
+                .first()
+                .get_two::<f64, f64>();
+            apx_eql(round_trip_sum.unwrap(), sum.unwrap(), 0.001);
+
+            for i in 1..100 {
+                let quantile = i as f64 / 100.0;
+                let (value, round_trip_value) = client
+                    .select(&format!("SELECT tdigest_quantile(d, {}), tdigest_quantile(r, {}) FROM digests", quantile, quantile), None, None)
+                    .first()
+                    .get_two::<f64, f64>();
+                apx_eql(round_trip_value.unwrap(), value.unwrap(), 0.000001);
+            }
+        });
+    }
+
+    #[pg_test]
+    fn test_serialize_round_trip() {
+        let mut state = super::TDigestTransState::new(10_000);
+        for i in 0..2_000_000i64 {
+            state.push(((i * 7919) % 2_000_000) as f64);
+        }
+        state.digest();
+        let digested = state.digested.clone();
+        let size = bincode::serialized_size(&state).unwrap() as usize;
+
+        let bytes = super::tdigest_serialize(state.into());
+        let len = unsafe { varsize_any(bytes as *const pg_sys::varlena) };
+        assert_eq!(len, size + 4);
+
+        let round_trip = super::tdigest_deserialize(bytes, None);
+        assert!(round_trip.buffer.is_empty());
+        assert_eq!(round_trip.digested, digested);
+
+        // buffered values must survive, even if they were never digested
+        let mut state = super::TDigestTransState::new(100);
+        for i in 0..50 {
+            state.push(i as f64);
+        }
+        let round_trip = super::tdigest_deserialize(super::tdigest_serialize(state.into()), None);
+        apx_eql(round_trip.digested.count(), 50.0, 0.000001);
+        apx_eql(round_trip.digested.sum(), 1225.0, 0.000001);
+
+        let empty = super::tdigest_serialize(super::TDigestTransState::new(100).into());
+        let round_trip = super::tdigest_deserialize(empty, None);
+        assert_eq!(round_trip.digested, super::TDigest::new_with_size(100));
+    }
+
+    #[pg_test]
+    fn test_percentile() {
+        Spi::execute(|client| {
+            client.select("CREATE TABLE test (data DOUBLE PRECISION)", None, None);
+            client.select("INSERT INTO test SELECT generate_series(0.01, 100, 0.01)", None, None);
+            client.select("CREATE VIEW digest AS SELECT t_digest(100, data) FROM test", None, None);
+
+            for &(percentile, quantile) in &[(0, 0.0), (50, 0.5), (95, 0.95), (100, 1.0)] {
+                let (by_percentile, by_quantile) = client
+                    .select(&format!("SELECT tdigest_percentile(t_digest, {}), tdigest_quantile(t_digest, {}) FROM digest", percentile, quantile), None, None)
+                    .first()
+                    .get_two::<f64, f64>();
+                assert_eq!(by_percentile.unwrap(), by_quantile.unwrap());
+            }
+        });
+    }
+
+    #[pg_test]
+    fn test_quantile_agg() {
+        Spi::execute(|client| {
+            client.select("CREATE TABLE test (data DOUBLE PRECISION)", None, None);
+            client.select("INSERT INTO test SELECT generate_series(0.01, 100, 0.01)", None, None);
+
+            for &quantile in &[0.0, 0.01, 0.5, 0.99, 1.0] {
+                let (single_pass, two_step) = client
+                    .select(&format!("SELECT quantile_agg({}, 100, data), tdigest_quantile(t_digest(100, data), {}) FROM test", quantile, quantile), None, None)
+                    .first()
+                    .get_two::<f64, f64>();
+                assert_eq!(single_pass.unwrap(), two_step.unwrap());
+            }
+
+            let empty = client
+                .select("SELECT quantile_agg(0.5, 100, data) FROM test WHERE data < 0", None, None)
+                .first()
+                .get_one::<f64>();
+            assert!(empty.is_none());
+        });
+    }
+
+    #[pg_test]
+    fn test_combine_with_empty_partial() {
+        let mut state = super::TDigestTransState::new(100);
+        for i in 0..1050 {
+            state.push(i as f64);
+        }
+        let empty = super::TDigestTransState::new(100);
+
+        let mut expected = state.clone();
+        expected.digest();
+
+        let combined = vec![
+            super::combine(Some(&state), Some(&empty)),
+            super::combine(Some(&empty), Some(&state)),
+            super::combine(Some(&state), None),
+            super::combine(None, Some(&state)),
+        ];
+        for combined in combined {
+            let mut combined = combined.unwrap();
+            combined.digest();
+            assert_eq!(combined.digested, expected.digested);
+        }
+
+        // both sides' buffered values must make it into the result
+        let combined = super::combine(Some(&state), Some(&state)).unwrap();
+        apx_eql(combined.digested.count(), 2100.0, 0.000001);
+        pct_eql(combined.digested.sum(), 2.0 * expected.digested.sum(), 0.000001);
+    }
+
+    #[pg_test]
+    fn test_central_range() {
+        Spi::execute(|client| {
+            client.select("CREATE TABLE test (data DOUBLE PRECISION)", None, None);
+            client.select("INSERT INTO test SELECT generate_series(0.01, 100, 0.01)", None, None);
+            client.select("CREATE VIEW digest AS SELECT t_digest(100, data) FROM test", None, None);
+
+            let (low, high) = client
+                .select("SELECT low, high FROM digest, tdigest_central_range(t_digest, 0.9)", None, None)
+                .first()
+                .get_two::<f64, f64>();
+            let (p5, p95) = client
+                .select("SELECT tdigest_quantile(t_digest, 0.05), tdigest_quantile(t_digest, 0.95) FROM digest", None, None)
+                .first()
+                .get_two::<f64, f64>();
+
+            apx_eql(low.unwrap(), p5.unwrap(), 0.000001);
+            apx_eql(high.unwrap(), p95.unwrap(), 0.000001);
+            pct_eql(low.unwrap(), 5.0, 0.01);
+            pct_eql(high.unwrap(), 95.0, 0.01);
+        });
+    }
+
+    #[pg_test]
+    fn test_count_within_iqr() {
+        Spi::execute(|client| {
+            client.select("CREATE TABLE test (data DOUBLE PRECISION)", None, None);
+            client.select("INSERT INTO test SELECT generate_series(1, 1000)", None, None);
+            client.select("INSERT INTO test SELECT 1000000 FROM generate_series(1, 10)", None, None);
+            client.select("CREATE VIEW digest AS SELECT t_digest(100, data) FROM test", None, None);
+
+            let (trimmed, all) = client
+                .select("SELECT tdigest_count_within_iqr(t_digest, 1.5), tdigest_count_within_iqr(t_digest, 1000000) \
+                    FROM digest", None, None)
+                .first()
+                .get_two::<f64, f64>();
+            assert!(trimmed.unwrap() < 1010.0);
+            pct_eql(trimmed.unwrap(), 1000.0, 0.01);
+            assert_eq!(all, Some(1010.0));
+        });
+    }
+
+    #[pg_test]
+    fn test_log_digest() {
+        Spi::execute(|client| {
+            // a Pareto distribution, with the values at evenly spaced
+            // quantiles in a fixed shuffled order
+            client.select("CREATE TABLE test (data DOUBLE PRECISION)", None, None);
+            client.select("INSERT INTO test SELECT 1 / (1 - ((i * 7919) % 100000 + 0.5) / 100000) \
+                FROM generate_series(0, 99999) i", None, None);
+            client.select("CREATE TABLE digests AS SELECT t_digest(100, data) AS linear, t_digest_log(100, data) AS log FROM test", None, None);
+
+            // the tail's values are far apart, too far for linear interpolation
+            for &(quantile, truth) in &[(0.99, 100.0), (0.999, 1000.0)] {
+                let (linear, log) = client
+                    .select(&format!("SELECT tdigest_quantile(linear, {0}), tdigest_log_quantile(log, {0}) FROM digests", quantile), None, None)
+                    .first()
+                    .get_two::<f64, f64>();
+                let linear_error = (linear.unwrap() - truth).abs() / truth;
+                let log_error = (log.unwrap() - truth).abs() / truth;
+                assert!(log_error < linear_error, "at {} log error {} linear error {}", quantile, log_error, linear_error);
+                assert!(log_error < 0.05, "at {} log error {}", quantile, log_error);
+            }
+
+            let (median, rank, below) = client
+                .select("SELECT tdigest_log_quantile(log, 0.5), tdigest_log_quantile_at_value(log, 2), \
+                        tdigest_log_quantile_at_value(log, 0) \
+                    FROM digests", None, None)
+                .first()
+                .get_three::<f64, f64, f64>();
+            pct_eql(median.unwrap(), 2.0, 0.01);
+            apx_eql(rank.unwrap(), 0.5, 0.01);
+            assert_eq!(below, Some(0.0));
+        });
+    }
+
+    #[pg_test(error = "TD004: t_digest_log values must be positive, got 0")]
+    fn test_log_digest_non_positive() {
+        Spi::execute(|client| {
+            client.select("SELECT t_digest_log(100, data) FROM generate_series(0, 10) data", None, None);
+        });
+    }
+
+    #[pg_test]
+    fn test_error_codes() {
+        use super::TDigestError::*;
+        let errors = vec![
+            NotAggregate,
+            BadQuantile(1.5),
+            SizeZero("tdigest_resize size"),
+            BadArgument("percentile must be between 0 and 100, got 101".to_string()),
+            OutOfRange("numeric sum out of range for t_digest".to_string()),
+            CorruptData("centroid 1 has zero weight".to_string()),
+            Internal("cannot output TimescaleTDigest".to_string()),
+        ];
+        let codes: std::collections::HashSet<_> = errors.iter().map(|e| e.code()).collect();
+        assert_eq!(codes.len(), errors.len());
+        for error in &errors {
+            assert!(error.code().starts_with("TD"));
+        }
+
+        assert_eq!(BadQuantile(1.5).to_string(), "quantile must be between 0 and 1, got 1.5");
+        assert_eq!(SizeZero("tdigest_resize size").to_string(), "tdigest_resize size must be at least 1");
+        assert_eq!(CorruptData("centroid 1 has zero weight".to_string()).to_string(),
+            "invalid TimescaleTDigest: centroid 1 has zero weight");
+    }
+
+    #[pg_test(error = "TD002: quantile must be between 0 and 1, got 1.5")]
+    fn test_error_bad_quantile() {
+        Spi::execute(|client| {
+            client.select("SELECT quantile_agg(1.5, 100, data) FROM generate_series(1, 10) data", None, None);
+        });
+    }
+
+    #[pg_test(error = "TD003: tdigest_resize size must be at least 1")]
+    fn test_error_size_zero() {
+        Spi::execute(|client| {
+            client.select("SELECT tdigest_resize(t_digest(100, data), 0) FROM generate_series(1, 10) data", None, None);
+        });
+    }
+
+    #[pg_test(error = "TD004: k must be finite and at least 0, got -1")]
+    fn test_count_within_iqr_negative_k() {
+        Spi::execute(|client| {
+            client.select("SELECT tdigest_count_within_iqr(t_digest(100, data), -1) FROM generate_series(1, 10) data", None, None);
+        });
+    }
+
+    #[pg_test]
+    fn test_validate() {
+        Spi::execute(|client| {
+            client.select("CREATE TABLE test (data DOUBLE PRECISION)", None, None);
+            client.select("INSERT INTO test SELECT generate_series(0.01, 100, 0.01)", None, None);
+
+            let valid = client
+                .select("SELECT tdigest_validate(t_digest(100, data), true) FROM test", None, None)
+                .first()
+                .get_one::<bool>();
+            assert!(valid.unwrap());
+        });
+
+        // unused slots at the end are fine
+        let valid = digest_from_parts(4, 6, 21.0, 1.0, 6.0, &[1.5, 3.5, 6.0, 0.0], &[2, 3, 1, 0]);
+        assert_eq!(valid.validate(), Ok(()));
+
+        let decreasing = digest_from_parts(4, 6, 21.0, 1.0, 6.0, &[3.5, 1.5, 6.0, 0.0], &[3, 2, 1, 0]);
+        assert!(decreasing.validate().is_err());
+
+        let hole = digest_from_parts(4, 6, 21.0, 1.0, 6.0, &[1.5, 0.0, 3.5, 6.0], &[2, 0, 3, 1]);
+        assert!(hole.validate().is_err());
+
+        let bad_count = digest_from_parts(4, 7, 21.0, 1.0, 6.0, &[1.5, 3.5, 6.0, 0.0], &[2, 3, 1, 0]);
+        assert!(bad_count.validate().is_err());
+
+        let bad_min = digest_from_parts(4, 6, 21.0, 2.0, 6.0, &[1.5, 3.5, 6.0, 0.0], &[2, 3, 1, 0]);
+        assert!(bad_min.validate().is_err());
+
+        let bad_max = digest_from_parts(4, 6, 21.0, 1.0, 5.0, &[1.5, 3.5, 6.0, 0.0], &[2, 3, 1, 0]);
+        assert!(bad_max.validate().is_err());
+
+        let nan_mean = digest_from_parts(4, 6, 21.0, 1.0, 6.0, &[1.5, std::f64::NAN, 6.0, 0.0], &[2, 3, 1, 0]);
+        assert!(nan_mean.validate().is_err());
+    }
+
+    #[pg_test]
+    fn test_accessors_are_immutable() {
+        Spi::execute(|client| {
+            let mutable = client
+                .select("SELECT count(*) FROM pg_proc \
+                        WHERE proname ~ '^tdigest_' \
+                        AND proname !~ '_(trans|combine|serialize|deserialize|final)$' \
+                        AND (provolatile <> 'i' OR proparallel <> 's')", None, None)
+                .first()
+                .get_one::<i64>();
+            assert_eq!(mutable.unwrap(), 0);
+
+            client.select("CREATE TABLE test (bucket INT, data DOUBLE PRECISION)", None, None);
+            client.select("INSERT INTO test SELECT i % 10, i FROM generate_series(1, 10000) i", None, None);
+            client.select("CREATE TABLE digests AS SELECT bucket, t_digest(100, data) AS d FROM test GROUP BY bucket", None, None);
+            client.select("CREATE INDEX digests_median ON digests (tdigest_quantile(d, 0.5))", None, None);
+
+            let count = client
+                .select("SELECT count(*) FROM digests WHERE tdigest_quantile(d, 0.5) > 0", None, None)
+                .first()
+                .get_one::<i64>();
+            assert_eq!(count.unwrap(), 10);
+        });
+    }
+
+    #[pg_test]
+    fn test_distinct() {
+        Spi::execute(|client| {
+            client.select("CREATE TABLE test (data DOUBLE PRECISION)", None, None);
+            client.select("INSERT INTO test SELECT i % 100 FROM generate_series(1, 10000) i", None, None);
+            client.select("CREATE VIEW digests AS SELECT \
+                    t_digest(100, data) AS total, \
+                    t_digest_distinct(100, data) AS distinct_values, \
+                    t_digest(DISTINCT 100, data) AS sql_distinct \
+                FROM test", None, None);
+
+            let (total, distinct) = client
+                .select("SELECT tdigest_count(total), tdigest_count(distinct_values) FROM digests", None, None)
+                .first()
+                .get_two::<f64, f64>();
+            apx_eql(total.unwrap(), 10000.0, 0.000001);
+            apx_eql(distinct.unwrap(), 100.0, 0.000001);
+
+            let (distinct, sql_distinct) = client
+                .select("SELECT tdigest_sum(distinct_values), tdigest_sum(sql_distinct) FROM digests", None, None)
+                .first()
+                .get_two::<f64, f64>();
+            apx_eql(distinct.unwrap(), 4950.0, 0.000001);
+            apx_eql(sql_distinct.unwrap(), 4950.0, 0.000001);
+
+            let median = client
+                .select("SELECT tdigest_quantile(distinct_values, 0.5) FROM digests", None, None)
+                .first()
+                .get_one::<f64>();
+            apx_eql(median.unwrap(), 49.5, 1.0);
+        });
+    }
+
+    #[pg_test]
+    fn test_empty_digest_accessors() {
+        Spi::execute(|client| {
+            client.select("CREATE VIEW digest AS \
+                SELECT tdigest_from_centroids('{}'::DOUBLE PRECISION[], '{}'::BIGINT[], 100) AS t_digest", None, None);
+
+            let count = client
+                .select("SELECT tdigest_count(t_digest) FROM digest", None, None)
+                .first()
+                .get_one::<f64>();
+            apx_eql(count.unwrap(), 0.0, 0.000001);
+
+            let all_null = client
+                .select("SELECT tdigest_quantile(t_digest, 0.5) IS NULL \
+                    AND tdigest_percentile(t_digest, 50) IS NULL \
+                    AND tdigest_quantile_at_value(t_digest, 1.0) IS NULL \
+                    AND tdigest_min(t_digest) IS NULL \
+                    AND tdigest_max(t_digest) IS NULL \
+                    AND tdigest_mean(t_digest) IS NULL \
+                    AND tdigest_sum(t_digest) IS NULL \
+                    FROM digest", None, None)
+                .first()
+                .get_one::<bool>();
+            assert!(all_null.unwrap());
+        });
+    }
+
+    #[pg_test]
+    fn test_max_centroids_during_merge() {
+        Spi::execute(|client| {
+            client.select("SET timescale_analytics.tdigest_max_centroids = 100", None, None);
+        });
+
+        let mut rollup: Option<super::TDigestTransState> = None;
+        for i in 0..200 {
+            let mut state = super::TDigestTransState::new(1000);
+            for j in 0..1000 {
+                state.push((i * 1000 + j) as f64);
+            }
+            rollup = super::combine(rollup.as_ref(), Some(&state));
+
+            let digested = &rollup.as_ref().unwrap().digested;
+            if i > 0 {
+                assert!(digested.raw_centroids().len() <= 100);
+            }
+        }
+
+        let mut rollup = rollup.unwrap();
+        rollup.digest();
+        assert!(rollup.digested.raw_centroids().len() <= 100);
+        apx_eql(rollup.digested.count(), 200000.0, 0.000001);
+        apx_eql(rollup.digested.min(), 0.0, 0.000001);
+        apx_eql(rollup.digested.max(), 199999.0, 0.000001);
+        pct_eql(rollup.digested.estimate_quantile(0.5), 100000.0, 0.01);
+
+        Spi::execute(|client| {
+            client.select("RESET timescale_analytics.tdigest_max_centroids", None, None);
+        });
+    }
+
+    #[pg_test]
+    fn test_weighted_quantile() {
+        Spi::execute(|client| {
+            client.select("CREATE TABLE test (region TEXT, data DOUBLE PRECISION)", None, None);
+            client.select("INSERT INTO test SELECT 'a', generate_series(0.01, 100, 0.01)", None, None);
+            client.select("INSERT INTO test SELECT 'b', generate_series(0.01, 100, 0.01)", None, None);
+            client.select("INSERT INTO test SELECT 'c', generate_series(100.01, 200, 0.01)", None, None);
+            client.select("CREATE VIEW digests AS \
+                SELECT region, t_digest(100, data) FROM test GROUP BY region", None, None);
+
+            // weighting equal digests differently doesn't change their distribution
+            let (weighted, single) = client
+                .select("SELECT \
+                        tdigest_weighted_quantile(ARRAY[a.t_digest, b.t_digest], ARRAY[3.0, 1.0], 0.9), \
+                        tdigest_quantile(a.t_digest, 0.9) \
+                    FROM digests a, digests b WHERE a.region = 'a' AND b.region = 'b'", None, None)
+                .first()
+                .get_two::<f64, f64>();
+            pct_eql(weighted.unwrap(), single.unwrap(), 0.01);
+
+            // three quarters of the weight is on a, so the median falls within it
+            let median = client
+                .select("SELECT tdigest_weighted_quantile(ARRAY[a.t_digest, c.t_digest], ARRAY[3.0, 1.0], 0.5) \
+                    FROM digests a, digests c WHERE a.region = 'a' AND c.region = 'c'", None, None)
+                .first()
+                .get_one::<f64>();
+            pct_eql(median.unwrap(), 66.67, 0.02);
+        });
+    }
+
+    #[pg_test]
+    fn test_max_buffer() {
+        Spi::execute(|client| {
+            client.select("SET timescale_analytics.tdigest_max_buffer = 1000", None, None);
+        });
+
+        let mut state = super::TDigestTransState::new(10000);
+        for i in 0..1_000_000 {
+            state.push(i as f64);
+            assert!(state.buffer.capacity() <= 1000);
+        }
+        state.digest();
+        assert!(state.digested.raw_centroids().len() <= 10000);
+        apx_eql(state.digested.count(), 1_000_000.0, 0.000001);
+        pct_eql(state.digested.estimate_quantile(0.5), 500_000.0, 0.01);
+
+        Spi::execute(|client| {
+            client.select("RESET timescale_analytics.tdigest_max_buffer", None, None);
+        });
+    }
+
+    #[pg_test]
+    fn test_max_total_buffer() {
+        Spi::execute(|client| {
+            client.select("SET timescale_analytics.tdigest_max_total_buffer = 10000", None, None);
+        });
+
+        // 100 values each for 2000 groups, which without the budget would
+        // each allocate a buffer of 1000 up-front
+        let mut states: Vec<_> = (0..2000).map(|_| super::TDigestTransState::new(1000)).collect();
+        for i in 0..100 {
+            for (group, state) in states.iter_mut().enumerate() {
+                state.push((i * 2000 + group) as f64);
+            }
+            let total: usize = states.iter().map(|state| state.buffer.capacity()).sum();
+            assert!(total <= 10000, "{} buffer entries allocated", total);
+        }
+        for (group, state) in states.iter_mut().enumerate() {
+            state.digest();
+            assert_eq!(state.digested.count(), 100.0);
+            assert_eq!(state.digested.min(), group as f64);
+        }
+
+        Spi::execute(|client| {
+            let (counts, median) = client
+                .select("SELECT bool_and(tdigest_count(d) = 100), percentile_cont(0.5) WITHIN GROUP (ORDER BY tdigest_quantile(d, 0.5)) \
+                    FROM (SELECT i % 2000 AS g, t_digest(1000, i) AS d FROM generate_series(0, 199999) i GROUP BY g) groups", None, None)
+                .first()
+                .get_two::<bool, f64>();
+            assert_eq!(counts, Some(true));
+            pct_eql(median.unwrap(), 100000.0, 0.01);
+
+            client.select("RESET timescale_analytics.tdigest_max_total_buffer", None, None);
+        });
+    }
+
+    #[pg_test]
+    fn test_combine_into_empty_fast_path() {
+        fn final_bytes(state: &super::TDigestTransState) -> Vec<u8> {
+            let mut state = state.clone();
+            state.digest();
+            super::TimescaleTDigest::from_tdigest(&state.digested).to_pg_bytes().to_vec()
+        }
+
+        let mut state = super::TDigestTransState::new(100);
+        for i in 0..1050 {
+            state.push(i as f64);
+        }
+        let empty = super::TDigestTransState::new(100);
+        let expected = final_bytes(&state);
+
+        let combined = super::combine_into(Some(state.clone().into()), Some(&empty)).unwrap();
+        assert_eq!(final_bytes(&combined), expected);
+
+        let combined = super::combine_into(Some(state.clone().into()), None).unwrap();
+        assert_eq!(final_bytes(&combined), expected);
+
+        let combined = super::combine_into(Some(empty.clone().into()), Some(&state)).unwrap();
+        assert_eq!(final_bytes(&combined), expected);
+
+        let combined = super::combine_into(None, Some(&state)).unwrap();
+        assert_eq!(final_bytes(&combined), expected);
+    }
+
+    #[pg_test]
+    fn test_quantile_ci() {
+        Spi::execute(|client| {
+            // dense below 1, sparse from 1000 to 2000
+            client.select("CREATE TABLE test (data DOUBLE PRECISION)", None, None);
+            client.select("INSERT INTO test SELECT i / 10000.0 FROM generate_series(0, 9999) i", None, None);
+            client.select("INSERT INTO test SELECT 1000 + 10 * i FROM generate_series(0, 99) i", None, None);
+            client.select("CREATE VIEW digest AS SELECT t_digest(100, data) FROM test", None, None);
+
+            let (value, lo, hi) = client
+                .select("SELECT value, lo, hi FROM digest, tdigest_quantile_ci(t_digest, 0.5)", None, None)
+                .first()
+                .get_three::<f64, f64, f64>();
+            let (value, lo, hi) = (value.unwrap(), lo.unwrap(), hi.unwrap());
+            assert!(lo <= value && value <= hi);
+            assert!(hi - lo < 0.1, "dense range {} to {}", lo, hi);
+
+            let (value, lo, hi) = client
+                .select("SELECT value, lo, hi FROM digest, tdigest_quantile_ci(t_digest, 0.995)", None, None)
+                .first()
+                .get_three::<f64, f64, f64>();
+            let (value, lo, hi) = (value.unwrap(), lo.unwrap(), hi.unwrap());
+            assert!(lo <= value && value <= hi);
+            assert!(hi - lo > 10.0, "sparse range {} to {}", lo, hi);
+
+            let (value, lo, hi) = client
+                .select("SELECT value, lo, hi FROM digest, tdigest_quantile_ci(t_digest, 1.0)", None, None)
+                .first()
+                .get_three::<f64, f64, f64>();
+            apx_eql(value.unwrap(), 1990.0, 0.000001);
+            assert!(lo.unwrap() <= 1990.0);
+            apx_eql(hi.unwrap(), 1990.0, 0.000001);
+
+            let rows = client
+                .select("SELECT count(*) FROM tdigest_quantile_ci((SELECT t_digest(100, data) FROM test WHERE false), 0.5)", None, None)
+                .first()
+                .get_one::<i64>();
+            assert_eq!(rows, Some(0));
+        });
+    }
+
+    #[pg_test]
+    fn test_continue_from_seed() {
+        Spi::execute(|client| {
+            client.select("CREATE TABLE test (data DOUBLE PRECISION, day INT)", None, None);
+            client.select("INSERT INTO test SELECT i, i % 2 FROM generate_series(1, 10000) i", None, None);
+            client.select("INSERT INTO test VALUES (NULL, 1)", None, None);
+            client.select("CREATE TABLE seed AS SELECT t_digest(100, data) FROM test WHERE day = 0", None, None);
+            client.select("CREATE VIEW digests AS SELECT \
+                    (SELECT t_digest_continue(seed.t_digest, data) FROM seed, test WHERE day = 1) AS continued, \
+                    (SELECT t_digest(100, data) FROM test) AS full", None, None);
+
+            let (count, min, max) = client
+                .select("SELECT tdigest_count(continued), tdigest_min(continued), tdigest_max(continued) FROM digests", None, None)
+                .first()
+                .get_three::<f64, f64, f64>();
+            apx_eql(count.unwrap(), 10000.0, 0.000001);
+            apx_eql(min.unwrap(), 1.0, 0.000001);
+            apx_eql(max.unwrap(), 10000.0, 0.000001);
+
+            let (size, matches) = client
+                .select("SELECT tdigest_compression(continued), \
+                        tdigest_distance(continued, full) < 0.01 FROM digests", None, None)
+                .first()
+                .get_two::<i32, bool>();
+            assert_eq!(size, Some(100));
+            assert!(matches.unwrap());
+
+            for i in 1..100 {
+                let quantile = i as f64 / 100.0;
+                let (continued, full) = client
+                    .select(&format!("SELECT tdigest_quantile(continued, {}), tdigest_quantile(full, {}) FROM digests",
+                            quantile, quantile), None, None)
+                    .first()
+                    .get_two::<f64, f64>();
+                pct_eql(continued.unwrap(), full.unwrap(), 0.01);
+            }
+        });
+    }
+
+    #[pg_test]
+    fn test_parallel_worker_counts() {
+        Spi::execute(|client| {
+            client.select("CREATE TABLE small (data DOUBLE PRECISION)", None, None);
+            client.select("INSERT INTO small SELECT generate_series(1, 50)", None, None);
+            client.select("CREATE TABLE large (data DOUBLE PRECISION)", None, None);
+            client.select("INSERT INTO large SELECT (i * 7919) % 100000 FROM generate_series(1, 100000) i", None, None);
+            client.select("SET parallel_setup_cost = 0", None, None);
+            client.select("SET parallel_tuple_cost = 0", None, None);
+            client.select("SET min_parallel_table_scan_size = 0", None, None);
+
+            let mut results = vec![];
+            for workers in &[0, 1, 4] {
+                client.select(&format!("SET max_parallel_workers_per_gather = {}", workers), None, None);
+                let (small, median) = client
+                    .select("SELECT (SELECT t_digest(100, data)::TEXT FROM small), \
+                            (SELECT tdigest_quantile(t_digest(100, data), 0.5) FROM large)", None, None)
+                    .first()
+                    .get_two::<String, f64>();
+                results.push((small.unwrap(), median.unwrap()));
+            }
+
+            // a digest with room for every value is the same however it was
+            // split up, a compressed one only approximately so
+            for (small, median) in &results[1..] {
+                assert_eq!(small, &results[0].0);
+                pct_eql(*median, results[0].1, 0.01);
+            }
+
+            client.select("RESET max_parallel_workers_per_gather", None, None);
+        });
+    }
+
+    // Random inputs of several shapes, each digested per group both serially
+    // and by parallel workers, whose partial states have to go through the
+    // serialize, deserialize and combine functions. The seeds keep the data,
+    // though not necessarily how it's split between workers, reproducible.
+    #[pg_test]
+    fn test_parallel_combine_fuzz() {
+        Spi::execute(|client| {
+            client.select("CREATE TABLE fuzz (grp INT, data DOUBLE PRECISION)", None, None);
+            client.select("SET parallel_setup_cost = 0", None, None);
+            client.select("SET parallel_tuple_cost = 0", None, None);
+            client.select("SET min_parallel_table_scan_size = 0", None, None);
+
+            for seed in 1..=5 {
+                client.select("TRUNCATE fuzz", None, None);
+                client.select(&format!("SELECT setseed({})", seed as f64 / 10.0), None, None);
+                client.select("INSERT INTO fuzz SELECT grp, CASE grp \
+                        WHEN 0 THEN random() * 1000 \
+                        WHEN 1 THEN -ln(1 - random()) \
+                        WHEN 2 THEN floor(random() * 5) \
+                        ELSE CASE WHEN random() < 0.5 THEN random() ELSE 100 + random() END \
+                    END \
+                    FROM (SELECT floor(random() * 4)::INT AS grp FROM generate_series(1, 20000)) groups", None, None);
+
+                client.select("SET max_parallel_workers_per_gather = 0", None, None);
+                client.select("CREATE TABLE serial_digests AS SELECT grp, t_digest(100, data) AS d FROM fuzz GROUP BY grp", None, None);
+                client.select("SET max_parallel_workers_per_gather = 4", None, None);
+                client.select("CREATE TABLE parallel_digests AS SELECT grp, t_digest(100, data) AS d FROM fuzz GROUP BY grp", None, None);
+
+                let (groups, same_summary) = client
+                    .select("SELECT count(*), bool_and(tdigest_count(s.d) = tdigest_count(p.d) \
+                            AND tdigest_min(s.d) = tdigest_min(p.d) \
+                            AND tdigest_max(s.d) = tdigest_max(p.d) \
+                            AND abs(tdigest_sum(s.d) - tdigest_sum(p.d)) <= 1e-9 * abs(tdigest_sum(s.d))) \
+                        FROM serial_digests s JOIN parallel_digests p USING (grp)", None, None)
+                    .first()
+                    .get_two::<i64, bool>();
+                assert_eq!(groups, Some(4), "seed {}", seed);
+                assert_eq!(same_summary, Some(true), "seed {}", seed);
+
+                // both estimates must be within the digest's error bound of
+                // the true quantile, measured by rank so that ties work out
+                let misses = client
+                    .select("SELECT count(*) FROM serial_digests s JOIN parallel_digests p USING (grp), \
+                            unnest(ARRAY[0.01, 0.1, 0.25, 0.5, 0.75, 0.9, 0.99]) q, \
+                            unnest(ARRAY[tdigest_quantile(s.d, q), tdigest_quantile(p.d, q)]) estimate, \
+                            LATERAL (SELECT \
+                                avg((data < estimate)::INT) AS below, \
+                                avg((data <= estimate)::INT) AS at_or_below \
+                                FROM fuzz f WHERE f.grp = s.grp) ranks \
+                        WHERE below > q + 0.02 OR at_or_below < q - 0.02", None, None)
+                    .first()
+                    .get_one::<i64>();
+                assert_eq!(misses, Some(0), "seed {}", seed);
+
+                client.select("DROP TABLE serial_digests, parallel_digests", None, None);
+            }
+
+            client.select("RESET max_parallel_workers_per_gather", None, None);
+        });
+    }
+
+    #[pg_test]
+    fn test_to_csv() {
+        Spi::execute(|client| {
+            client.select("CREATE TABLE test (data DOUBLE PRECISION)", None, None);
+            client.select("INSERT INTO test SELECT i / 7.0 FROM generate_series(1, 10000) i", None, None);
+            client.select("CREATE VIEW digest AS SELECT t_digest(100, data) FROM test", None, None);
+
+            let (lines, centroids) = client
+                .select("SELECT array_length(string_to_array(rtrim(tdigest_to_csv(t_digest), E'\\n'), E'\\n'), 1), \
+                        (SELECT count(*) FROM tdigest_centroids(t_digest)) \
+                    FROM digest", None, None)
+                .first()
+                .get_two::<i32, i64>();
+            assert_eq!(lines.unwrap() as i64, centroids.unwrap() + 1);
+
+            let csv = client
+                .select("SELECT tdigest_to_csv(t_digest) FROM digest", None, None)
+                .first()
+                .get_one::<String>()
+                .unwrap();
+            let mut lines = csv.lines();
+            assert_eq!(lines.next(), Some("mean,weight"));
+            let mut first = lines.next().unwrap().splitn(2, ',');
+            let mean: f64 = first.next().unwrap().parse().unwrap();
+            let weight: u32 = first.next().unwrap().parse().unwrap();
+            assert!(mean > 0.0 && mean < 1.0, "first mean {}", mean);
+            assert!(weight > 0);
+        });
+    }
+
+    #[pg_test]
+    fn test_sum_of_different_magnitudes() {
+        Spi::execute(|client| {
+            client.select("CREATE TABLE test (data DOUBLE PRECISION)", None, None);
+            client.select("INSERT INTO test VALUES (1e16 + 2), (-1e16)", None, None);
+            client.select("INSERT INTO test SELECT 1 FROM generate_series(1, 10000)", None, None);
+            client.select("INSERT INTO test SELECT i / 1024.0 FROM generate_series(1, 10000) i", None, None);
+
+            let (sum, mean, exact) = client
+                .select("SELECT tdigest_sum(t_digest(100, data)), tdigest_mean(t_digest(100, data)), \
+                        sum(data::NUMERIC)::DOUBLE PRECISION \
+                    FROM test", None, None)
+                .first()
+                .get_three::<f64, f64, f64>();
+            let exact = exact.unwrap();
+            apx_eql(sum.unwrap(), exact, exact * 1e-12);
+            pct_eql(mean.unwrap(), exact / 20002.0, 1e-12);
+        });
+    }
+
+    #[pg_test]
+    fn test_value_at_count() {
+        Spi::execute(|client| {
+            client.select("CREATE TABLE test (data DOUBLE PRECISION)", None, None);
+            client.select("INSERT INTO test SELECT generate_series(1, 10000)", None, None);
+            client.select("CREATE VIEW digest AS SELECT t_digest(100, data) FROM test", None, None);
+
+            let (half, median) = client
+                .select("SELECT tdigest_value_at_count(t_digest, tdigest_count(t_digest)::BIGINT / 2), \
+                        tdigest_quantile(t_digest, 0.5) \
+                    FROM digest", None, None)
+                .first()
+                .get_two::<f64, f64>();
+            apx_eql(half.unwrap(), median.unwrap(), 0.000001);
+            pct_eql(half.unwrap(), 5000.0, 0.01);
+
+            let (all, max) = client
+                .select("SELECT tdigest_value_at_count(t_digest, 10000), tdigest_max(t_digest) FROM digest", None, None)
+                .first()
+                .get_two::<f64, f64>();
+            apx_eql(all.unwrap(), max.unwrap(), 0.000001);
+        });
+    }
+
+    #[pg_test(error = "TD004: n must be between 0 and the digest's count of 10, got 11")]
+    fn test_value_at_count_past_count() {
+        Spi::execute(|client| {
+            client.select("SELECT tdigest_value_at_count(t_digest(10, data::DOUBLE PRECISION), 11) FROM generate_series(1, 10) data", None, None);
+        });
+    }
+
+    #[pg_test]
+    fn test_first_and_last() {
+        Spi::execute(|client| {
+            client.select("CREATE TABLE test (data DOUBLE PRECISION, ts INT)", None, None);
+            client.select("INSERT INTO test SELECT (i * 7919) % 10000, i FROM generate_series(1, 10000) i", None, None);
+            client.select("INSERT INTO test VALUES (NULL, 10001)", None, None);
+            client.select("CREATE VIEW digest AS SELECT t_digest(100, data ORDER BY ts) FROM test", None, None);
+
+            let (first, last) = client
+                .select("SELECT tdigest_first(t_digest), tdigest_last(t_digest) FROM digest", None, None)
+                .first()
+                .get_two::<f64, f64>();
+            assert_eq!(first, Some(7919.0));
+            assert_eq!(last, Some(0.0));
+
+            let (first, last) = client
+                .select("SELECT tdigest_first(tdigest_from_json(tdigest_to_json(t_digest))), \
+                        tdigest_last(t_digest::TEXT::TimescaleTDigest) FROM digest", None, None)
+                .first()
+                .get_two::<f64, f64>();
+            assert_eq!(first, Some(7919.0));
+            assert_eq!(last, Some(0.0));
+
+            // rolled up in order, the first comes from the earliest digest and
+            // the last from the latest
+            let (first, last) = client
+                .select("SELECT tdigest_first(rollup(t_digest ORDER BY half)), tdigest_last(rollup(t_digest ORDER BY half)) \
+                    FROM (SELECT ts > 5000 AS half, t_digest(100, data ORDER BY ts) FROM test GROUP BY 1) digests", None, None)
+                .first()
+                .get_two::<f64, f64>();
+            assert_eq!(first, Some(7919.0));
+            assert_eq!(last, Some(0.0));
+
+            let valid = client
+                .select("SELECT tdigest_validate(t_digest) FROM digest", None, None)
+                .first()
+                .get_one::<bool>();
+            assert_eq!(valid, Some(true));
+        });
+
+        let mut state = super::TDigestTransState::new(100);
+        assert_eq!(state.ends(), None);
+        for i in 0..1000 {
+            state.push(i as f64);
+        }
+        let mut later = super::TDigestTransState::new(200);
+        later.push(-1.0);
+        let combined = super::combine(Some(&state), Some(&later)).unwrap();
+        assert_eq!(combined.ends(), Some((0.0, -1.0)));
+        let combined = super::combine(Some(&later), Some(&state)).unwrap();
+        assert_eq!(combined.ends(), Some((-1.0, 999.0)));
+    }
+
+    #[pg_test]
+    fn test_other_numeric_types() {
+        Spi::execute(|client| {
+            client.select("CREATE TABLE test (i INT, b BIGINT, r REAL, d DOUBLE PRECISION)", None, None);
+            client.select("INSERT INTO test SELECT v, v, v, v FROM generate_series(1, 10000) v", None, None);
+
+            // the implicit casts to double precision are preferred over those
+            // to numeric, so this is the exact same aggregate for every type
+            for column in &["i", "b", "r", "d"] {
+                let (median, is_float) = client
+                    .select(&format!("SELECT tdigest_quantile(t_digest(100, {0}), 0.5), \
+                            tdigest_numeric_sum(t_digest(100, {0})) IS NULL \
+                        FROM test", column), None, None)
+                    .first()
+                    .get_two::<f64, bool>();
+                pct_eql(median.unwrap(), 5000.0, 0.01);
+                assert!(is_float.unwrap(), "{} was aggregated as numeric", column);
+            }
+        });
+    }
+
+    #[pg_test]
+    fn test_quantile_resolution() {
+        Spi::execute(|client| {
+            client.select("CREATE TABLE test (data DOUBLE PRECISION)", None, None);
+            client.select("INSERT INTO test SELECT generate_series(1, 100000)", None, None);
+            client.select("CREATE VIEW digest AS SELECT t_digest(100, data) FROM test", None, None);
+
+            let resolution = |quantile: f64| client
+                .select(&format!("SELECT tdigest_quantile_resolution(t_digest, {}) FROM digest", quantile), None, None)
+                .first()
+                .get_one::<f64>()
+                .unwrap();
+            let median = resolution(0.5);
+            for &tail in &[0.001, 0.01, 0.99, 0.999] {
+                assert!(resolution(tail) < median / 2.0, "resolution at {} is {}, at the median {}",
+                    tail, resolution(tail), median);
+            }
+
+            let (ci, resolution) = client
+                .select("SELECT hi - lo, tdigest_quantile_resolution(t_digest, 0.3) \
+                    FROM digest, tdigest_quantile_ci(t_digest, 0.3)", None, None)
+                .first()
+                .get_two::<f64, f64>();
+            apx_eql(ci.unwrap(), resolution.unwrap(), 0.000001);
+        });
+    }
+
+    #[pg_test]
+    fn test_owned_digest_in_hash_set() {
+        use std::collections::HashSet;
+        use super::TimescaleTDigestOwned;
+
+        let build = |values: &[f64]| {
+            let mut state = super::TDigestTransState::new(100);
+            for &value in values {
+                state.push(value);
+            }
+            state.digest();
+            super::TimescaleTDigest::from_tdigest_with(&state.digested, None, None, state.ends(), state.range)
+        };
+        let values: Vec<f64> = (0..1000).map(|i| ((i * 7919) % 1000) as f64).collect();
+        let a = build(&values);
+        let b = build(&values);
+        let c = build(&values[..999]);
+
+        let mut digests = HashSet::new();
+        assert!(digests.insert(TimescaleTDigestOwned::from(&a)));
+        assert!(!digests.insert(TimescaleTDigestOwned::from(&b)));
+        assert_eq!(digests.len(), 1);
+        assert!(digests.insert(TimescaleTDigestOwned::from(&c)));
+        assert_eq!(digests.len(), 2);
+
+        // and back to the same bytes
+        let owned = TimescaleTDigestOwned::from(&a);
+        assert_eq!(owned.to_digest().unwrap().to_pg_bytes(), a.to_pg_bytes());
+        assert_eq!(TimescaleTDigestOwned::from(&owned.to_digest().unwrap()), owned);
+    }
+
+    #[pg_test]
+    fn test_needs_compaction() {
+        Spi::execute(|client| {
+            client.select("CREATE TABLE test (data DOUBLE PRECISION, hour INT)", None, None);
+            client.select("INSERT INTO test SELECT (i * 7919) % 100000, i % 100 FROM generate_series(1, 100000) i", None, None);
+            client.select("CREATE TABLE hourly AS SELECT hour, t_digest(100, data) FROM test GROUP BY hour", None, None);
+
+            // a small fresh digest has centroids to spare, a rollup of all of
+            // them has used up nearly all of its
+            let (fresh, rolled_up) = client
+                .select("SELECT tdigest_needs_compaction(t_digest(100, data), 0.9), \
+                        (SELECT tdigest_needs_compaction(rollup(t_digest), 0.9) FROM hourly) \
+                    FROM test WHERE hour = 0 AND data < 5000", None, None)
+                .first()
+                .get_two::<bool, bool>();
+            assert_eq!(fresh, Some(false));
+            assert_eq!(rolled_up, Some(true));
+        });
+    }
+
+    #[pg_test(error = "TD005: digest size 4294967296 is too large, must be at most 4294967295")]
+    fn test_size_too_large_to_store() {
+        let digest = super::TDigest::new_with_size(u32::MAX as usize + 1);
+        super::TimescaleTDigest::from_tdigest(&digest);
+    }
+
+    #[pg_test(error = "TD005: digest count 4294967296 is too large, must be at most 4294967295")]
+    fn test_count_too_large_to_store() {
+        let cents = vec![super::Centroid::new(1.0, u32::MAX as f64 + 1.0)];
+        let digest = super::TDigest::new(cents, 1.0, u32::MAX as f64 + 1.0, 1.0, 1.0, 100);
+        super::TimescaleTDigest::from_tdigest(&digest);
+    }
+
+    #[pg_test]
+    fn test_sampled() {
+        Spi::execute(|client| {
+            client.select("CREATE TABLE test (data DOUBLE PRECISION)", None, None);
+            client.select("INSERT INTO test SELECT (i * 7919) % 100000 FROM generate_series(1, 100000) i", None, None);
+
+            let sampled = |seed: i64| client
+                .select(&format!("SELECT t_digest_sampled(100, 0.1, {}, data)::TEXT FROM test", seed), None, None)
+                .first()
+                .get_one::<String>()
+                .unwrap();
+            assert_eq!(sampled(1), sampled(1));
+            assert_ne!(sampled(1), sampled(2));
+
+            let (count, median, all) = client
+                .select("SELECT tdigest_count(t_digest_sampled(100, 0.1, 1, data)), \
+                        tdigest_quantile(t_digest_sampled(100, 0.1, 1, data), 0.5), \
+                        t_digest_sampled(100, 1, 1, data)::TEXT = t_digest(100, data)::TEXT \
+                    FROM test", None, None)
+                .first()
+                .get_three::<f64, f64, bool>();
+            pct_eql(count.unwrap(), 10000.0, 0.05);
+            pct_eql(median.unwrap(), 50000.0, 0.05);
+            assert!(all.unwrap());
+        });
+    }
+
+    #[pg_test(error = "TD004: sample_rate must be greater than 0 and at most 1, got 0")]
+    fn test_sampled_rejects_zero_rate() {
+        Spi::execute(|client| {
+            client.select("SELECT t_digest_sampled(100, 0, 1, data) FROM generate_series(1.0, 10.0) data", None, None);
+        });
+    }
+
+    #[pg_test]
+    fn test_partial_sum() {
+        Spi::execute(|client| {
+            client.select("CREATE TABLE test (data DOUBLE PRECISION)", None, None);
+            client.select("INSERT INTO test SELECT generate_series(1, 10000)", None, None);
+            client.select("CREATE VIEW digest AS SELECT t_digest(100, data) FROM test", None, None);
+
+            let (all, sum) = client
+                .select("SELECT tdigest_partial_sum(t_digest, 0, 1), tdigest_sum(t_digest) FROM digest", None, None)
+                .first()
+                .get_two::<f64, f64>();
+            pct_eql(all.unwrap(), sum.unwrap(), 1e-9);
+
+            // the top 10% are 9001 to 10000, the halves split the total
+            let (top, low_half, high_half) = client
+                .select("SELECT tdigest_partial_sum(t_digest, 0.9, 1), tdigest_partial_sum(t_digest, 0, 0.5), \
+                        tdigest_partial_sum(t_digest, 0.5, 1) FROM digest", None, None)
+                .first()
+                .get_three::<f64, f64, f64>();
+            pct_eql(top.unwrap(), 9500500.0, 0.01);
+            pct_eql(low_half.unwrap() + high_half.unwrap(), sum.unwrap(), 1e-9);
+            pct_eql(low_half.unwrap(), 12502500.0, 0.01);
+        });
+    }
+
+    #[pg_test(error = "TD004: quantiles must satisfy 0 <= low_q < high_q <= 1, got 0.5 and 0.5")]
+    fn test_partial_sum_empty_range() {
+        Spi::execute(|client| {
+            client.select("SELECT tdigest_partial_sum(t_digest(10, data), 0.5, 0.5) FROM generate_series(1.0, 10.0) data", None, None);
+        });
+    }
+
+    #[pg_test]
+    fn test_debug() {
+        Spi::execute(|client| {
+            let debug = client
+                .select("SELECT tdigest_debug(t_digest(37, data)) FROM generate_series(1.0, 1000.0) data", None, None)
+                .first()
+                .get_one::<String>()
+                .unwrap();
+            assert!(debug.contains("version: 5,"), "{}", debug);
+            assert!(debug.contains("buckets: 37,"), "{}", debug);
+            assert!(debug.contains("count: 1000,"), "{}", debug);
+            assert!(debug.contains("problem: none"), "{}", debug);
+        });
+    }
+
+    #[pg_test]
+    fn test_from_histogram() {
+        Spi::execute(|client| {
+            // 1000 values uniformly spread over 0 to 100, in buckets of 1
+            client.select("CREATE VIEW histogram AS SELECT \
+                    array_agg(i::DOUBLE PRECISION ORDER BY i) AS edges, \
+                    array_agg(10::BIGINT) FILTER (WHERE i < 100) AS counts \
+                FROM generate_series(0, 100) i", None, None);
+            client.select("CREATE VIEW digest AS SELECT tdigest_from_histogram(edges, counts, 100) AS digest FROM histogram", None, None);
+
+            let (count, median, min, max) = (
+                client.select("SELECT tdigest_count(digest) FROM digest", None, None).first().get_one::<f64>(),
+                client.select("SELECT tdigest_quantile(digest, 0.5) FROM digest", None, None).first().get_one::<f64>(),
+                client.select("SELECT tdigest_min(digest) FROM digest", None, None).first().get_one::<f64>(),
+                client.select("SELECT tdigest_max(digest) FROM digest", None, None).first().get_one::<f64>(),
+            );
+            assert_eq!(count, Some(1000.0));
+            pct_eql(median.unwrap(), 50.0, 0.02);
+            assert_eq!(min, Some(0.0));
+            assert_eq!(max, Some(100.0));
+
+            let valid = client
+                .select("SELECT tdigest_validate(digest) FROM digest", None, None)
+                .first()
+                .get_one::<bool>();
+            assert_eq!(valid, Some(true));
+        });
+    }
+
+    #[pg_test(error = "TD004: a histogram with 2 bucket counts needs 3 edges, got 2")]
+    fn test_from_histogram_edge_count() {
+        Spi::execute(|client| {
+            client.select("SELECT tdigest_from_histogram(ARRAY[0, 1]::DOUBLE PRECISION[], ARRAY[1, 2]::BIGINT[], 10)", None, None);
+        });
+    }
+
+    #[pg_test]
+    fn test_normality() {
+        apx_eql(super::normal_cdf(0.0), 0.5, 0.000001);
+        apx_eql(super::normal_cdf(1.96), 0.975, 0.0001);
+        apx_eql(super::normal_cdf(-1.96), 0.025, 0.0001);
+
+        // evenly spaced points in (0, 1), to be mapped through inverse CDFs
+        let uniform: Vec<f64> = (0..10000).map(|i| (i as f64 + 0.5) / 10000.0).collect();
+        let score = |values: Vec<f64>| {
+            let digest = super::TDigest::new_with_size(100).merge_unsorted(values);
+            super::tdigest_normality(super::TimescaleTDigest::from_tdigest(&digest), std::ptr::null_mut()).unwrap()
+        };
+        // a sum of 12 uniforms is close to normal
+        let mut state: u64 = 1;
+        let mut random = || {
+            state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (state >> 11) as f64 / (1u64 << 53) as f64
+        };
+        let normal = score((0..10000).map(|_| (0..12).map(|_| random()).sum::<f64>() - 6.0).collect());
+        // Cauchy is about as heavy-tailed as it gets
+        let cauchy = score(uniform.iter().map(|u| (std::f64::consts::PI * (u - 0.5)).tan()).collect());
+        let flat = score(uniform.clone());
+
+        assert!(normal < 0.02, "normal scored {}", normal);
+        assert!(cauchy > 10.0 * normal, "cauchy scored {}, normal {}", cauchy, normal);
+        assert!(flat > normal, "uniform scored {}, normal {}", flat, normal);
+    }
+
+    #[pg_test]
+    fn test_tdigest_round_trip() {
+        let empty = super::TDigest::new_with_size(100);
+        let digest = super::TimescaleTDigest::from_tdigest(&empty);
+        assert_eq!(digest.to_tdigest(), empty);
+
+        let values: Vec<f64> = (0..10000).map(|i| ((i * 7919) % 10000) as f64 / 10.0).collect();
+        let digested = super::TDigest::new_with_size(100).merge_unsorted(values);
+        let digest = super::TimescaleTDigest::from_tdigest(&digested);
+        assert_eq!(*digest.buckets, 100);
+        assert_eq!(*digest.count, 10000);
+        assert_eq!(digest.to_tdigest(), digested);
+
+        // and back again, to the same bytes
+        let round_trip = super::TimescaleTDigest::from_tdigest(&digest.to_tdigest());
+        assert_eq!(round_trip.to_pg_bytes(), digest.to_pg_bytes());
+    }
+
+    #[pg_test]
+    fn test_combine_in_place_matches_merge_digests() {
+        let states: Vec<super::TDigestTransState> = (0..200)
+            .map(|i| {
+                let mut state = super::TDigestTransState::new(100);
+                for j in 0..1000 {
+                    state.push(((i * 1000 + j) * 7919 % 200000) as f64);
+                }
+                state
+            })
+            .collect();
+
+        let mut expected = super::TDigest::new_with_size(100);
+        let mut folded: Option<crate::palloc::Internal<super::TDigestTransState>> = None;
+        let mut combined: Option<super::TDigestTransState> = None;
+        for state in &states {
+            let mut digested = state.clone();
+            digested.digest();
+            expected = super::TDigest::merge_digests(vec![expected, digested.digested]);
+
+            folded = super::combine_into(folded, Some(state));
+            combined = super::combine(combined.as_ref(), Some(state));
+            assert_eq!(folded.as_ref().unwrap().digested, expected);
+            assert_eq!(combined.as_ref().unwrap().digested, expected);
+        }
+
+        // a smaller state is folded into the larger one, not the other way round
+        let mut small = super::TDigestTransState::new(20);
+        small.push(1.0);
+        let folded = super::combine_into(Some(small.into()), Some(&combined.unwrap())).unwrap();
+        assert_eq!(folded.digested.max_size(), 100);
+    }
+
+    #[pg_test]
+    fn test_numeric_input() {
+        Spi::execute(|client| {
+            client.select("CREATE TABLE test (data NUMERIC)", None, None);
+            client.select("INSERT INTO test SELECT 0.1 * i + 0.000000000000000001 FROM generate_series(1, 10000) i", None, None);
+            client.select("INSERT INTO test VALUES (NULL), (-123456789012345.678901234)", None, None);
+            client.select("CREATE VIEW digest AS SELECT t_digest(100, data) FROM test", None, None);
+
+            let exact = client
+                .select("SELECT tdigest_numeric_sum(t_digest) = (SELECT sum(data) FROM test) FROM digest", None, None)
+                .first()
+                .get_one::<bool>();
+            assert!(exact.unwrap());
+
+            let (count, median) = client
+                .select("SELECT tdigest_count(t_digest), tdigest_quantile(t_digest, 0.5) FROM digest", None, None)
+                .first()
+                .get_two::<f64, f64>();
+            apx_eql(count.unwrap(), 10001.0, 0.000001);
+            pct_eql(median.unwrap(), 500.0, 0.01);
+
+            let float_sum = client
+                .select("SELECT tdigest_numeric_sum(t_digest(100, data::DOUBLE PRECISION)) FROM test", None, None)
+                .first()
+                .get_one::<Numeric>();
+            assert!(float_sum.is_none());
+        });
+
+        let mut sum = super::ExactSum::parse("-0.05").unwrap();
+        sum.add(&super::ExactSum::parse("12.5").unwrap());
+        assert_eq!(sum.to_string(), "12.45");
+        sum.add(&super::ExactSum::parse("-12.46").unwrap());
+        assert_eq!(sum.to_string(), "-0.01");
+        assert_eq!(super::ExactSum::parse("1.2.3"), None);
+    }
+
+    #[pg_test]
+    fn test_quantile_endpoints() {
+        Spi::execute(|client| {
+            client.select("CREATE TABLE test (data DOUBLE PRECISION)", None, None);
+            client.select("INSERT INTO test SELECT random() * 1000 FROM generate_series(1, 10000)", None, None);
+            client.select("CREATE VIEW digest AS SELECT t_digest(100, data) FROM test", None, None);
+
+            let exact = client
+                .select("SELECT tdigest_quantile(t_digest, 0.0) = tdigest_min(t_digest) \
+                        AND tdigest_quantile(t_digest, 1.0) = tdigest_max(t_digest) \
+                        AND tdigest_min(t_digest) = (SELECT min(data) FROM test) \
+                        AND tdigest_max(t_digest) = (SELECT max(data) FROM test) \
+                    FROM digest", None, None)
+                .first()
+                .get_one::<bool>();
+            assert!(exact.unwrap());
+
+            let within = client
+                .select("SELECT bool_and(tdigest_quantile(t_digest, q) BETWEEN tdigest_min(t_digest) AND tdigest_max(t_digest)) \
+                    FROM digest, unnest(ARRAY[1e-12, 0.0001, 0.9999, 1 - 1e-12]::DOUBLE PRECISION[]) q", None, None)
+                .first()
+                .get_one::<bool>();
+            assert!(within.unwrap());
+        });
+    }
+
+    #[pg_test]
+    fn test_rollup() {
+        Spi::execute(|client| {
+            client.select("CREATE TABLE test (bucket INT, data DOUBLE PRECISION)", None, None);
+            client.select("INSERT INTO test SELECT i % 10, i FROM generate_series(1, 10000) i", None, None);
+            // materialize per-bucket digests, the way a continuous aggregate would
+            client.select("CREATE TABLE partials AS \
+                SELECT bucket, t_digest(100, data), t_digest(100, data::NUMERIC) AS numeric_digest \
+                FROM test GROUP BY bucket", None, None);
+            client.select("CREATE VIEW digests AS SELECT \
+                    (SELECT rollup(t_digest) FROM partials) AS rolled_up, \
+                    (SELECT t_digest(100, data) FROM test) AS direct", None, None);
+
+            let (count, min, max) = client
+                .select("SELECT tdigest_count(rolled_up), tdigest_min(rolled_up), tdigest_max(rolled_up) FROM digests", None, None)
+                .first()
+                .get_three::<f64, f64, f64>();
+            apx_eql(count.unwrap(), 10000.0, 0.000001);
+            apx_eql(min.unwrap(), 1.0, 0.000001);
+            apx_eql(max.unwrap(), 10000.0, 0.000001);
+
+            let (rolled_up, direct) = client
+                .select("SELECT tdigest_quantile(rolled_up, 0.9), tdigest_quantile(direct, 0.9) FROM digests", None, None)
+                .first()
+                .get_two::<f64, f64>();
+            pct_eql(rolled_up.unwrap(), direct.unwrap(), 0.01);
+
+            let exact = client
+                .select("SELECT tdigest_numeric_sum(rollup(numeric_digest)) = (SELECT sum(data::NUMERIC) FROM test), \
+                        tdigest_numeric_sum(rollup(t_digest)) IS NULL \
+                    FROM partials", None, None)
+                .first()
+                .get_two::<bool, bool>();
+            assert_eq!(exact, (Some(true), Some(true)));
+        });
+
+        // the partial states themselves must survive being stored as bytea
+        // and merged in a later session
+        let mut partials = vec![];
+        for bucket in 0..10 {
+            let mut state = super::TDigestTransState::new(100);
+            for i in (1..=10000).filter(|i| i % 10 == bucket) {
+                state.push(i as f64);
+            }
+            partials.push(super::tdigest_serialize(state.into()));
+        }
+        let mut finalized: Option<Internal<super::TDigestTransState>> = None;
+        for bytes in partials {
+            let state = super::tdigest_deserialize(bytes, None);
+            finalized = super::combine_into(finalized, Some(&*state));
+        }
+        let mut finalized = finalized.unwrap();
+        finalized.digest();
+        apx_eql(finalized.digested.count(), 10000.0, 0.000001);
+        apx_eql(finalized.digested.min(), 1.0, 0.000001);
+        apx_eql(finalized.digested.max(), 10000.0, 0.000001);
+    }
+
+    #[pg_test]
+    fn test_modality() {
+        Spi::execute(|client| {
+            client.select("CREATE TABLE uniform (data DOUBLE PRECISION)", None, None);
+            client.select("INSERT INTO uniform SELECT generate_series(0.01, 100, 0.01)", None, None);
+            // two separate clusters, like cache hits and misses
+            client.select("CREATE TABLE bimodal (data DOUBLE PRECISION)", None, None);
+            client.select("INSERT INTO bimodal SELECT (i % 100) * 0.01 FROM generate_series(1, 5000) i", None, None);
+            client.select("INSERT INTO bimodal SELECT 10 + (i % 100) * 0.01 FROM generate_series(1, 5000) i", None, None);
+
+            let (uniform, bimodal) = client
+                .select("SELECT \
+                        (SELECT tdigest_modality(t_digest(100, data)) FROM uniform), \
+                        (SELECT tdigest_modality(t_digest(100, data)) FROM bimodal)", None, None)
+                .first()
+                .get_two::<i32, i32>();
+            assert_eq!(uniform.unwrap(), 1);
+            assert_eq!(bimodal.unwrap(), 2);
+        });
+    }
+
+    #[pg_test]
+    fn test_resize() {
+        Spi::execute(|client| {
+            client.select("CREATE TABLE test (data DOUBLE PRECISION)", None, None);
+            client.select("INSERT INTO test SELECT random() * 1000 FROM generate_series(1, 100000)", None, None);
+            client.select("CREATE TABLE digests AS SELECT t_digest(500, data) AS original FROM test", None, None);
+            client.select("ALTER TABLE digests ADD COLUMN resized TimescaleTDigest", None, None);
+            client.select("UPDATE digests SET resized = tdigest_resize(original, 50)", None, None);
+
+            let preserved = client
+                .select("SELECT tdigest_mean(resized) = tdigest_mean(original) \
+                        AND tdigest_sum(resized) = tdigest_sum(original) \
+                        AND tdigest_count(resized) = tdigest_count(original) \
+                        AND tdigest_min(resized) = tdigest_min(original) \
+                        AND tdigest_max(resized) = tdigest_max(original) \
+                        AND tdigest_validate(resized) \
+                    FROM digests", None, None)
+                .first()
+                .get_one::<bool>();
+            assert!(preserved.unwrap());
+
+            let centroids = client
+                .select("SELECT count(*) FROM digests, tdigest_centroids(resized)", None, None)
+                .first()
+                .get_one::<i64>();
+            assert!(centroids.unwrap() <= 50);
+
+            let (smaller, median) = client
+                .select("SELECT pg_column_size(resized) < pg_column_size(original), tdigest_quantile(resized, 0.5) FROM digests", None, None)
+                .first()
+                .get_two::<bool, f64>();
+            assert!(smaller.unwrap());
+            pct_eql(median.unwrap(), 500.0, 0.05);
+
+            // growing is a no-op
+            let unchanged = client
+                .select("SELECT tdigest_resize(resized, 500)::TEXT = resized::TEXT FROM digests", None, None)
+                .first()
+                .get_one::<bool>();
+            assert!(unchanged.unwrap());
+        });
+    }
+
+    #[pg_test]
+    fn test_resized_aggregate() {
+        Spi::execute(|client| {
+            client.select("CREATE TABLE test (data DOUBLE PRECISION)", None, None);
+            client.select("INSERT INTO test SELECT random() * 1000 FROM generate_series(1, 100000)", None, None);
+            client.select("CREATE TABLE digests AS SELECT \
+                    t_digest(500, data) AS original, \
+                    t_digest_resized(500, 50, data) AS resized \
+                FROM test", None, None);
+
+            let (size, count, valid) = client
+                .select("SELECT (tdigest_to_json(resized)->>'buckets')::INT, tdigest_count(resized), tdigest_validate(resized) FROM digests", None, None)
+                .first()
+                .get_three::<i32, f64, bool>();
+            assert_eq!(size, Some(50));
+            assert_eq!(count, Some(100000.0));
+            assert_eq!(valid, Some(true));
+
+            let centroids = client
+                .select("SELECT count(*) FROM digests, tdigest_centroids(resized)", None, None)
+                .first()
+                .get_one::<i64>();
+            assert!(centroids.unwrap() <= 50);
+
+            // the same digest tdigest_resize produces, in one pass
+            let same = client
+                .select("SELECT tdigest_resize(original, 50)::TEXT = resized::TEXT FROM digests", None, None)
+                .first()
+                .get_one::<bool>();
+            assert_eq!(same, Some(true));
+
+            let median = client
+                .select("SELECT tdigest_quantile(resized, 0.5) FROM digests", None, None)
+                .first()
+                .get_one::<f64>();
+            pct_eql(median.unwrap(), 500.0, 0.05);
+        });
+    }
+
+    #[pg_test]
+    fn test_exact_mode() {
+        Spi::execute(|client| {
+            client.select("CREATE TABLE test (data DOUBLE PRECISION)", None, None);
+            // 5 makes up 40% of the values, everything else 10%
+            client.select("INSERT INTO test SELECT CASE WHEN i % 10 < 3 THEN 5 ELSE i % 10 END \
+                FROM generate_series(1, 10000) i", None, None);
+
+            let (mode, sql_mode) = client
+                .select("SELECT tdigest_exact_mode(t_digest_with_exact_mode(100, data)), \
+                        mode() WITHIN GROUP (ORDER BY data) \
+                    FROM test", None, None)
+                .first()
+                .get_two::<f64, f64>();
+            assert_eq!(mode.unwrap(), 5.0);
+            assert_eq!(sql_mode.unwrap(), 5.0);
+
+            // past the bound on distinct values there's only an approximation
+            client.select("INSERT INTO test SELECT i * 0.001 FROM generate_series(1, 10000) i", None, None);
+            let (mode, sql_mode) = client
+                .select("SELECT tdigest_exact_mode(t_digest_with_exact_mode(100, data)), \
+                        mode() WITHIN GROUP (ORDER BY data) \
+                    FROM test", None, None)
+                .first()
+                .get_two::<f64, f64>();
+            assert_eq!(sql_mode.unwrap(), 5.0);
+            apx_eql(mode.unwrap(), 5.0, 0.5);
+        });
+    }
+
+    #[pg_test]
+    fn test_check_centroids() {
+        use super::{check_centroids, Centroid};
+
+        let good = [Centroid::new(1.0, 2.0), Centroid::new(1.0, 1.0), Centroid::new(3.0, 1.0)];
+        assert_eq!(check_centroids(&good), Ok(()));
+        assert_eq!(check_centroids(&[]), Ok(()));
+
+        let zero_weight = [Centroid::new(1.0, 2.0), Centroid::new(2.0, 0.0)];
+        assert!(check_centroids(&zero_weight).is_err());
+
+        let negative_weight = [Centroid::new(1.0, -2.0)];
+        assert!(check_centroids(&negative_weight).is_err());
+
+        let nan_mean = [Centroid::new(std::f64::NAN, 1.0)];
+        assert!(check_centroids(&nan_mean).is_err());
+
+        let infinite_mean = [Centroid::new(1.0, 1.0), Centroid::new(std::f64::INFINITY, 1.0)];
+        assert!(check_centroids(&infinite_mean).is_err());
+
+        let decreasing = [Centroid::new(2.0, 1.0), Centroid::new(1.0, 1.0)];
+        assert!(check_centroids(&decreasing).is_err());
+    }
+
+    #[pg_test(error = "TD004: invalid centroids: centroid 0 has non-positive weight -3")]
+    fn test_from_centroids_rejects_negative_weight() {
+        Spi::execute(|client| {
+            client.select("SELECT tdigest_from_centroids(ARRAY[1.0, 2.0], ARRAY[-3, 1], 100)", None, None);
+        });
+    }
+
+    #[pg_test(error = "TD004: invalid centroids: centroid 1 has non-finite mean NaN")]
+    fn test_from_centroids_rejects_nan_mean() {
+        Spi::execute(|client| {
+            client.select("SELECT tdigest_from_centroids(ARRAY[1.0, 'NaN'], ARRAY[1, 1], 100)", None, None);
+        });
+    }
+
+    // A serialized transition state holding the centroids as they are,
+    // however broken.
+    fn serialized_state(centroids: &[(f64, f64)]) -> pg_sys::Datum {
+        let centroids = centroids.iter().map(|&(mean, weight)| super::Centroid::new(mean, weight)).collect();
+        let mut state = super::TDigestTransState::new(4);
+        state.digested = super::TDigest::new(centroids, 21.0, 6.0, 6.0, 1.0, 4);
+        super::tdigest_serialize(state.into())
+    }
+
+    #[pg_test(error = "TD006: invalid TimescaleTDigest: bad serialized state, centroid 1 has a smaller mean than the one before it")]
+    fn test_deserialize_rejects_decreasing_means() {
+        super::tdigest_deserialize(serialized_state(&[(3.5, 3.0), (1.5, 2.0), (6.0, 1.0)]), None);
+    }
+
+    #[pg_test(error = "TD006: invalid TimescaleTDigest: bad serialized state, centroid 1 has non-positive weight 0")]
+    fn test_deserialize_rejects_zero_weight() {
+        super::tdigest_deserialize(serialized_state(&[(1.5, 2.0), (0.0, 0.0), (3.5, 3.0), (6.0, 1.0)]), None);
+    }
+
+    #[pg_test]
+    fn test_centroid_slices_match_to_tdigest() {
+        let mut state = super::TDigestTransState::new(500);
+        for i in 0..100_000i64 {
+            state.push(((i * 7919) % 100_000) as f64);
+        }
+        state.digest();
+        let digest = super::TimescaleTDigest::from_tdigest(&state.digested);
+
+        let reference = digest.to_tdigest();
+        let slices = digest.centroid_slices();
+        for i in 0..=1000 {
+            let quantile = i as f64 / 1000.0;
+            assert_eq!(slices.estimate_quantile(quantile).to_bits(), reference.estimate_quantile(quantile).to_bits());
+        }
+    }
+
+    #[pg_test]
+    fn test_serde_round_trip() {
+        let mut state = super::TDigestTransState::new(100);
+        for i in 0..1050 {
+            state.push(i as f64);
+        }
+        state.digest();
+        let digest = super::TimescaleTDigest::from_tdigest(&state.digested);
+
+        let json = serde_json::to_string(&digest).unwrap();
+        let round_trip: super::TimescaleTDigest<'static> = serde_json::from_str(&json).unwrap();
+        assert_eq!(round_trip.to_pg_bytes(), digest.to_pg_bytes());
+        assert_eq!(round_trip.to_tdigest(), state.digested);
+
+        // the NaNs of an empty digest survive as well
+        let empty = super::TimescaleTDigest::from_tdigest(&super::TDigest::new_with_size(100));
+        let json = serde_json::to_string(&empty).unwrap();
+        let round_trip: super::TimescaleTDigest<'static> = serde_json::from_str(&json).unwrap();
+        assert_eq!(round_trip.to_pg_bytes(), empty.to_pg_bytes());
+
+        // and the centroids are checked on the way in
+        let decreasing = json.replace("\"means\":[]", "\"means\":[2.0,1.0]")
+            .replace("\"weights\":[]", "\"weights\":[1,1]")
+            .replace("\"count\":0", "\"count\":2");
+        assert!(serde_json::from_str::<super::TimescaleTDigest<'static>>(&decreasing).is_err());
+    }
+
+    #[pg_test]
+    fn test_quantile_each() {
+        Spi::execute(|client| {
+            client.select("CREATE TABLE test (bucket INT, data DOUBLE PRECISION)", None, None);
+            client.select("INSERT INTO test SELECT 1, generate_series(0.01, 100, 0.01)", None, None);
+            client.select("INSERT INTO test SELECT 2, generate_series(100.01, 200, 0.01)", None, None);
+            client.select("CREATE VIEW digests AS \
+                SELECT bucket, t_digest(100, data) FROM test GROUP BY bucket", None, None);
+
+            let matches = client
+                .select("SELECT array_length(each, 1) = 3 \
+                        AND each[1] = tdigest_quantile(a.t_digest, 0.5) \
+                        AND each[2] IS NULL \
+                        AND each[3] = tdigest_quantile(b.t_digest, 0.5) \
+                    FROM digests a, digests b, \
+                        tdigest_quantile_each(ARRAY[a.t_digest, NULL, b.t_digest], 0.5) each \
+                    WHERE a.bucket = 1 AND b.bucket = 2", None, None)
+                .first()
+                .get_one::<bool>();
+            assert!(matches.unwrap());
+        });
+    }
+
+    #[pg_test]
+    fn test_quantiles_monotonic() {
+        Spi::execute(|client| {
+            // two centroids close together at the top: interpolating within
+            // the middle one overshoots where the last one starts
+            client.select("CREATE VIEW digest AS SELECT \
+                tdigest_from_centroids(ARRAY[0, 9, 10], ARRAY[1, 1, 1], 100) AS digest", None, None);
+
+            let inverted = client
+                .select("SELECT tdigest_quantile(digest, 0.67) < tdigest_quantile(digest, 0.66) FROM digest", None, None)
+                .first()
+                .get_one::<bool>();
+            assert_eq!(inverted, Some(true));
+
+            let monotonic = client
+                .select("SELECT bool_and(value >= prev) FROM ( \
+                        SELECT value, lag(value) OVER (ORDER BY i) AS prev \
+                        FROM digest, unnest(tdigest_quantiles(digest, ARRAY[0.6, 0.66, 0.67, 0.7, 0.9])) \
+                            WITH ORDINALITY AS q(value, i) \
+                    ) q", None, None)
+                .first()
+                .get_one::<bool>();
+            assert_eq!(monotonic, Some(true));
+
+            // unsorted and NULL quantiles
+            let matches = client
+                .select("SELECT q = ARRAY[tdigest_quantile(digest, 0.66), NULL, tdigest_quantile(digest, 0.66), 0] \
+                    FROM digest, tdigest_quantiles(digest, ARRAY[0.67, NULL, 0.66, 0]) q", None, None)
+                .first()
+                .get_one::<bool>();
+            assert_eq!(matches, Some(true));
+
+            let empty = client
+                .select("SELECT tdigest_quantiles(tdigest_from_centroids('{}', '{}', 100), ARRAY[0.5]) IS NULL", None, None)
+                .first()
+                .get_one::<bool>();
+            assert_eq!(empty, Some(true));
+        });
+    }
+
+    // A digest of 1, 2 and 3 at size 4 as the first release stored it, byte
+    // for byte: the varlena header, version 1 and padding, buckets, count,
+    // sum, min and max, then the means and the weights. Digests in this layout
+    // are on disk already, so they must stay readable however it evolves.
+    const VERSION_1_IMAGE: [u8; 76] = [
+        0x30, 0x01, 0, 0, 1, 0, 0, 0,
+        4, 0, 0, 0, 3, 0, 0, 0,
+        0, 0, 0, 0, 0, 0, 0x18, 0x40,
+        0, 0, 0, 0, 0, 0, 0xf0, 0x3f,
+        0, 0, 0, 0, 0, 0, 0x08, 0x40,
+        0, 0, 0, 0, 0, 0, 0xf0, 0x3f,
+        0, 0, 0, 0, 0, 0, 0x00, 0x40,
+        0, 0, 0, 0, 0, 0, 0x08, 0x40,
+        1, 0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0,
+    ];
+
+    // The same digest in a later version of the layout, with the fields each
+    // version up to it added: no mode or exact sum in version 2, first and
+    // last values of 1 and 3 in version 3, and no range in version 4.
+    fn image_of_version(version: u8) -> Vec<u8> {
+        let old = &VERSION_1_IMAGE;
+        let mut image = old[..40].to_vec();
+        image[4] = version;
+        if version >= 2 {
+            image.extend_from_slice(&std::f64::NAN.to_le_bytes());
+        }
+        if version >= 3 {
+            image.extend_from_slice(&1.0f64.to_le_bytes());
+            image.extend_from_slice(&3.0f64.to_le_bytes());
+        }
+        if version >= 4 {
+            image.extend_from_slice(&super::NO_RANGE.0.to_le_bytes());
+            image.extend_from_slice(&super::NO_RANGE.1.to_le_bytes());
+        }
+        image.extend_from_slice(&old[40..]);
+        if version >= 2 {
+            image.extend_from_slice(&0u32.to_le_bytes());
+        }
+        image
+    }
+
+    unsafe fn read_image(image: &[u8]) -> super::TimescaleTDigest<'static> {
+        let bytes = pg_sys::palloc(image.len()) as *mut u8;
+        std::ptr::copy_nonoverlapping(image.as_ptr(), bytes, image.len());
+        set_varsize(bytes as *mut _, image.len() as i32);
+        super::TimescaleTDigest::from_datum(bytes as pg_sys::Datum, false, pg_sys::BYTEAOID).unwrap()
+    }
+
+    #[pg_test]
+    fn test_read_version_1() {
+        let digest = unsafe { read_image(&VERSION_1_IMAGE) };
+        assert_eq!(*digest.version, 1);
+        assert_eq!(digest.validate(), Ok(()));
+        assert_eq!(super::tdigest_count(digest, std::ptr::null_mut()), 3.0);
+        assert_eq!(super::tdigest_sum(digest, std::ptr::null_mut()), Some(6.0));
+        assert_eq!(super::tdigest_quantile(digest, 0.0, std::ptr::null_mut()), Some(1.0));
+        assert_eq!(super::tdigest_quantile(digest, 1.0, std::ptr::null_mut()), Some(3.0));
+        assert_eq!(digest.to_tdigest().raw_centroids().len(), 3);
+        assert_eq!(digest.exact_mode(), None);
+        assert_eq!(digest.parsed_exact_sum(), None);
+        assert_eq!(digest.ends(), None);
+        assert_eq!(digest.range(), None);
+
+        // rewriting it gives the current layout: no mode, unknown first and
+        // last values and range after the max, an empty exact sum after the
+        // weights, and a checksum at the end
+        let rewritten = super::TimescaleTDigest::from_tdigest(&digest.to_tdigest());
+        let old = &VERSION_1_IMAGE;
+        let new = rewritten.to_pg_bytes();
+        assert_eq!(new[4], 5);
+        assert_eq!(&new[5..40], &old[5..40]);
+        use std::convert::TryInto;
+        for offset in &[40, 48, 56] {
+            assert!(f64::from_le_bytes(new[*offset..offset + 8].try_into().unwrap()).is_nan());
+        }
+        assert_eq!(i64::from_le_bytes(new[64..72].try_into().unwrap()), std::i64::MAX);
+        assert_eq!(i64::from_le_bytes(new[72..80].try_into().unwrap()), std::i64::MIN);
+        assert_eq!(&new[80..new.len() - 8], &old[40..]);
+        assert_eq!(&new[new.len() - 8..new.len() - 4], &[0; 4]);
+    }
+
+    #[pg_test]
+    fn test_read_later_versions() {
+        let original = unsafe { read_image(&VERSION_1_IMAGE) }.to_tdigest();
+        for version in 2..=4 {
+            let digest = unsafe { read_image(&image_of_version(version)) };
+            assert_eq!(*digest.version, version);
+            assert_eq!(digest.validate(), Ok(()));
+            assert_eq!(digest.to_tdigest(), original);
+            assert_eq!(digest.exact_mode(), None);
+            assert_eq!(digest.parsed_exact_sum(), None);
+            assert_eq!(digest.ends(), if version >= 3 { Some((1.0, 3.0)) } else { None });
+            assert_eq!(digest.range(), None);
+            assert_eq!(super::tdigest_verify_checksum(digest, std::ptr::null_mut()), None);
+        }
+    }
+
+    #[pg_test]
+    fn test_checksum() {
+        let values: Vec<f64> = (1..=1000).map(|i| (i * i) as f64).collect();
+        let digest = super::TimescaleTDigest::from_tdigest(&super::TDigest::new_with_size(100).merge_unsorted(values));
+        assert_eq!(super::tdigest_verify_checksum(digest, std::ptr::null_mut()), Some(true));
+
+        // a flipped bit in the first mean leaves a perfectly consistent
+        // digest, but not the same one
+        let mut image = digest.to_pg_bytes().to_vec();
+        image[80] ^= 1;
+        let damaged = unsafe { read_image(&image) };
+        assert_eq!(damaged.validate(), Ok(()));
+        assert_eq!(super::tdigest_verify_checksum(damaged, std::ptr::null_mut()), Some(false));
+
+        // digests from before checksums don't have one to verify
+        let old = unsafe { read_image(&VERSION_1_IMAGE) };
+        assert_eq!(super::tdigest_verify_checksum(old, std::ptr::null_mut()), None);
+    }
+
+    // There is only one digest type, whatever version of the layout it was
+    // stored with, so every accessor has to give the same answers for old
+    // layouts as for the one written by this build.
+    #[pg_test]
+    fn test_accessors_agree_across_versions() {
+        let old = unsafe { read_image(&VERSION_1_IMAGE) };
+        let current = super::TimescaleTDigest::from_tdigest(&old.to_tdigest());
+        assert_eq!(*current.version, super::TimescaleTDigest::CURRENT_VERSION);
+
+        let null = std::ptr::null_mut();
+        for &q in &[0.0, 0.1, 0.25, 0.5, 0.75, 0.9, 1.0] {
+            assert_eq!(super::tdigest_quantile(old, q, null), super::tdigest_quantile(current, q, null));
+        }
+        for &p in &[0, 10, 50, 90, 100] {
+            assert_eq!(super::tdigest_percentile(old, p, null), super::tdigest_percentile(current, p, null));
+        }
+        for &value in &[0.5, 1.0, 1.5, 2.0, 3.0, 4.0] {
+            assert_eq!(super::tdigest_quantile_at_value(old, value, null),
+                super::tdigest_quantile_at_value(current, value, null));
+        }
+        assert_eq!(super::tdigest_count(old, null), super::tdigest_count(current, null));
+        assert_eq!(super::tdigest_min(old, null), super::tdigest_min(current, null));
+        assert_eq!(super::tdigest_max(old, null), super::tdigest_max(current, null));
+        assert_eq!(super::tdigest_mean(old, null), super::tdigest_mean(current, null));
+        assert_eq!(old.validate(), current.validate());
+        assert_eq!(old.to_tdigest(), current.to_tdigest());
+    }
+
+    #[pg_test(error = "unsupported TimescaleTDigest version 99, expected at most 5")]
+    fn test_reject_future_version() {
+        let mut image = VERSION_1_IMAGE.to_vec();
+        image[4] = 99;
+        unsafe { read_image(&image) };
+    }
+
+    #[pg_test]
+    fn test_run_length_buffer() {
+        // a step-like signal, with long runs of the same reading
+        let values: Vec<f64> = (0..10_000).map(|i| ((i / 250) * 7 % 11) as f64).collect();
+
+        let mut state = super::TDigestTransState::new(100);
+        for &value in &values {
+            state.push(value);
+            // runs are longer than the flush interval, so there's never more
+            // than the end of one and the start of the next in the buffer
+            assert!(state.buffer.len() <= 2);
+        }
+        state.digest();
+
+        // the same flushes, but with every value buffered individually
+        let mut naive = super::TDigest::new_with_size(100);
+        for chunk in values.chunks(100) {
+            naive = naive.merge_unsorted(chunk.to_vec());
+        }
+        assert_eq!(state.digested, naive);
+
+        // -0 is added as 0, so it joins the same run
+        let mut state = super::TDigestTransState::new(100);
+        state.push(0.0);
+        state.push(-0.0);
+        state.push(-0.0);
+        assert_eq!(state.buffer.len(), 1);
+        assert!(state.buffer[0].0.is_sign_positive());
+        assert_eq!(state.buffered, 3);
+    }
+
+    #[pg_test]
+    fn test_signed_zero_and_extremes() {
+        Spi::execute(|client| {
+            // -0 sorts the same as 0, so without normalizing it which one came
+            // out as the min or max would depend on the input order
+            let (min, max) = client
+                .select("SELECT tdigest_min(d)::TEXT, tdigest_max(d)::TEXT \
+                    FROM (SELECT t_digest(100, data) AS d FROM unnest(ARRAY['-0'::float8, 0, '-0']) data) d", None, None)
+                .first()
+                .get_two::<String, String>();
+            assert_eq!(min.as_deref(), Some("0"));
+            assert_eq!(max.as_deref(), Some("0"));
+
+            client.select("CREATE TABLE test (data DOUBLE PRECISION)", None, None);
+            client.select("INSERT INTO test VALUES ('-0'), (0), (-1), (1), \
+                ('1.7976931348623157e308'), ('-1.7976931348623157e308'), \
+                ('1.7976931348623157e308'), ('-1.7976931348623157e308')", None, None);
+            // subnormals, the smallest being 2^-1074
+            client.select("INSERT INTO test SELECT i * 2 ^ -1074 FROM generate_series(1, 100) i", None, None);
+            client.select("INSERT INTO test SELECT -i * 2 ^ -1074 FROM generate_series(1, 100) i", None, None);
+            client.select("CREATE TABLE digests AS SELECT t_digest(100, data) AS d FROM test", None, None);
+
+            let (min, max, first) = client
+                .select("SELECT tdigest_min(d), tdigest_max(d), tdigest_first(d)::TEXT FROM digests", None, None)
+                .first()
+                .get_three::<f64, f64, String>();
+            assert_eq!(min, Some(std::f64::MIN));
+            assert_eq!(max, Some(std::f64::MAX));
+            assert_eq!(first.as_deref(), Some("0"));
+
+            let finite = client
+                .select("SELECT bool_and(tdigest_quantile(d, q) BETWEEN tdigest_min(d) AND tdigest_max(d)) \
+                    FROM digests, generate_series(0, 1, 0.01) q", None, None)
+                .first()
+                .get_one::<bool>();
+            assert_eq!(finite, Some(true));
+
+            let finite_means = client
+                .select("SELECT bool_and(mean BETWEEN tdigest_min(d) AND tdigest_max(d)) \
+                    FROM digests, tdigest_centroids(d)", None, None)
+                .first()
+                .get_one::<bool>();
+            assert_eq!(finite_means, Some(true));
+
+            // the subnormals are kept apart from 0, not flushed to it
+            let (low, high) = client
+                .select("SELECT tdigest_quantile(d, 0.3), tdigest_quantile(d, 0.7) FROM digests", None, None)
+                .first()
+                .get_two::<f64, f64>();
+            assert!(low.unwrap() < 0.0 && low.unwrap() > -1e-300);
+            assert!(high.unwrap() > 0.0 && high.unwrap() < 1e-300);
+        });
+    }
+
+    #[pg_test]
+    fn test_is_exact() {
+        Spi::execute(|client| {
+            let (small, large) = client
+                .select("SELECT \
+                        tdigest_is_exact((SELECT t_digest(100, data) FROM generate_series(1, 50) data)), \
+                        tdigest_is_exact((SELECT t_digest(100, data) FROM generate_series(1, 10000) data))", None, None)
+                .first()
+                .get_two::<bool, bool>();
+            assert_eq!(small, Some(true));
+            assert_eq!(large, Some(false));
+
+            // a full digest has merged some of its values already
+            let (full, merged) = client
+                .select("SELECT tdigest_is_exact(d), (SELECT count(*) FROM tdigest_centroids(d)) \
+                    FROM (SELECT t_digest(100, data) d FROM generate_series(1, 100) data) s", None, None)
+                .first()
+                .get_two::<bool, i64>();
+            assert_eq!(full, Some(false));
+            assert_eq!(merged, Some(88));
+
+            // an exact digest gives the values themselves back
+            let centroids = client
+                .select("SELECT count(*) FROM tdigest_centroids((SELECT t_digest(100, data) FROM generate_series(1, 50) data))", None, None)
+                .first()
+                .get_one::<i64>();
+            assert_eq!(centroids, Some(50));
+        });
+    }
+
+    #[pg_test]
+    fn test_digest_by() {
+        Spi::execute(|client| {
+            client.select("CREATE TABLE test (status TEXT, latency DOUBLE PRECISION)", None, None);
+            client.select("INSERT INTO test SELECT CASE WHEN i % 10 = 0 THEN '500' ELSE '200' END, i % 997 \
+                FROM generate_series(1, 10000) i", None, None);
+            client.select("INSERT INTO test VALUES (NULL, 1), ('200', NULL)", None, None);
+            client.select("CREATE TABLE by_status AS SELECT t_digest_by(100, status, latency) AS digests FROM test", None, None);
+
+            let keys = client
+                .select("SELECT string_agg(key, ',' ORDER BY key) FROM by_status, jsonb_object_keys(digests) key", None, None)
+                .first()
+                .get_one::<String>();
+            assert_eq!(keys.as_deref(), Some("200,500"));
+
+            let matches = client
+                .select("SELECT bool_and(tdigest_from_json((digests->status)::JSON)::TEXT = grouped.digest::TEXT) \
+                    FROM by_status, ( \
+                        SELECT status, t_digest(100, latency) AS digest FROM test WHERE status IS NOT NULL GROUP BY status \
+                    ) grouped", None, None)
+                .first()
+                .get_one::<bool>();
+            assert_eq!(matches, Some(true));
+        });
+    }
+
+    #[pg_test(error = "TD005: t_digest_by got more than 1000 distinct keys")]
+    fn test_digest_by_too_many_keys() {
+        Spi::execute(|client| {
+            client.select("SELECT t_digest_by(10, i::TEXT, i) FROM generate_series(1, 1001) i", None, None);
+        });
+    }
+
+    #[pg_test]
+    fn test_gini() {
+        Spi::execute(|client| {
+            let (equal, uniform, skewed) = client
+                .select("SELECT \
+                        tdigest_gini((SELECT t_digest(100, 5) FROM generate_series(1, 1000))), \
+                        tdigest_gini((SELECT t_digest(100, data) FROM generate_series(1, 1000) data)), \
+                        tdigest_gini((SELECT t_digest(100, CASE WHEN i = 1000 THEN 1000 ELSE 0 END) FROM generate_series(1, 1000) i))", None, None)
+                .first()
+                .get_three::<f64, f64, f64>();
+            apx_eql(equal.unwrap(), 0.0, 1e-9);
+            // the Gini coefficient of 1..n is (n - 1) / 3n
+            apx_eql(uniform.unwrap(), 999.0 / 3000.0, 0.01);
+            assert!(skewed.unwrap() > 0.99, "skewed gini {}", skewed.unwrap());
+        });
+    }
+
+    #[pg_test(error = "TD004: tdigest_gini requires non-negative values, got a minimum of -1")]
+    fn test_gini_negative() {
+        Spi::execute(|client| {
+            client.select("SELECT tdigest_gini(t_digest(100, data)) FROM generate_series(-1, 10) data", None, None);
+        });
+    }
+
+    #[pg_test]
+    fn test_jsonb_cast() {
+        Spi::execute(|client| {
+            client.select("CREATE TABLE test (data DOUBLE PRECISION)", None, None);
+            client.select("INSERT INTO test SELECT generate_series(0.01, 100, 0.01)", None, None);
+            client.select("CREATE VIEW digest AS SELECT t_digest(100, data) FROM test", None, None);
+
+            let (same, volatility) = client
+                .select("SELECT t_digest::jsonb = tdigest_to_json(t_digest)::jsonb, \
+                        (SELECT provolatile::TEXT FROM pg_cast JOIN pg_proc ON castfunc = pg_proc.oid \
+                            WHERE castsource = 'TimescaleTDigest'::regtype AND casttarget = 'jsonb'::regtype) \
+                    FROM digest", None, None)
+                .first()
+                .get_two::<bool, String>();
+            assert_eq!(same, Some(true));
+            assert_eq!(volatility.as_deref(), Some("i"));
+        });
+    }
+
+    #[pg_test]
+    fn test_jsonb_round_trip() {
+        Spi::execute(|client| {
+            client.select("CREATE TABLE test (data DOUBLE PRECISION)", None, None);
+            client.select("INSERT INTO test SELECT generate_series(0.01, 100, 0.01)", None, None);
+            client.select("CREATE TABLE api_data AS SELECT t_digest(100, data)::jsonb AS payload FROM test", None, None);
+            client.select("CREATE TABLE digests (d TimescaleTDigest)", None, None);
+            client.select("INSERT INTO digests (d) SELECT payload::TimescaleTDigest FROM api_data", None, None);
+
+            let (same, quantiles_match) = client
+                .select("SELECT d::TEXT = original::TEXT, \
+                        tdigest_quantile(d, 0.1) = tdigest_quantile(original, 0.1) \
+                        AND tdigest_quantile(d, 0.5) = tdigest_quantile(original, 0.5) \
+                        AND tdigest_quantile(d, 0.99) = tdigest_quantile(original, 0.99) \
+                    FROM digests, (SELECT t_digest(100, data) AS original FROM test) original", None, None)
+                .first()
+                .get_two::<bool, bool>();
+            assert_eq!(same, Some(true));
+            assert_eq!(quantiles_match, Some(true));
+        });
+    }
+
+    #[pg_test(error = "TD006: invalid TimescaleTDigest: bad JSON, expected an object, got \"digest\"")]
+    fn test_jsonb_cast_malformed() {
+        Spi::execute(|client| {
+            client.select("SELECT '\"digest\"'::jsonb::TimescaleTDigest", None, None);
+        });
+    }
+
+    #[pg_test(error = "TD006: invalid TimescaleTDigest: bad JSON, missing field `buckets`")]
+    fn test_jsonb_cast_missing_field() {
+        Spi::execute(|client| {
+            client.select("SELECT '{\"count\": 1}'::jsonb::TimescaleTDigest", None, None);
+        });
+    }
+
+    #[pg_test]
+    fn test_dedup_stats() {
+        Spi::execute(|client| {
+            client.select("CREATE TABLE archive (id INT, digest TimescaleTDigest)", None, None);
+            // three copies of one digest, two others, and a missing one
+            client.select("INSERT INTO archive SELECT i, (SELECT t_digest(100, data) FROM generate_series(1, 1000) data) \
+                FROM generate_series(1, 3) i", None, None);
+            client.select("INSERT INTO archive SELECT i, (SELECT t_digest(100, data * i) FROM generate_series(1, 1000) data) \
+                FROM generate_series(4, 5) i", None, None);
+            client.select("INSERT INTO archive VALUES (6, NULL)", None, None);
+
+            let (total, distinct) = client
+                .select("SELECT total, \"distinct\" FROM tdigest_dedup_stats('archive', 'digest')", None, None)
+                .first()
+                .get_two::<i64, i64>();
+            assert_eq!(total, Some(5));
+            assert_eq!(distinct, Some(3));
+
+            let (same, different) = client
+                .select("SELECT tdigest_fingerprint(a.digest) = tdigest_fingerprint(b.digest), \
+                        tdigest_fingerprint(a.digest) = tdigest_fingerprint(c.digest) \
+                    FROM archive a, archive b, archive c WHERE a.id = 1 AND b.id = 2 AND c.id = 4", None, None)
+                .first()
+                .get_two::<bool, bool>();
+            assert_eq!(same, Some(true));
+            assert_eq!(different, Some(false));
+        });
+    }
+
+    #[pg_test]
+    fn test_quantile_extrapolation() {
+        Spi::execute(|client| {
+            client.select("CREATE TABLE test (data DOUBLE PRECISION)", None, None);
+            client.select("INSERT INTO test SELECT generate_series(1, 1000)", None, None);
+            client.select("CREATE VIEW digest AS SELECT t_digest(100, data) FROM test", None, None);
+
+            for &quantile in &[0.0, 0.0001, 0.5, 0.9999, 1.0] {
+                let (clamped, plain) = client
+                    .select(&format!("SELECT tdigest_quantile(t_digest, {0}, false), tdigest_quantile(t_digest, {0}) FROM digest", quantile), None, None)
+                    .first()
+                    .get_two::<f64, f64>();
+                assert_eq!(clamped, plain, "at {}", quantile);
+                assert!(clamped.unwrap() >= 1.0 && clamped.unwrap() <= 1000.0);
+            }
+
+            // the values are evenly spaced, so the lines continue them
+            let (low, middle, high) = client
+                .select("SELECT tdigest_quantile(t_digest, 0, true), tdigest_quantile(t_digest, 0.5, true), \
+                        tdigest_quantile(t_digest, 1, true) FROM digest", None, None)
+                .first()
+                .get_three::<f64, f64, f64>();
+            assert!(low.unwrap() < 1.0, "extrapolated low {}", low.unwrap());
+            apx_eql(low.unwrap(), 0.5, 0.1);
+            assert!(high.unwrap() > 1000.0, "extrapolated high {}", high.unwrap());
+            apx_eql(high.unwrap(), 1000.5, 0.1);
+            pct_eql(middle.unwrap(), 500.0, 0.01);
+        });
+    }
+
+    #[pg_test]
+    fn test_json_includes_size() {
+        Spi::execute(|client| {
+            client.select("CREATE TABLE test (data DOUBLE PRECISION)", None, None);
+            client.select("INSERT INTO test SELECT generate_series(0.01, 100, 0.01)", None, None);
+            client.select("CREATE TABLE digests AS SELECT t_digest(37, data) FROM test", None, None);
+
+            let (buckets, text_buckets) = client
+                .select("SELECT (tdigest_to_json(t_digest)->>'buckets')::INT, \
+                        (t_digest::TEXT::JSON->>'buckets')::INT \
+                    FROM digests", None, None)
+                .first()
+                .get_two::<i32, i32>();
+            assert_eq!(buckets.unwrap(), 37);
+            assert_eq!(text_buckets.unwrap(), 37);
+
+            let (from_json, from_text, same) = client
+                .select("SELECT tdigest_compression(tdigest_from_json(tdigest_to_json(t_digest))), \
+                        tdigest_compression(t_digest::TEXT::TimescaleTDigest), \
+                        tdigest_from_json(tdigest_to_json(t_digest))::TEXT = t_digest::TEXT \
+                    FROM digests", None, None)
+                .first()
+                .get_three::<i32, i32, bool>();
+            assert_eq!(from_json.unwrap(), 37);
+            assert_eq!(from_text.unwrap(), 37);
+            assert!(same.unwrap());
+
+            // merging digests of different sizes keeps the larger size
+            let rolled_up = client
+                .select("SELECT tdigest_compression(rollup(d)) FROM ( \
+                        SELECT t_digest FROM digests UNION ALL SELECT t_digest(100, data) FROM test \
+                    ) digests(d)", None, None)
+                .first()
+                .get_one::<i32>();
+            assert_eq!(rolled_up.unwrap(), 100);
+        });
+
+        let json = r#"{"count":0,"sum":0.0,"min":null,"max":null,"mode":null,"means":[],"weights":[],"exact_sum":""}"#;
+        assert!(serde_json::from_str::<super::TimescaleTDigest<'static>>(json).is_err());
+    }
+
+    #[pg_test]
+    fn test_base64_round_trip() {
+        Spi::execute(|client| {
+            client.select("CREATE TABLE test (data DOUBLE PRECISION)", None, None);
+            client.select("INSERT INTO test SELECT generate_series(0.01, 100, 0.01)", None, None);
+            client.select("CREATE TABLE digests AS SELECT t_digest(100, data) FROM test", None, None);
+
+            let (same, smaller) = client
+                .select("SELECT tdigest_from_base64(tdigest_to_base64(t_digest))::TEXT = t_digest::TEXT, \
+                        length(tdigest_to_base64(t_digest)) < length(tdigest_to_json(t_digest)::TEXT) \
+                    FROM digests", None, None)
+                .first()
+                .get_two::<bool, bool>();
+            assert_eq!(same, Some(true));
+            assert_eq!(smaller, Some(true));
+        });
+
+        // digests stored by older builds keep their version
+        let encoded = base64::encode(&VERSION_1_IMAGE[4..]);
+        let digest = super::tdigest_from_base64(&encoded, std::ptr::null_mut());
+        assert_eq!(*digest.version, 1);
+        assert_eq!(super::tdigest_to_base64(digest, std::ptr::null_mut()), encoded);
+    }
+
+    #[pg_test(error = "unsupported TimescaleTDigest version 99, expected at most 5")]
+    fn test_base64_from_newer_version() {
+        let mut image = VERSION_1_IMAGE.to_vec();
+        image[4] = 99;
+        let encoded = base64::encode(&image[4..]);
+        super::tdigest_from_base64(&encoded, std::ptr::null_mut());
+    }
+
+    #[pg_test(error = "TD006: invalid TimescaleTDigest: bad JSON, centroid 1 has a smaller mean than the one before it")]
+    fn test_from_json_checks_centroids() {
+        Spi::execute(|client| {
+            client.select("SELECT tdigest_from_json('{\"buckets\":10,\"count\":2,\"sum\":3.0,\"min\":1.0,\
+                \"max\":2.0,\"mode\":null,\"means\":[2.0,1.0],\"weights\":[1,1],\"exact_sum\":\"\"}')", None, None);
+        });
+    }
+
+    #[pg_test]
+    fn test_centroids_iter() {
+        let mut state = super::TDigestTransState::new(100);
+        for i in 0..10_000 {
+            state.push((i % 977) as f64);
+        }
+        state.digest();
+        let digest = super::TimescaleTDigest::from_tdigest(&state.digested);
+
+        let total: u64 = digest.centroids_iter().map(|(_, weight)| weight).sum();
+        assert_eq!(total, *digest.count as u64);
+        assert!(digest.centroids_iter().count() <= 100);
+        assert!(digest.centroids_iter().zip(digest.centroids_iter().skip(1)).all(|((a, _), (b, _))| a <= b));
+
+        // unused slots at the end are skipped
+        let short = digest_from_parts(4, 6, 21.0, 1.0, 6.0, &[1.5, 3.5, 6.0, 0.0], &[2, 3, 1, 0]);
+        assert_eq!(short.centroids_iter().collect::<Vec<_>>(), vec![(1.5, 2), (3.5, 3), (6.0, 1)]);
+    }
+
+    #[pg_test]
+    fn test_json_aggregate() {
+        Spi::execute(|client| {
+            client.select("CREATE TABLE logs (payload jsonb)", None, None);
+            client.select("INSERT INTO logs SELECT jsonb_build_object('request', jsonb_build_object('latency', i)) \
+                FROM generate_series(1, 1000) i", None, None);
+            client.select("INSERT INTO logs VALUES ('{}'), ('{\"request\": {}}'), \
+                ('{\"request\": {\"latency\": \"slow\"}}'), ('{\"request\": 5}'), (NULL)", None, None);
+
+            client.select("CREATE TABLE digests AS SELECT t_digest_json(100, payload, 'request.latency') FROM logs", None, None);
+
+            let (count, min, max) = client
+                .select("SELECT tdigest_count(t_digest_json), tdigest_min(t_digest_json), tdigest_max(t_digest_json) \
+                    FROM digests", None, None)
+                .first()
+                .get_three::<f64, f64, f64>();
+            assert_eq!(count, Some(1000.0));
+            assert_eq!(min, Some(1.0));
+            assert_eq!(max, Some(1000.0));
+
+            // the same digest as extracting the numbers by hand
+            let same = client
+                .select("SELECT t_digest_json::TEXT = (SELECT t_digest(100, (payload->'request'->>'latency')::float8) \
+                        FROM logs WHERE jsonb_typeof(payload->'request'->'latency') = 'number')::TEXT \
+                    FROM digests", None, None)
+                .first()
+                .get_one::<bool>();
+            assert_eq!(same, Some(true));
+        });
+
+        let payload = serde_json::json!({"a": {"b": [1, 2.5]}, "c": "3"});
+        assert_eq!(super::json_path_number(&payload, "a.b.1"), Some(2.5));
+        assert_eq!(super::json_path_number(&payload, "a.b.2"), None);
+        assert_eq!(super::json_path_number(&payload, "a"), None);
+        assert_eq!(super::json_path_number(&payload, "c"), None);
+    }
+
+    // Pins how far tdigest_quantile may stray from the exact percentiles, so
+    // that changes to how digests are built, combined or estimated from can't
+    // quietly make them less accurate.
+    #[pg_test]
+    fn test_quantile_accuracy() {
+        Spi::execute(|client| {
+            client.select("CREATE TABLE test (data DOUBLE PRECISION)", None, None);
+            client.select("INSERT INTO test SELECT generate_series(0.01, 100, 0.01)", None, None);
+
+            for &(size, error) in &[(100, 0.05), (1000, 0.02)] {
+                for i in 1..100 {
+                    let quantile = i as f64 / 100.0;
+                    let (estimate, exact) = client
+                        .select(&format!("SELECT tdigest_quantile(t_digest({}, data), {}), \
+                                percentile_cont({}) WITHIN GROUP (ORDER BY data) \
+                            FROM test", size, quantile, quantile), None, None)
+                        .first()
+                        .get_two::<f64, f64>();
+                    let (estimate, exact) = (estimate.unwrap(), exact.unwrap());
+                    assert!((estimate - exact).abs() <= error * exact,
+                        "size {} estimated {} at quantile {}, the exact value is {}",
+                        size, estimate, quantile, exact);
+                }
+            }
+        });
+    }
+
+    #[pg_test]
+    fn test_entropy() {
+        Spi::execute(|client| {
+            client.select("CREATE TABLE test (uniform DOUBLE PRECISION, concentrated DOUBLE PRECISION)", None, None);
+            client.select("INSERT INTO test SELECT i, CASE WHEN i % 100 = 0 THEN i ELSE 50 + i % 3 END \
+                FROM generate_series(1, 10000) i", None, None);
+
+            let (uniform, concentrated, constant) = client
+                .select("SELECT tdigest_entropy(t_digest(100, uniform), 10), \
+                        tdigest_entropy(t_digest(100, concentrated), 10), \
+                        tdigest_entropy(t_digest(100, 7.0), 10) \
+                    FROM test", None, None)
+                .first()
+                .get_three::<f64, f64, f64>();
+            apx_eql(uniform.unwrap(), 10.0f64.ln(), 0.01);
+            assert!(concentrated.unwrap() < uniform.unwrap() / 2.0,
+                "concentrated entropy {} should be well below uniform entropy {}", concentrated.unwrap(), uniform.unwrap());
+            assert_eq!(constant, Some(0.0));
+        });
+    }
+
+    #[pg_test(error = "TD004: nbins must be at least 2, got 1")]
+    fn test_entropy_needs_two_bins() {
+        Spi::execute(|client| {
+            client.select("SELECT tdigest_entropy(t_digest(100, data), 1) FROM generate_series(1, 10) data", None, None);
+        });
+    }
+
+    #[pg_test]
+    fn test_trans_state_builder() {
+        let mut state = super::TDigestTransState::new(100);
+        for i in 1..=1000 {
+            state.push(i as f64);
+        }
+        let digest = state.finish();
+        assert_eq!(*digest.count, 1000);
+        assert_eq!(*digest.buckets, 100);
+        assert_eq!(digest.ends(), Some((1.0, 1000.0)));
+        pct_eql(digest.centroid_slices().estimate_quantile(0.5), 500.0, 0.01);
+
+        // the state keeps going after finishing
+        state.push(1001.0);
+        assert_eq!(*state.finish().count, 1001);
+
+        // the same digest as t_digest builds
+        let encoded = super::tdigest_to_base64(digest, std::ptr::null_mut());
+        Spi::execute(|client| {
+            let built = client
+                .select("SELECT tdigest_to_base64(t_digest(100, i)) FROM generate_series(1, 1000) i", None, None)
+                .first()
+                .get_one::<String>();
+            assert_eq!(built, Some(encoded));
+        });
+    }
+
+    #[pg_test]
+    fn test_merge_strategy() {
+        Spi::execute(|client| {
+            client.select("CREATE TABLE partials AS SELECT i, t_digest(100, (((j * 7919 + i * 131) % 1000) + i * 100)::float8) \
+                FROM generate_series(0, 9) i, generate_series(0, 999) j GROUP BY i", None, None);
+
+            let rollups = "SELECT tdigest_to_csv(rollup(t_digest ORDER BY i)), tdigest_to_csv(rollup(t_digest ORDER BY i DESC)), \
+                    tdigest_quantile(rollup(t_digest ORDER BY i), 0.3) / tdigest_quantile(rollup(t_digest ORDER BY i DESC), 0.3) \
+                FROM partials";
+
+            // merging one at a time depends a little on the order
+            let (_, _, ratio) = client.select(rollups, None, None)
+                .first()
+                .get_three::<String, String, f64>();
+            apx_eql(ratio.unwrap(), 1.0, 0.01);
+
+            client.select("SET timescale_analytics.tdigest_merge_strategy = 'stable'", None, None);
+            let (forward, backward, ratio) = client.select(rollups, None, None)
+                .first()
+                .get_three::<String, String, f64>();
+            assert_eq!(forward, backward);
+            assert_eq!(ratio, Some(1.0));
+
+            // the first and last values still follow the input order
+            let (first, last) = client
+                .select("SELECT tdigest_first(rollup(t_digest ORDER BY i)), tdigest_last(rollup(t_digest ORDER BY i)) \
+                    FROM partials", None, None)
+                .first()
+                .get_two::<f64, f64>();
+            assert_eq!(first, Some(0.0));
+            assert_eq!(last, Some(((999 * 7919 + 9 * 131) % 1000 + 900) as f64));
+
+            let (count, sum) = client
+                .select("SELECT tdigest_count(rollup(t_digest)), tdigest_sum(rollup(t_digest)) FROM partials", None, None)
+                .first()
+                .get_two::<f64, f64>();
+            assert_eq!(count, Some(10000.0));
+            assert_eq!(sum, Some((0..10).map(|i| 499500.0 + i as f64 * 100000.0).sum::<f64>()));
+            client.select("RESET timescale_analytics.tdigest_merge_strategy", None, None);
+        });
+    }
+
+    #[pg_test(error = "TD004: timescale_analytics.tdigest_merge_strategy must be fast or stable, got slow")]
+    fn test_merge_strategy_unknown() {
+        Spi::execute(|client| {
+            client.select("SET timescale_analytics.tdigest_merge_strategy = 'slow'", None, None);
+            client.select("SELECT rollup(t_digest) FROM (SELECT t_digest(100, 1.0::float8)) s", None, None);
+        });
+    }
+
+    #[pg_test]
+    fn test_exact_quantile() {
+        Spi::execute(|client| {
+            client.select("CREATE TABLE test (data DOUBLE PRECISION)", None, None);
+            client.select("INSERT INTO test VALUES (3), (1), (4), (1), (5), (9), (2), (6), (5), (3)", None, None);
+
+            for i in 0..=20 {
+                let quantile = i as f64 / 20.0;
+                let (exact, disc) = client
+                    .select(&format!("SELECT tdigest_exact_quantile(t_digest(100, data), {}), \
+                            percentile_disc({}) WITHIN GROUP (ORDER BY data) \
+                        FROM test", quantile, quantile), None, None)
+                    .first()
+                    .get_two::<f64, f64>();
+                assert_eq!(exact, disc, "at quantile {}", quantile);
+            }
+
+            // interpolated estimates generally aren't observed values
+            let interpolated = client
+                .select("SELECT tdigest_quantile(t_digest(100, data), 0.3) FROM test", None, None)
+                .first()
+                .get_one::<f64>();
+            assert_eq!(interpolated, Some(2.75));
+        });
+    }
+
+    #[pg_test(error = "TD004: tdigest_exact_quantile requires an exact digest, this one has merged some of its 1000 values")]
+    fn test_exact_quantile_of_approximation() {
+        Spi::execute(|client| {
+            client.select("SELECT tdigest_exact_quantile(t_digest(100, data), 0.5) FROM generate_series(1, 1000) data", None, None);
+        });
+    }
+
+    #[pg_test]
+    fn test_deserialize_array() {
+        Spi::execute(|client| {
+            client.select("CREATE TABLE digests AS SELECT i, t_digest(100, data * i) \
+                FROM generate_series(1, 5) i, generate_series(1, 1000) data GROUP BY i", None, None);
+            client.select("CREATE TABLE serialized AS SELECT \
+                    array_agg(decode(tdigest_to_base64(t_digest), 'base64') ORDER BY i) || NULL::bytea AS bytes \
+                FROM digests", None, None);
+
+            let (same, nulls) = client
+                .select("SELECT bool_and(d.t_digest::TEXT = (tdigest_deserialize_array(bytes))[d.i]::TEXT), \
+                        bool_and((tdigest_deserialize_array(bytes))[6] IS NULL) \
+                    FROM digests d, serialized", None, None)
+                .first()
+                .get_two::<bool, bool>();
+            assert_eq!(same, Some(true));
+            assert_eq!(nulls, Some(true));
+
+            let length = client
+                .select("SELECT array_length(tdigest_deserialize_array(bytes), 1) FROM serialized", None, None)
+                .first()
+                .get_one::<i32>();
+            assert_eq!(length, Some(6));
+        });
+    }
+
+    #[pg_test(error = "TD006: invalid TimescaleTDigest: element 2, 3 trailing bytes")]
+    fn test_deserialize_array_checks_elements() {
+        Spi::execute(|client| {
+            client.select("SELECT tdigest_deserialize_array(ARRAY[decode(tdigest_to_base64(d), 'base64'), \
+                    decode(tdigest_to_base64(d), 'base64') || '\\x000000'::bytea]) \
+                FROM (SELECT t_digest(100, data) d FROM generate_series(1, 10) data) s", None, None);
+        });
+    }
+
+    #[pg_test]
+    fn test_log_histogram() {
+        Spi::execute(|client| {
+            client.select("CREATE TABLE test (data DOUBLE PRECISION)", None, None);
+            client.select("INSERT INTO test SELECT -ln(1 - i / 10001.0) * 100 FROM generate_series(1, 10000) i", None, None);
+            client.select("CREATE TABLE buckets AS SELECT h.* \
+                FROM (SELECT t_digest(100, data) FROM test) s, tdigest_log_histogram(t_digest, 10) h", None, None);
+
+            let (buckets, low, high, total) = (
+                client.select("SELECT count(*) FROM buckets", None, None).first().get_one::<i64>(),
+                client.select("SELECT min(bucket_low) FROM buckets", None, None).first().get_one::<f64>(),
+                client.select("SELECT max(bucket_high) FROM buckets", None, None).first().get_one::<f64>(),
+                client.select("SELECT sum(count)::BIGINT FROM buckets", None, None).first().get_one::<i64>(),
+            );
+            // the min is just under 0.01 and the max just over 900
+            assert_eq!(buckets, Some(6));
+            apx_eql(low.unwrap(), 0.001, 1e-12);
+            apx_eql(high.unwrap(), 1000.0, 1e-9);
+            assert_eq!(total, Some(10000));
+
+            let worst = client
+                .select("SELECT max(abs(count - (SELECT count(*) FROM test WHERE data >= bucket_low AND data < bucket_high))) \
+                    FROM buckets", None, None)
+                .first()
+                .get_one::<i64>();
+            assert!(worst.unwrap() <= 20, "bucket counts off by up to {}", worst.unwrap());
+        });
+    }
+
+    #[pg_test(error = "TD004: tdigest_log_histogram requires positive values, got a minimum of 0")]
+    fn test_log_histogram_needs_positive_values() {
+        Spi::execute(|client| {
+            client.select("SELECT tdigest_log_histogram(t_digest(100, data), 10) FROM generate_series(0, 10) data", None, None);
+        });
+    }
+
+    // Finalizing a large digest only copies its centroids into the flat
+    // layout: the result holds nothing but the fields and one slot per
+    // bucket.
+    #[pg_test]
+    fn test_large_final() {
+        let mut state = super::TDigestTransState::new(50_000);
+        for i in 0..1_000_000i64 {
+            state.push(((i * 7919) % 1_000_000) as f64);
+        }
+        state.digest();
+        let centroids = state.digested.raw_centroids().len();
+
+        let digest = state.finish();
+        // 88 bytes of fields, then a mean and a weight per bucket
+        assert_eq!(digest.pg_bytes().len(), 88 + 12 * 50_000);
+        assert_eq!(digest.centroids_iter().count(), centroids);
+        assert_eq!(*digest.count, 1_000_000);
+    }
+
+    #[pg_test]
+    fn test_merged_quantile() {
+        Spi::execute(|client| {
+            client.select("CREATE TABLE regions AS SELECT region, t_digest(CASE WHEN region = 1 THEN 200 ELSE 100 END, latency) \
+                FROM (SELECT i % 3 AS region, (i % 3 + 1) * (i % 1009)::float8 AS latency FROM generate_series(1, 30000) i) s \
+                GROUP BY region", None, None);
+
+            for &quantile in &[0.01, 0.5, 0.9, 0.99] {
+                let (merged, rolled_up) = client
+                    .select(&format!("SELECT tdigest_merged_quantile(array_agg(t_digest) || NULL::TimescaleTDigest, {}), \
+                            tdigest_quantile(rollup(t_digest), {}) \
+                        FROM regions", quantile, quantile), None, None)
+                    .first()
+                    .get_two::<f64, f64>();
+                // merging all at once compresses differently from one at a
+                // time, which shows most in the tails
+                pct_eql(merged.unwrap(), rolled_up.unwrap(), 0.05);
+            }
+
+            let (empty, nulls, null_array) = client
+                .select("SELECT tdigest_merged_quantile('{}', 0.5), \
+                        tdigest_merged_quantile(ARRAY[NULL, NULL]::TimescaleTDigest[], 0.5), \
+                        tdigest_merged_quantile(NULL, 0.5)", None, None)
+                .first()
+                .get_three::<f64, f64, f64>();
+            assert_eq!((empty, nulls, null_array), (None, None, None));
+        });
+    }
+
+    #[pg_test]
+    fn test_ranged_aggregate() {
+        Spi::execute(|client| {
+            client.select("SET TIME ZONE 'UTC'", None, None);
+            client.select("CREATE TABLE readings (time timestamptz, day int, value DOUBLE PRECISION)", None, None);
+            client.select("INSERT INTO readings SELECT '2021-03-01 00:00:00+00'::timestamptz + i * interval '1 minute', i / 1440, i % 97 \
+                FROM generate_series(0, 4319) i", None, None);
+            client.select("INSERT INTO readings VALUES ('2021-02-01 00:00:00+00', 0, NULL), (NULL, 1, 5)", None, None);
+            client.select("CREATE TABLE daily AS SELECT day, t_digest_ranged(100, time, value) FROM readings GROUP BY day", None, None);
+
+            // NULL values don't widen the range, NULL timestamps don't either
+            let (range, count) = client
+                .select("SELECT tdigest_range(t_digest_ranged)::text, tdigest_count(t_digest_ranged) FROM daily WHERE day = 0", None, None)
+                .first()
+                .get_two::<String, f64>();
+            assert_eq!(range.as_deref(), Some("[\"2021-03-01 00:00:00+00\",\"2021-03-01 23:59:00+00\"]"));
+            assert_eq!(count, Some(1440.0));
+            let count = client
+                .select("SELECT tdigest_count(t_digest_ranged) FROM daily WHERE day = 1", None, None)
+                .first()
+                .get_one::<f64>();
+            assert_eq!(count, Some(1441.0));
+
+            // rollups cover the union of the ranges, and so does the aggregate run over everything
+            let (rolled_up, direct) = client
+                .select("SELECT tdigest_range(rollup(t_digest_ranged ORDER BY day DESC)) = \
+                        (SELECT tstzrange(min(time), max(time), '[]') FROM readings WHERE value IS NOT NULL), \
+                        (SELECT tdigest_range(t_digest_ranged(100, time, value)) FROM readings) = \
+                        (SELECT tstzrange(min(time), max(time), '[]') FROM readings WHERE value IS NOT NULL) \
+                    FROM daily", None, None)
+                .first()
+                .get_two::<bool, bool>();
+            assert_eq!((rolled_up, direct), (Some(true), Some(true)));
+
+            // the range survives a round trip through JSON
+            let same = client
+                .select("SELECT tdigest_range(tdigest_from_json(tdigest_to_json(t_digest_ranged))) = tdigest_range(t_digest_ranged) \
+                    FROM daily WHERE day = 2", None, None)
+                .first()
+                .get_one::<bool>();
+            assert_eq!(same, Some(true));
+
+            // digests built without timestamps don't have a range
+            let range = client
+                .select("SELECT tdigest_range(t_digest(100, value))::text FROM readings", None, None)
+                .first()
+                .get_one::<String>();
+            assert_eq!(range, None);
+        });
+    }
+
+    #[pg_test]
+    fn test_quantile_ends() {
+        Spi::execute(|client| {
+            client.select("CREATE TABLE test (data DOUBLE PRECISION)", None, None);
+            client.select("INSERT INTO test SELECT sin(i) * 1000 FROM generate_series(1, 10000) i", None, None);
+            client.select("CREATE TABLE digest AS SELECT t_digest(50, data) FROM test", None, None);
+
+            let (low, min) = client
+                .select("SELECT tdigest_quantile(t_digest, 0.0), tdigest_min(t_digest) FROM digest", None, None)
+                .first()
+                .get_two::<f64, f64>();
+            assert_eq!(low, min);
+            let (high, max) = client
+                .select("SELECT tdigest_quantile(t_digest, 1.0), tdigest_max(t_digest) FROM digest", None, None)
+                .first()
+                .get_two::<f64, f64>();
+            assert_eq!(high, max);
+
+            let (exact_min, exact_max) = client
+                .select("SELECT min(data), max(data) FROM test", None, None)
+                .first()
+                .get_two::<f64, f64>();
+            assert_eq!((low, high), (exact_min, exact_max));
+        });
+    }
+
+    #[pg_test]
+    fn test_lazy_rollup() {
+        Spi::execute(|client| {
+            client.select("CREATE TABLE hourly AS SELECT i % 24 AS hour, t_digest(100, (i % 24 + 1) * (sin(i) + 2)::float8) \
+                FROM generate_series(1, 48000) i GROUP BY hour", None, None);
+            client.select("CREATE TABLE daily AS SELECT rollup(t_digest) AS eager, lazy_rollup(t_digest) AS lazy FROM hourly", None, None);
+
+            let (components, count) = client
+                .select("SELECT tdigest_lazy_components(lazy), tdigest_count(tdigest_lazy_merge(lazy)) FROM daily", None, None)
+                .first()
+                .get_two::<i64, f64>();
+            assert_eq!((components, count), (Some(24), Some(48000.0)));
+
+            for &quantile in &[0.01, 0.1, 0.5, 0.9, 0.99] {
+                let (lazy, eager, resized) = client
+                    .select(&format!("SELECT tdigest_quantile(lazy, {}), tdigest_quantile(eager, {}), \
+                            tdigest_quantile(tdigest_lazy_merge(lazy, 2400), {}) \
+                        FROM daily", quantile, quantile, quantile), None, None)
+                    .first()
+                    .get_three::<f64, f64, f64>();
+                pct_eql(lazy.unwrap(), eager.unwrap(), 0.02);
+                pct_eql(resized.unwrap(), eager.unwrap(), 0.02);
+            }
+
+            // merging at a larger size keeps the components' centroids
+            let (size, centroids) = client
+                .select("SELECT tdigest_compression(tdigest_lazy_merge(lazy, 2400)), \
+                        (SELECT count(*) FROM tdigest_centroids(tdigest_lazy_merge(lazy, 2400))) > 100 \
+                    FROM daily", None, None)
+                .first()
+                .get_two::<i32, bool>();
+            assert_eq!((size, centroids), (Some(2400), Some(true)));
+
+            // the text format round trips
+            let same = client
+                .select("SELECT tdigest_lazy_merge(lazy::text::TimescaleTDigestLazyRollup)::text = tdigest_lazy_merge(lazy)::text FROM daily", None, None)
+                .first()
+                .get_one::<bool>();
+            assert_eq!(same, Some(true));
+        });
+    }
+
+    #[pg_test]
+    fn test_sla() {
+        Spi::execute(|client| {
+            client.select("CREATE TABLE digest AS SELECT t_digest(100, i * i) FROM generate_series(1, 10000) i", None, None);
+
+            let quantiles = [0.5, 0.9, 0.95, 0.99, 0.999];
+            for (field, quantile) in ["p50", "p90", "p95", "p99", "p999"].iter().zip(&quantiles) {
+                let (sla, single) = client
+                    .select(&format!("SELECT (tdigest_sla(t_digest)).{}, tdigest_quantile(t_digest, {}) FROM digest", field, quantile), None, None)
+                    .first()
+                    .get_two::<f64, f64>();
+                assert!(sla.is_some());
+                assert_eq!(sla, single, "{}", field);
+            }
+
+            let empty = client
+                .select("SELECT (tdigest_sla(t_digest(100, NULL::float8))).p99", None, None)
+                .first()
+                .get_one::<f64>();
+            assert_eq!(empty, None);
+        });
+    }
+
+    #[pg_test]
+    fn test_nan_skipped() {
+        Spi::execute(|client| {
+            client.select("CREATE TABLE test (data DOUBLE PRECISION)", None, None);
+            client.select("INSERT INTO test SELECT CASE WHEN i % 7 = 0 THEN 'NaN' ELSE i END FROM generate_series(1, 1000) i", None, None);
+
+            // NaN is dropped like NULL, leaving the same digest as without it
+            let (same, count, max) = client
+                .select("SELECT t_digest(100, data)::text = (t_digest(100, data) FILTER (WHERE data <> 'NaN'))::text, \
+                        tdigest_count(t_digest(100, data)), tdigest_max(t_digest(100, data)) \
+                    FROM test", None, None)
+                .first()
+                .get_three::<bool, f64, f64>();
+            assert_eq!((same, count, max), (Some(true), Some(858.0), Some(1000.0)));
+
+            // and so by the aggregates that keep more than the digest
+            let (mode, numeric, distinct) = client
+                .select("SELECT tdigest_count(t_digest_with_exact_mode(100, data)), \
+                        tdigest_numeric_sum(t_digest(100, data::numeric)) = (SELECT sum(data::numeric) FROM test WHERE data <> 'NaN'), \
+                        tdigest_count(t_digest_distinct(100, data)) \
+                    FROM test", None, None)
+                .first()
+                .get_three::<f64, bool, f64>();
+            assert_eq!((mode, numeric, distinct), (Some(858.0), Some(true), Some(858.0)));
+
+            // only NaN gives an empty digest
+            let (count, max) = client
+                .select("SELECT tdigest_count(t_digest(100, data)), tdigest_max(t_digest(100, data)) FROM test WHERE data = 'NaN'", None, None)
+                .first()
+                .get_two::<f64, f64>();
+            assert_eq!((count, max), (Some(0.0), None));
+        });
+    }
+
+    #[pg_test]
+    fn test_emd() {
+        Spi::execute(|client| {
+            client.select("CREATE TABLE digests AS SELECT t_digest(100, i) AS a, t_digest(100, i + 500) AS b, t_digest(100, -i) AS c \
+                FROM generate_series(1, 10000) i", None, None);
+
+            let (same, shifted, mirrored) = client
+                .select("SELECT tdigest_emd(a, a), tdigest_emd(a, b), tdigest_emd(a, c, 1000) FROM digests", None, None)
+                .first()
+                .get_three::<f64, f64, f64>();
+            assert_eq!(same, Some(0.0));
+            pct_eql(shifted.unwrap(), 500.0, 0.01);
+            // values 1..10000 against -10000..-1 have to move 10001 on average
+            pct_eql(mirrored.unwrap(), 10001.0, 0.01);
+
+            let empty = client
+                .select("SELECT tdigest_emd(a, (SELECT t_digest(100, 'NaN'::float8))) FROM digests", None, None)
+                .first()
+                .get_one::<f64>();
+            assert_eq!(empty, None);
+        });
+    }
+
+    #[pg_test]
+    fn test_gridded() {
+        Spi::execute(|client| {
+            client.select("CREATE TABLE digests AS SELECT t_digest_gridded(100, i * i) AS gridded, t_digest(100, i * i) AS plain \
+                FROM generate_series(1, 10000) i", None, None);
+
+            // the grid holds the digest's own estimates, so in between them
+            // only the interpolation differs, by far less than 0.1% of the range
+            for i in 1..1000 {
+                let quantile = i as f64 / 1000.0;
+                let (gridded, plain) = client
+                    .select(&format!("SELECT tdigest_quantile(gridded, {}), tdigest_quantile(plain, {}) FROM digests",
+                        quantile, quantile), None, None)
+                    .first()
+                    .get_two::<f64, f64>();
+                apx_eql(gridded.unwrap(), plain.unwrap(), 1e5);
+            }
+
+            let (same, count, text) = client
+                .select("SELECT gridded::TimescaleTDigest::text = plain::text, \
+                        tdigest_count(gridded::TimescaleTDigest), \
+                        tdigest_quantile(gridded::text::TimescaleTDigestGridded, 0.37) = tdigest_quantile(tdigest_gridded(plain), 0.37) \
+                    FROM digests", None, None)
+                .first()
+                .get_three::<bool, f64, bool>();
+            assert_eq!((same, count, text), (Some(true), Some(10000.0), Some(true)));
+
+            let empty = client
+                .select("SELECT tdigest_quantile(t_digest_gridded(100, 'NaN'::float8), 0.5)", None, None)
+                .first()
+                .get_one::<f64>();
+            assert_eq!(empty, None);
+        });
+    }
+
+    #[pg_test]
+    fn test_rollup_bytea() {
+        Spi::execute(|client| {
+            client.select("CREATE TABLE digests AS SELECT i % 10 AS bucket, t_digest(100, i) FROM generate_series(1, 10000) i GROUP BY bucket", None, None);
+            client.select("CREATE TABLE stored (serialized bytea)", None, None);
+            client.select("INSERT INTO stored SELECT decode(tdigest_to_base64(t_digest), 'base64') FROM digests", None, None);
+            client.select("INSERT INTO stored VALUES (NULL)", None, None);
+
+            let (same, count) = client
+                .select("SELECT rollup(serialized)::text = (SELECT rollup(t_digest)::text FROM digests), \
+                        tdigest_count(rollup(serialized)) \
+                    FROM stored", None, None)
+                .first()
+                .get_two::<bool, f64>();
+            assert_eq!((same, count), (Some(true), Some(10000.0)));
+        });
+    }
+
+    #[pg_test(error = "TD006: invalid TimescaleTDigest: 3 trailing bytes")]
+    fn test_rollup_bytea_corrupt() {
+        Spi::execute(|client| {
+            client.select("SELECT rollup(decode(tdigest_to_base64(d), 'base64') || '\\x000000'::bytea) \
+                FROM (SELECT t_digest(100, data) d FROM generate_series(1, 10) data) s", None, None);
+        });
+    }
+
+    #[pg_test]
+    fn test_to_tdigest_large() {
+        let values: Vec<f64> = (0..100_000).map(|i| ((i * 7919) % 100_000) as f64).collect();
+        let digested = super::TDigest::new_with_size(1000).merge_unsorted(values);
+        let digest = super::TimescaleTDigest::from_tdigest(&digested);
+        let round_trip = digest.to_tdigest();
+        assert_eq!(round_trip, digested);
+        assert!(round_trip.raw_centroids().len() > 500);
+    }
+
+    #[pg_test]
+    fn test_percentile_str() {
+        Spi::execute(|client| {
+            client.select("CREATE TABLE digest AS SELECT t_digest(100, i) FROM generate_series(1, 10000) i", None, None);
+
+            for &(pct, quantile) in &[("50%", 0.5), ("99.9%", 0.999), (" 25 % ", 0.25), ("75", 0.75), ("100%", 1.0)] {
+                let (text, typed) = client
+                    .select(&format!("SELECT tdigest_percentile_str(t_digest, '{}'), tdigest_quantile(t_digest, {}) FROM digest",
+                        pct, quantile), None, None)
+                    .first()
+                    .get_two::<f64, f64>();
+                // e.g. 99.9 / 100 needn't be the same double as 0.999
+                apx_eql(text.unwrap(), typed.unwrap(), 1e-6);
+            }
+        });
+    }
+
+    #[pg_test(error = "TD004: invalid percentile \"p99\", expected a number like '99.9%'")]
+    fn test_percentile_str_invalid() {
+        Spi::execute(|client| {
+            client.select("SELECT tdigest_percentile_str(t_digest(100, i), 'p99') FROM generate_series(1, 10) i", None, None);
+        });
+    }
+
+    #[pg_test(error = "TD004: percentile must be between 0 and 100, got 150")]
+    fn test_percentile_str_out_of_range() {
+        Spi::execute(|client| {
+            client.select("SELECT tdigest_percentile_str(t_digest(100, i), '150%') FROM generate_series(1, 10) i", None, None);
+        });
+    }
+
+    #[pg_test]
+    fn test_memory_footprint() {
+        Spi::execute(|client| {
+            let (small, large) = client
+                .select("SELECT \
+                        tdigest_memory_footprint((SELECT t_digest(100, data) FROM generate_series(1, 50) data)), \
+                        tdigest_memory_footprint((SELECT t_digest(100, data) FROM generate_series(1, 10000) data))", None, None)
+                .first()
+                .get_two::<i64, i64>();
+            // a TDigest is a Vec, its size, and four doubles, and a centroid
+            // two doubles
+            assert_eq!(small, Some(24 + 8 + 4 * 8 + 50 * 16));
+            assert_eq!(large, Some(24 + 8 + 4 * 8 + 100 * 16));
+        });
+    }
+
+    #[pg_test]
+    fn test_ordered_aggregate() {
+        Spi::execute(|client| {
+            // inserted out of order, so that only the ORDER BY puts them in it
+            client.select("CREATE TABLE test (data DOUBLE PRECISION, ts TIMESTAMPTZ)", None, None);
+            client.select("INSERT INTO test SELECT i, '2020-01-01'::timestamptz + (i * 7919 % 10000) * '1 second'::interval \
+                    FROM generate_series(0, 9999) i", None, None);
+            client.select("CREATE VIEW digest AS SELECT t_digest_ordered(100, data ORDER BY ts) FROM test", None, None);
+
+            let (first, last) = client
+                .select("SELECT tdigest_first(t_digest_ordered), tdigest_last(t_digest_ordered) FROM digest", None, None)
+                .first()
+                .get_two::<f64, f64>();
+            let (earliest, latest) = client
+                .select("SELECT (SELECT data FROM test ORDER BY ts LIMIT 1), (SELECT data FROM test ORDER BY ts DESC LIMIT 1)", None, None)
+                .first()
+                .get_two::<f64, f64>();
+            assert_eq!((first, last), (earliest, latest));
+            assert_eq!((first, last), (Some(0.0), Some(2321.0)));
+
+            // otherwise it's the same digest as t_digest's
+            let same = client
+                .select("SELECT tdigest_to_json(t_digest_ordered) = \
+                        (SELECT tdigest_to_json(t_digest(100, data ORDER BY ts)) FROM test) FROM digest", None, None)
+                .first()
+                .get_one::<bool>();
+            assert_eq!(same, Some(true));
+        });
+    }
+
+    #[pg_test]
+    fn test_cliffs() {
+        Spi::execute(|client| {
+            // few enough values that each is a centroid of its own
+            let (cliffs, none) = client
+                .select("SELECT tdigest_cliffs(t_digest, 100), tdigest_cliffs(t_digest, 1000) FROM ( \
+                        SELECT t_digest(100, CASE WHEN i <= 60 THEN i ELSE 1000 + i END) FROM generate_series(1, 100) i) d", None, None)
+                .first()
+                .get_two::<Vec<f64>, Vec<f64>>();
+            assert_eq!(cliffs, Some(vec![0.6]));
+            assert_eq!(none, Some(vec![]));
+
+            // two plateaus, which the centroid straddling them may split into
+            // two cliffs, both close to the step
+            let cliffs = client
+                .select("SELECT tdigest_cliffs(t_digest(100, CASE WHEN i % 10 < 6 THEN 10 ELSE 1000 END + i % 7 * 0.1), 100) \
+                        FROM generate_series(1, 10000) i", None, None)
+                .first()
+                .get_one::<Vec<f64>>()
+                .unwrap();
+            assert!(!cliffs.is_empty());
+            for cliff in cliffs {
+                assert!((0.55..0.65).contains(&cliff), "{}", cliff);
+            }
+
+            let empty = client
+                .select("SELECT tdigest_cliffs(t_digest(100, 'NaN'::float8), 1)", None, None)
+                .first()
+                .get_one::<Vec<f64>>();
+            assert_eq!(empty, None);
+        });
+    }
+
+    #[pg_test]
+    fn test_combine_branches() {
+        assert!(super::combine(None, None).is_none());
+        assert!(super::combine_into(None, None).is_none());
+
+        // states of different sizes, each with values still buffered
+        let mut small = super::TDigestTransState::new(100);
+        for i in 0..1050 {
+            small.push(i as f64);
+        }
+        let mut large = super::TDigestTransState::new(200);
+        for i in 1050..3000 {
+            large.push(i as f64);
+        }
+
+        for &(state1, state2) in &[(&small, &large), (&large, &small)] {
+            let mut combined = super::combine(Some(state1), Some(state2)).unwrap();
+            combined.digest();
+            let digest = &combined.digested;
+            assert_eq!(digest.max_size(), 200);
+            apx_eql(digest.count(), 3000.0, 0.000001);
+            pct_eql(digest.sum(), 2999.0 * 3000.0 / 2.0, 0.000001);
+            assert_eq!((digest.min(), digest.max()), (0.0, 2999.0));
+            pct_eql(digest.estimate_quantile(0.5), 1500.0, 0.01);
+            pct_eql(digest.estimate_quantile(0.9), 2700.0, 0.01);
+            assert_eq!(combined.ends(), Some((state1.first.unwrap(), state2.last)));
+        }
+
+        // a missing side leaves the other as it was, buffer included
+        for combined in vec![super::combine(Some(&small), None), super::combine(None, Some(&small))] {
+            let mut combined = combined.unwrap();
+            combined.digest();
+            apx_eql(combined.digested.count(), 1050.0, 0.000001);
+            pct_eql(combined.digested.sum(), 1049.0 * 1050.0 / 2.0, 0.000001);
+        }
+    }
+
+    #[pg_test]
+    fn test_flush_mode() {
+        Spi::execute(|client| {
+            client.select("CREATE TABLE test (data DOUBLE PRECISION)", None, None);
+            client.select("INSERT INTO test SELECT (i * 7919 % 10000) * 0.01 + 0.01 FROM generate_series(0, 9999) i", None, None);
+            let error = "SELECT avg(abs(tdigest_quantile(d, q) - exact)) \
+                FROM (SELECT t_digest(100, data) d FROM test) s, \
+                    (SELECT q, percentile_cont(q) WITHIN GROUP (ORDER BY data) exact \
+                    FROM test, generate_series(0.01, 0.99, 0.01) q GROUP BY q) e";
+
+            let eager = client.select(error, None, None).first().get_one::<f64>().unwrap();
+            client.select("SET timescale_analytics.tdigest_flush_mode = 'final_only'", None, None);
+            let final_only = client.select(error, None, None).first().get_one::<f64>().unwrap();
+            assert!(final_only < eager / 2.0, "{} {}", final_only, eager);
+            assert!(final_only < 0.01, "{}", final_only);
+
+            // the values stay buffered until the digest is needed
+            let mut state = super::TDigestTransState::new(100);
+            for i in 0..1000 {
+                state.push(i as f64);
+            }
+            assert_eq!(state.buffered, 1000);
+            assert_eq!(state.digested.count(), 0.0);
+            state.digest();
+            apx_eql(state.digested.count(), 1000.0, 0.000001);
+            client.select("RESET timescale_analytics.tdigest_flush_mode", None, None);
+        });
+    }
+
+    #[pg_test(error = "TD004: timescale_analytics.tdigest_flush_mode must be eager or final_only, got lazy")]
+    fn test_flush_mode_unknown() {
+        Spi::execute(|client| {
+            client.select("SET timescale_analytics.tdigest_flush_mode = 'lazy'", None, None);
+            client.select("SELECT t_digest(100, i) FROM generate_series(1, 1000) i", None, None);
+        });
+    }
+
+    #[pg_test]
+    fn test_zscore() {
+        Spi::execute(|client| {
+            client.select("CREATE TABLE test (data DOUBLE PRECISION)", None, None);
+            client.select("INSERT INTO test SELECT generate_series(1, 10000)", None, None);
+            client.select("CREATE VIEW digest AS SELECT t_digest(100, data) FROM test", None, None);
+
+            let (at_mean, above, below) = client
+                .select("SELECT tdigest_zscore(t_digest, 5000.5), \
+                        tdigest_zscore(t_digest, 5000.5 + (SELECT stddev(data) FROM test)), \
+                        tdigest_zscore(t_digest, 5000.5 - 2 * (SELECT stddev(data) FROM test)) \
+                    FROM digest", None, None)
+                .first()
+                .get_three::<f64, f64, f64>();
+            assert_eq!(at_mean, Some(0.0));
+            pct_eql(above.unwrap(), 1.0, 0.01);
+            apx_eql(below.unwrap(), -2.0, 0.02);
+
+            let (single, constant) = client
+                .select("SELECT tdigest_zscore((SELECT t_digest(100, 1.0::float8)), 1), \
+                        tdigest_zscore((SELECT t_digest(100, 5.0::float8) FROM generate_series(1, 10)), 1)", None, None)
+                .first()
+                .get_two::<f64, f64>();
+            assert_eq!((single, constant), (None, None));
+        });
+    }
+
+    #[pg_test]
+    fn test_rollup_in_place() {
+        let digests: Vec<super::TimescaleTDigest<'static>> = (0..2000)
+            .map(|i| {
+                let mut state = super::TDigestTransState::new(100);
+                for j in 0..50 {
+                    state.push(((i * 50 + j) * 7919 % 100000) as f64);
+                }
+                state.finish()
+            })
+            .collect();
+        // a larger digest part way through, which the rest is folded into
+        let mut large = super::TDigestTransState::new(200);
+        large.push(-1.0);
+        let large = large.finish();
+
+        let mut rollup: Option<super::TDigestRollupTransState> = None;
+        let mut expected: Option<super::TDigestTransState> = None;
+        for (i, digest) in digests.iter().enumerate() {
+            let digest = if i == 1000 { &large } else { digest };
+            let incoming = super::TDigestRollupTransState::new(digest, super::MergeStrategy::Fast);
+            expected = super::combine(expected.as_ref(), Some(&incoming.digest_state));
+            match &mut rollup {
+                None => rollup = Some(incoming),
+                Some(rollup) => rollup.add(&incoming),
+            }
+        }
+
+        // the same as copying at every step, ends and all
+        let rollup = rollup.unwrap().digest_state;
+        let expected = expected.unwrap();
+        assert_eq!(rollup.digested, expected.digested);
+        assert_eq!(rollup.ends(), expected.ends());
+        assert_eq!(rollup.digested.max_size(), 200);
+        apx_eql(rollup.digested.count(), 1999.0 * 50.0 + 1.0, 0.000001);
+        assert_eq!((rollup.digested.min(), rollup.digested.max()), (-1.0, 99999.0));
+        pct_eql(rollup.digested.estimate_quantile(0.5), 50000.0, 0.02);
+    }
+
+    #[pg_test]
+    fn test_sparkline() {
+        Spi::execute(|client| {
+            client.select("CREATE TABLE test (data DOUBLE PRECISION)", None, None);
+            client.select("INSERT INTO test SELECT i * i FROM generate_series(1, 10000) i", None, None);
+            client.select("CREATE VIEW digest AS SELECT t_digest(100, data) FROM test", None, None);
+
+            for &width in &[1, 20, 80] {
+                let (sparkline, length) = client
+                    .select(&format!("SELECT tdigest_sparkline(t_digest, {0}), length(tdigest_sparkline(t_digest, {0})) FROM digest", width),
+                        None, None)
+                    .first()
+                    .get_two::<String, i32>();
+                let sparkline = sparkline.unwrap();
+                assert_eq!(length, Some(width));
+                assert_eq!(sparkline.chars().count(), width as usize);
+                assert!(sparkline.chars().all(|c| ('\u{2581}'..='\u{2588}').contains(&c)), "{}", sparkline);
+                // squares crowd together near 0
+                assert!(sparkline.starts_with('\u{2588}'), "{}", sparkline);
+            }
+
+            let (constant, empty) = client
+                .select("SELECT tdigest_sparkline((SELECT t_digest(100, 5.0::float8) FROM generate_series(1, 10)), 3), \
+                        tdigest_sparkline((SELECT t_digest(100, 'NaN'::float8)), 3)", None, None)
+                .first()
+                .get_two::<String, String>();
+            assert_eq!(constant.as_deref(), Some("\u{2588}\u{2588}\u{2588}"));
+            assert_eq!(empty, None);
+        });
+    }
+
+    #[pg_test(error = "TD004: tdigest_sparkline width must be at least 1, got 0")]
+    fn test_sparkline_width_zero() {
+        Spi::execute(|client| {
+            client.select("SELECT tdigest_sparkline(t_digest(100, i), 0) FROM generate_series(1, 10) i", None, None);
+        });
+    }
+
+    // How serializing partial states with bincode compares to storing them in
+    // the flat layout of the final digest, round trip included. Run with
+    // --ignored.
+    #[pg_test]
+    #[ignore]
+    fn bench_serialize() {
+        let mut state = super::TDigestTransState::new(10_000);
+        for i in 0..2_000_000i64 {
+            state.push(((i * 7919) % 2_000_000) as f64);
+        }
+        state.digest();
+
+        let runs = 100;
+        let start = std::time::Instant::now();
+        for _ in 0..runs {
+            let bytes = super::tdigest_serialize(state.clone().into());
+            super::tdigest_deserialize(bytes, None);
+        }
+        let bincode = start.elapsed();
+
+        let start = std::time::Instant::now();
+        for _ in 0..runs {
+            let bytes = state.clone().finish().into_datum().unwrap();
+            let digest = unsafe { super::TimescaleTDigest::from_datum(bytes, false, pg_sys::BYTEAOID) }.unwrap();
+            super::TDigestTransState::from_digest(&digest);
+        }
+        let flat = start.elapsed();
+
+        println!("serializing {} centroids: bincode {:?}, flat layout {:?}", state.digested.raw_centroids().len(),
+            bincode / runs, flat / runs);
+    }
+
+    #[pg_test(error = "TD002: quantile must be between 0 and 1, got -0.5")]
+    fn test_quantile_out_of_range() {
+        Spi::execute(|client| {
+            client.select("SELECT tdigest_quantile(t_digest(100, data), -0.5) FROM generate_series(1, 10) data", None, None);
+        });
+    }
+
+    #[pg_test(error = "TD002: quantile must be between 0 and 1, got 1.5")]
+    fn test_quantile_each_out_of_range() {
+        Spi::execute(|client| {
+            client.select("SELECT tdigest_quantile_each(ARRAY[t_digest(100, data)], 1.5) FROM generate_series(1, 10) data", None, None);
+        });
+    }
+
+    #[pg_test(error = "TD002: quantile must be between 0 and 1, got NaN")]
+    fn test_log_quantile_out_of_range() {
+        Spi::execute(|client| {
+            client.select("SELECT tdigest_log_quantile(t_digest_log(100, data), 'NaN') FROM generate_series(1, 10) data", None, None);
+        });
+    }
+
+    #[pg_test(error = "TD002: quantile must be between 0 and 1, got 1.5")]
+    fn test_quantile_ci_out_of_range() {
+        Spi::execute(|client| {
+            client.select("SELECT * FROM tdigest_quantile_ci((SELECT t_digest(100, data) FROM generate_series(1, 10) data), 1.5)", None, None);
+        });
+    }
+
+    #[pg_test(error = "TD002: quantile must be between 0 and 1, got -0.5")]
+    fn test_quantile_resolution_out_of_range() {
+        Spi::execute(|client| {
+            client.select("SELECT tdigest_quantile_resolution(t_digest(100, data), -0.5) FROM generate_series(1, 10) data", None, None);
+        });
+    }
+
+    #[pg_test(error = "TD002: quantile must be between 0 and 1, got 1.5")]
+    fn test_weighted_quantile_out_of_range() {
+        Spi::execute(|client| {
+            client.select("SELECT tdigest_weighted_quantile(ARRAY[t_digest(100, data)], ARRAY[1.0::float8], 1.5) FROM generate_series(1, 10) data", None, None);
+        });
+    }
+
+    #[pg_test(error = "TD002: quantile must be between 0 and 1, got 1.5")]
+    fn test_merged_quantile_out_of_range() {
+        Spi::execute(|client| {
+            client.select("SELECT tdigest_merged_quantile(ARRAY[t_digest(100, data)], 1.5) FROM generate_series(1, 10) data", None, None);
+        });
+    }
+
+    #[pg_test]
+    fn test_distinct_bounded() {
+        Spi::execute(|client| {
+            // more distinct values than are kept, each seen once, so flushing
+            // them doesn't count any twice
+            let (count, min, max) = client
+                .select("SELECT tdigest_count(d), tdigest_min(d), tdigest_max(d) \
+                    FROM (SELECT t_digest_distinct(100, i) d FROM generate_series(1, 25000) i) s", None, None)
+                .first()
+                .get_three::<f64, f64, f64>();
+            apx_eql(count.unwrap(), 25000.0, 0.000001);
+            apx_eql(min.unwrap(), 1.0, 0.000001);
+            apx_eql(max.unwrap(), 25000.0, 0.000001);
+        });
+    }
+
+    #[pg_test]
+    fn test_buffer_accounting() {
+        Spi::execute(|client| {
+            client.select("SET timescale_analytics.tdigest_max_total_buffer = 10000", None, None);
+        });
+
+        let total = || super::account_buffer(0, 0);
+        let before = total();
+        let mut state = super::TDigestTransState::new(1000);
+        for i in 0..10 {
+            state.push(i as f64);
+        }
+        assert_eq!(total() - before, state.buffer.capacity());
+
+        // a state copied by combine holds a buffer of its own
+        let pushed = total();
+        let combined = super::combine(Some(&state), None).unwrap();
+        assert_eq!(total() - pushed, combined.buffer.capacity());
+
+        // however often it's counted in, a context has a single account
+        let mut accounts = 0;
+        unsafe {
+            let mut account = super::BUFFER_ACCOUNTS;
+            while !account.is_null() {
+                if (*account).context == pg_sys::CurrentMemoryContext {
+                    accounts += 1;
+                }
+                account = (*account).next;
+            }
+        }
+        assert_eq!(accounts, 1);
+
+        Spi::execute(|client| {
+            client.select("RESET timescale_analytics.tdigest_max_total_buffer", None, None);
+        });
+    }
+
+    // How long finalizing a large digest takes, compared to digesting its
+    // values. Run with --ignored.
+    #[pg_test]
+    #[ignore]
+    fn bench_large_final() {
+        let start = std::time::Instant::now();
+        let mut state = super::TDigestTransState::new(50_000);
+        for i in 0..1_000_000i64 {
+            state.push(((i * 7919) % 1_000_000) as f64);
+        }
+        state.digest();
+        let digesting = start.elapsed();
+
+        let runs = 10;
+        let start = std::time::Instant::now();
+        for _ in 0..runs {
+            state.finish();
+        }
+        let finishing = start.elapsed() / runs;
+
+        println!("{} centroids: digesting {:?}, finishing {:?}", state.digested.raw_centroids().len(),
+            digesting, finishing);
+    }
+
+    // as many values as buckets is already too many to keep them all apart
+    #[pg_test(error = "TD004: tdigest_exact_quantile requires an exact digest, this one has merged some of its 100 values")]
+    fn test_exact_quantile_of_full_digest() {
+        Spi::execute(|client| {
+            client.select("SELECT tdigest_exact_quantile(t_digest(100, data), 0.5) FROM generate_series(1, 100) data", None, None);
+        });
+    }
+
+    #[pg_test]
+    fn test_sampled_rows() {
+        Spi::execute(|client| {
+            // rows are sampled, not values, so neither of two values is lost
+            let (count, min, max) = client
+                .select("SELECT tdigest_count(d), tdigest_min(d), tdigest_max(d) \
+                    FROM (SELECT t_digest_sampled(100, 0.5, 1, (i % 2)::float8) d FROM generate_series(1, 10000) i) s", None, None)
+                .first()
+                .get_three::<f64, f64, f64>();
+            pct_eql(count.unwrap(), 5000.0, 0.05);
+            assert_eq!(min, Some(0.0));
+            assert_eq!(max, Some(1.0));
+        });
+    }
+
+    #[pg_test]
+    fn test_quantile_series_limits() {
+        Spi::execute(|client| {
+            let rows = client
+                .select("SELECT count(*) FROM tdigest_quantile_series(\
+                        (SELECT t_digest(100, 'NaN'::float8)), 0.0, 1.0, 0.1)", None, None)
+                .first()
+                .get_one::<i64>();
+            assert_eq!(rows, Some(0));
+
+            let rows = client
+                .select("SELECT count(*) FROM tdigest_quantile_series(\
+                        (SELECT t_digest(100, data) FROM generate_series(1.0, 10.0) data), 0.0, 1.0, 0.00001)", None, None)
+                .first()
+                .get_one::<i64>();
+            assert_eq!(rows, Some(100_001));
+        });
+    }
+
+    #[pg_test(error = "quantile must be between 0 and 1, got 1.5")]
+    fn test_quantile_series_bad_stop() {
+        Spi::execute(|client| {
+            client.select("SELECT * FROM tdigest_quantile_series(\
+                (SELECT t_digest(100, data) FROM generate_series(1.0, 10.0) data), 0.0, 1.5, 0.1)", None, None);
+        });
+    }
+
+    #[pg_test(error = "tdigest_quantile_series step is too small, gives more than 1000000 rows from 0 to 1")]
+    fn test_quantile_series_tiny_step() {
+        Spi::execute(|client| {
+            client.select("SELECT * FROM tdigest_quantile_series(\
+                (SELECT t_digest(100, data) FROM generate_series(1.0, 10.0) data), 0.0, 1.0, 1e-300)", None, None);
+        });
+    }
+
+    #[pg_test]
+    fn test_central_range_of_empty() {
+        Spi::execute(|client| {
+            let (low, high) = client
+                .select("SELECT low, high FROM tdigest_central_range((SELECT t_digest(100, 'NaN'::float8)), 0.9)", None, None)
+                .first()
+                .get_two::<f64, f64>();
+            assert_eq!(low, None);
+            assert_eq!(high, None);
+        });
+    }
+
+    #[pg_test]
+    fn test_distance_of_empty() {
+        Spi::execute(|client| {
+            let (one_empty, both_empty) = client
+                .select("SELECT tdigest_distance(a, e), tdigest_distance(e, e) FROM \
+                        (SELECT t_digest(100, data) AS a, (SELECT t_digest(100, 'NaN'::float8)) AS e \
+                        FROM generate_series(1.0, 10.0) data) digests", None, None)
+                .first()
+                .get_two::<f64, f64>();
+            assert_eq!(one_empty, None);
+            assert_eq!(both_empty, None);
+        });
+    }
+
+    #[pg_test(error = "TD003: t_digest size must be at least 1")]
+    fn test_t_digest_size_zero() {
+        Spi::execute(|client| {
+            client.select("SELECT t_digest(0, data) FROM generate_series(1, 10) data", None, None);
+        });
+    }
+
+    #[pg_test(error = "TD003: tdigest_from_centroids size must be at least 1")]
+    fn test_from_centroids_size_zero() {
+        Spi::execute(|client| {
+            client.select("SELECT tdigest_from_centroids(ARRAY[1.0, 2.0], ARRAY[1, 1], 0)", None, None);
+        });
+    }
+
+    #[pg_test(error = "TD003: tdigest_from_histogram size must be at least 1")]
+    fn test_from_histogram_size_zero() {
+        Spi::execute(|client| {
+            client.select("SELECT tdigest_from_histogram(ARRAY[0, 1, 2]::DOUBLE PRECISION[], ARRAY[3, 4]::BIGINT[], 0)", None, None);
+        });
+    }
+
+    #[pg_test]
+    fn test_weighted_quantile_merge_size() {
+        Spi::execute(|client| {
+            client.select("CREATE TABLE test (data DOUBLE PRECISION)", None, None);
+            client.select("INSERT INTO test SELECT generate_series(0.01, 100, 0.01)", None, None);
+            client.select("CREATE VIEW digests AS SELECT t_digest(10, data) FILTER (WHERE data <= 50) AS small, \
+                    t_digest(1000, data) FILTER (WHERE data > 50) AS large FROM test", None, None);
+
+            // with all weights 1 the small digest coming first mustn't shrink
+            // the merge to its size
+            for quantile in &[0.01, 0.25, 0.5, 0.75, 0.99] {
+                let (weighted, merged) = client
+                    .select(&format!("SELECT tdigest_weighted_quantile(ARRAY[small, large], ARRAY[1.0, 1.0], {}), \
+                            tdigest_merged_quantile(ARRAY[small, large], {}) FROM digests", quantile, quantile), None, None)
+                    .first()
+                    .get_two::<f64, f64>();
+                apx_eql(weighted.unwrap(), merged.unwrap(), 0.000001);
             }
         });
     }
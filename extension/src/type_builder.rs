@@ -8,10 +8,9 @@ macro_rules! pg_type {
         }
     ) => {
         ::paste::paste! {
-            use pgx::PostgresType;
-
+            // no `use` here, so that a module can declare several types
             $(#[$attrs])?
-            #[derive(PostgresType, Copy, Clone)]
+            #[derive(pgx::PostgresType, Copy, Clone)]
             #[inoutfuncs]
             pub struct $name<'input>([<$name Data>]<'input>, Option<&'input [u8]>);
 
@@ -27,18 +26,31 @@ macro_rules! pg_type {
 
             impl<'input> [<$name Data>]<'input> {
                 pub unsafe fn flatten(&self) -> $name<'static> {
-                    let bytes = self.to_pg_bytes();
+                    self.flatten_patched(|_| ())
+                }
+
+                // like flatten, but `patch` gets to fill in the serialized
+                // bytes, e.g. with a checksum over the rest, before they are
+                // shared with anything
+                pub unsafe fn flatten_patched(&self, patch: impl FnOnce(&mut [u8])) -> $name<'static> {
+                    let mut output = self.to_pg_vec();
+                    patch(&mut output);
+                    let bytes: &'static [u8] = output.leak();
                     let wrapped = [<$name Data>]::try_ref(bytes).unwrap().0;
                     (wrapped, bytes).into()
                 }
 
                 pub fn to_pg_bytes(&self) -> &'static [u8] {
+                    self.to_pg_vec().leak()
+                }
+
+                fn to_pg_vec(&self) -> Vec<u8> {
                     let mut output = vec![];
                     self.fill_vec(&mut output);
                     unsafe {
                         set_varsize(output.as_mut_ptr() as *mut _, output.len() as i32);
                     }
-                    &*output.leak()
+                    output
                 }
             }
 
@@ -55,6 +67,16 @@ macro_rules! pg_type {
                     let data_len = varsize_any(ptr);
                     let bytes = slice::from_raw_parts(ptr as *mut u8, data_len);
 
+                    // layouts are only ever extended, so anything up to the
+                    // current version can be read, but newer data can't be;
+                    // the version comes first, as a newer layout needn't parse
+                    // as any version this build knows
+                    match bytes.get(4) {
+                        Some(&version) if version == 0 || version > $name::CURRENT_VERSION =>
+                            error!(concat!("unsupported ", stringify!($name), " version {}, expected at most {}"),
+                                version, $name::CURRENT_VERSION),
+                        _ => {},
+                    }
                     let (data, _) = match [<$name Data>]::try_ref(bytes) {
                         Ok(wrapped) => wrapped,
                         Err(e) => error!(concat!("invalid ", stringify!($name), " {:?}, got len {}"), e, bytes.len()),
@@ -64,6 +86,18 @@ macro_rules! pg_type {
                 }
             }
 
+            impl<'input> $name<'input> {
+                // the varlena bytes the value was read from or flattened to,
+                // without copying them; only a value that was never either
+                // is serialized for this
+                pub fn pg_bytes(&self) -> &'input [u8] {
+                    match self.1 {
+                        Some(bytes) => bytes,
+                        None => self.0.to_pg_bytes(),
+                    }
+                }
+            }
+
             impl<'input> pgx::IntoDatum for $name<'input> {
                 fn into_datum(self) -> Option<Datum> {
                     let datum = match self.1 {
@@ -128,20 +162,23 @@ macro_rules! debug_inout_funcs {
 #[macro_export]
 macro_rules! flatten {
     ($typ:ident { $($field:ident: $value:expr),* $(,)? }) => {
+        $crate::flatten!($typ { $($field: $value),* }, |_| ())
+    };
+    ($typ:ident { $($field:ident: $value:expr),* $(,)? }, $patch:expr) => {
         {
             let data = ::paste::paste! {
                 [<$typ Data>] {
                     header: &0,
-                    version: &1,
+                    version: &$typ::CURRENT_VERSION,
                     padding: &[0; 3],
                     $(
                         $field: $value
                     ),*
                 }
             };
-            data.flatten()
+            data.flatten_patched($patch)
         }
-    }
+    };
 }
 
 #[macro_export]
@@ -150,16 +187,21 @@ macro_rules! do_serialize {
         {
             let state = &*$state;
             let size = bincode::serialized_size(state)
-            .unwrap_or_else(|e| pgx::error!("serialization error {}", e));
-            let mut bytes = Vec::with_capacity(size as usize + 4);
-            let mut varsize = [0; 4];
+                .unwrap_or_else(|e| pgx::error!("serialization error {}", e));
+            // the varlena length includes the 4-byte header itself, and palloc
+            // limits us to just under 1GB
+            let total_size = size as usize + 4;
+            if total_size > 0x3FFF_FFFF {
+                pgx::error!("serialized state too large: {} bytes", total_size)
+            }
             unsafe {
-                pgx::set_varsize(&mut varsize as *mut _ as *mut _, size as _);
+                let bytes = pg_sys::palloc(total_size) as *mut u8;
+                pgx::set_varsize(bytes as *mut _, total_size as _);
+                let mut data = std::slice::from_raw_parts_mut(bytes.add(4), size as usize);
+                bincode::serialize_into(&mut data, state)
+                    .unwrap_or_else(|e| pgx::error!("serialization error {}", e));
+                bytes as pg_sys::Datum
             }
-            bytes.extend_from_slice(&varsize);
-            bincode::serialize_into(&mut bytes, state)
-                .unwrap_or_else(|e| pgx::error!("serialization error {}", e));
-            bytes.as_mut_ptr() as pg_sys::Datum
         }
     };
 }